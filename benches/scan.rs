@@ -0,0 +1,47 @@
+//! Exact scan throughput across collection sizes.
+//!
+//! Compares the parallel matrix-vector product used by `cosine_query`
+//!
+//! with a single-threaded scalar loop over the rows. Run with
+//!
+//! `cargo bench --bench scan`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use valentinus::embeddings::exact_scores;
+
+/// Dimensions of all-MiniLM-L6-v2 embeddings
+const DIMENSIONS: usize = 384;
+/// Collection sizes scanned
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+/// Scalar reference: one dot product per row, in order
+fn scalar_scores(embeddings: ArrayView2<f32>, query: ArrayView1<f32>) -> Vec<f32> {
+    embeddings
+        .axis_iter(Axis(0))
+        .map(|row| row.iter().zip(query.iter()).map(|(a, b)| a * b).sum())
+        .collect()
+}
+
+fn scan_benchmark(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(47);
+    let query: Array1<f32> = Array1::from_shape_fn(DIMENSIONS, |_| rng.random::<f32>() - 0.5);
+    let mut group = c.benchmark_group("exact_scan");
+    for size in SIZES {
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((size, DIMENSIONS), |_| rng.random::<f32>() - 0.5);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("parallel", size), &embeddings, |b, e| {
+            b.iter(|| exact_scores(black_box(e.view()), black_box(query.view())))
+        });
+        group.bench_with_input(BenchmarkId::new("scalar", size), &embeddings, |b, e| {
+            b.iter(|| scalar_scores(black_box(e.view()), black_box(query.view())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan_benchmark);
+criterion_main!(benches);
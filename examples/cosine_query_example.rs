@@ -41,19 +41,28 @@ fn main() -> Result<(), ValentinusError> {
     for i in 0..documents.len() {
         ids.push(format!("id{}", i));
     }
+    let valentinus = Valentinus::open(&DatabaseConfig::default())?;
     let model_path = String::from("all-Mini-LM-L6-v2_onnx");
     let model_type = ModelType::AllMiniLmL6V2;
     let name = String::from("test_collection");
     let expected: Vec<String> = documents.clone();
-    let mut ec: EmbeddingCollection =
-        EmbeddingCollection::new(documents, metadata, ids, name, model_type, model_path)?;
+    let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+        &valentinus,
+        documents,
+        metadata,
+        ids,
+        name,
+        model_type,
+        model_path,
+    )?;
     let created_docs: &Vec<String> = ec.get_documents();
     assert_eq!(expected, created_docs.to_vec());
     // save collection to db
-    ec.save()?;
+    ec.save(&valentinus)?;
     // query the collection
     let query_string: &String = &String::from("Find the best reviews.");
     let result: CosineQueryResult = EmbeddingCollection::cosine_query(
+        &valentinus,
         String::from(query_string),
         String::from(ec.get_view()),
         10,
@@ -82,6 +91,7 @@ fn main() -> Result<(), ValentinusError> {
         year_filter
     );
     let no_filter_result: CosineQueryResult = EmbeddingCollection::cosine_query(
+        &valentinus,
         String::from(query_string),
         String::from(ec.get_view()),
         5,
@@ -89,6 +99,6 @@ fn main() -> Result<(), ValentinusError> {
     )?;
     assert_eq!(no_filter_result.get_docs().len(), 5);
     // remove collection from db
-    EmbeddingCollection::delete(String::from(ec.get_view()))?;
+    EmbeddingCollection::delete(&valentinus, String::from(ec.get_view()))?;
     Ok(())
 }
@@ -25,9 +25,11 @@ fn main() -> Result<(), ValentinusError> {
     }
     let name = String::from("test_collection");
     let expected: Vec<String> = documents.clone();
+    let valentinus = Valentinus::open(&DatabaseConfig::default())?;
     let model_path = String::from("all-Mini-LM-L6-v2_onnx");
     let model_type = ModelType::AllMiniLmL6V2;
     let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+        &valentinus,
         documents.clone(),
         vec![metadata],
         ids,
@@ -38,13 +40,16 @@ fn main() -> Result<(), ValentinusError> {
     let created_docs: &Vec<String> = ec.get_documents();
     assert_eq!(expected, created_docs.to_vec());
     // save collection to db
-    ec.save()?;
+    ec.save(&valentinus)?;
     // query the collection
     let query_string: String = String::from("Find me some delicious food!");
-    let result: usize =
-        EmbeddingCollection::nearest_query(query_string, String::from(ec.get_view()))?;
+    let result: usize = EmbeddingCollection::nearest_query(
+        &valentinus,
+        query_string,
+        String::from(ec.get_view()),
+    )?;
     assert_eq!(documents.clone()[result], documents[3]);
     // remove collection from db
-    EmbeddingCollection::delete(String::from(ec.get_view()))?;
+    EmbeddingCollection::delete(&valentinus, String::from(ec.get_view()))?;
     Ok(())
 }
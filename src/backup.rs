@@ -0,0 +1,203 @@
+#![deny(missing_docs)]
+
+//! Hot backup and restore of the LMDB environment.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use kn0sys_lmdb_rs::EnvCreateFlags;
+use log::*;
+use uuid::Uuid;
+
+use crate::database::*;
+use crate::embeddings::ValentinusError;
+
+/// Name of the LMDB data file inside an environment directory
+const DATA_FILE: &str = "data.mdb";
+
+/// Copy the environment into the directory `dir`, creating it if needed.
+///
+/// Writers may keep running, the copy is taken from a single read snapshot.
+pub fn backup(db: &dyn StorageBackend, dir: &Path, compact: bool) -> Result<(), ValentinusError> {
+    info!("backing up database to {}", dir.display());
+    if dir.join(DATA_FILE).exists() {
+        error!("backup target {} already holds a database", dir.display());
+        return Err(ValentinusError::BackupError(format!(
+            "{} already holds a database",
+            dir.display()
+        )));
+    }
+    fs::create_dir_all(dir).map_err(ValentinusError::IoError)?;
+    db.copy_to(dir, compact).map_err(ValentinusError::from)
+}
+
+/// Stream a copy of the environment's data file to `writer`
+pub fn backup_to_writer(
+    db: &dyn StorageBackend,
+    writer: &mut impl Write,
+    compact: bool,
+) -> Result<(), ValentinusError> {
+    let staging: PathBuf =
+        std::env::temp_dir().join(format!("valentinus-backup-{}", Uuid::new_v4()));
+    let result = backup(db, &staging, compact).and_then(|_| {
+        let mut data = File::open(staging.join(DATA_FILE)).map_err(ValentinusError::IoError)?;
+        io::copy(&mut data, writer).map_err(ValentinusError::IoError)?;
+        writer.flush().map_err(ValentinusError::IoError)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// Restore the backup at `backup`, either a backup directory or its data
+///
+/// file, into the environment described by `config`.
+pub fn restore(backup: &Path, config: &DatabaseConfig) -> Result<(), ValentinusError> {
+    let source: PathBuf = if backup.is_dir() {
+        backup.join(DATA_FILE)
+    } else {
+        backup.to_path_buf()
+    };
+    let mut reader = File::open(&source).map_err(ValentinusError::IoError)?;
+    restore_from_reader(&mut reader, config)
+}
+
+/// Restore a data file produced by `backup_to_writer`. The backup is
+///
+/// staged next to the environment and opened to validate its format
+///
+/// version before it replaces the current environment.
+pub fn restore_from_reader(
+    reader: &mut impl Read,
+    config: &DatabaseConfig,
+) -> Result<(), ValentinusError> {
+    if config.flags.contains(EnvCreateFlags::EnvCreateNoSubDir) {
+        return Err(ValentinusError::BackupError(String::from(
+            "restore requires an environment directory",
+        )));
+    }
+    let staging_env: String = format!("{}.restore-{}", config.env, Uuid::new_v4());
+    let staging: PathBuf = config.path.join(&staging_env);
+    info!("staging restore in {}", staging.display());
+    let result = stage(reader, config, &staging, staging_env).and_then(|_| swap(config, &staging));
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&staging);
+    }
+    result
+}
+
+/// Write the backup to `staging` and check that it opens
+fn stage(
+    reader: &mut impl Read,
+    config: &DatabaseConfig,
+    staging: &Path,
+    staging_env: String,
+) -> Result<(), ValentinusError> {
+    fs::create_dir_all(staging).map_err(ValentinusError::IoError)?;
+    let mut data = File::create(staging.join(DATA_FILE)).map_err(ValentinusError::IoError)?;
+    io::copy(reader, &mut data).map_err(ValentinusError::IoError)?;
+    data.sync_all().map_err(ValentinusError::IoError)?;
+    let staged = DatabaseConfig {
+        env: staging_env,
+        flags: EnvCreateFlags::EnvCreateReadOnly,
+        ..config.clone()
+    };
+    let db = DatabaseEnvironment::open(&staged).map_err(ValentinusError::from)?;
+    info!(
+        "restoring format version {}",
+        db.format_version().unwrap_or(1)
+    );
+    Ok(())
+}
+
+/// Replace the environment with the staged backup
+fn swap(config: &DatabaseConfig, staging: &Path) -> Result<(), ValentinusError> {
+    let target: PathBuf = config.path.join(&config.env);
+    let old: PathBuf = config
+        .path
+        .join(format!("{}.old-{}", config.env, Uuid::new_v4()));
+    if target.exists() {
+        fs::rename(&target, &old).map_err(ValentinusError::IoError)?;
+    }
+    if let Err(e) = fs::rename(staging, &target) {
+        let _ = fs::rename(&old, &target);
+        return Err(ValentinusError::IoError(e));
+    }
+    let _ = fs::remove_dir_all(&old);
+    Ok(())
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Write a single key/value pair
+    fn put(db: &dyn StorageBackend, k: &[u8], v: &[u8]) -> Result<(), ValentinusError> {
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        db.commit_batch(&batch).map_err(ValentinusError::from)
+    }
+
+    #[test]
+    fn backup_restore_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        let backup_dir = path.join("backup");
+        let compact_dir = path.join("compact");
+        let mut streamed: Vec<u8> = Vec::new();
+        {
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            put(&db, b"k", b"backed up")?;
+            backup(&db, &backup_dir, false)?;
+            backup(&db, &compact_dir, true)?;
+            backup_to_writer(&db, &mut streamed, true)?;
+            assert!(backup(&db, &backup_dir, false).is_err());
+            put(&db, b"k", b"changed")?;
+        }
+        let read = || -> Result<Vec<u8>, ValentinusError> {
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            db.read(b"k").map_err(ValentinusError::from)
+        };
+        restore(&backup_dir, &config)?;
+        assert_eq!(read()?, b"backed up".to_vec());
+        restore(&compact_dir, &config)?;
+        assert_eq!(read()?, b"backed up".to_vec());
+        {
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            put(&db, b"k", b"changed")?;
+        }
+        restore_from_reader(&mut &streamed[..], &config)?;
+        assert_eq!(read()?, b"backed up".to_vec());
+        // invalid backups leave the database untouched
+        assert!(restore_from_reader(&mut &b"not lmdb"[..], &config).is_err());
+        assert_eq!(read()?, b"backed up".to_vec());
+        let _ = fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_format_version_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", Uuid::new_v4()));
+        let newer = DatabaseConfig {
+            env: String::from("newer"),
+            ..DatabaseConfig::new(&path)
+        };
+        let mut streamed: Vec<u8> = Vec::new();
+        {
+            let db = DatabaseEnvironment::open(&newer).map_err(ValentinusError::from)?;
+            put(
+                &db,
+                VALENTINUS_FORMAT.as_bytes(),
+                &(FORMAT_VERSION + 1).to_be_bytes(),
+            )?;
+            backup_to_writer(&db, &mut streamed, false)?;
+        }
+        let config = DatabaseConfig::new(&path);
+        assert!(restore_from_reader(&mut &streamed[..], &config).is_err());
+        let _ = fs::remove_dir_all(path);
+        Ok(())
+    }
+}
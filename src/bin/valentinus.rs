@@ -0,0 +1,168 @@
+//! Maintenance commands for a valentinus database.
+//!
+//! ```text
+//! valentinus [--path <dir>] [--env <name>] [--read-only] [--key-file <file>]
+//!            [--previous-key-file <file>]... [--allow-plaintext] <command>
+//!
+//! commands:
+//!     verify [--repair]           check (and optionally repair) the indexers
+//!     backup <dir> [--compact]    copy the live database into <dir>
+//!     restore <backup>            replace the database with a backup
+//!     migrate                     upgrade collections stored in an older format
+//!     reencrypt                   rewrite values not encrypted with --key-file
+//! ```
+//!
+//! Key files hold the 32 raw bytes of an `EncryptionKey`.
+
+use std::process::ExitCode;
+
+use valentinus::embeddings::*;
+
+const USAGE: &str =
+    "usage: valentinus [--path <dir>] [--env <name>] [--read-only] [--key-file <file>]
+                  [--previous-key-file <file>]... [--allow-plaintext] <command>
+
+commands:
+    verify [--repair]           check (and optionally repair) the indexers
+    backup <dir> [--compact]    copy the live database into <dir>
+    restore <backup>            replace the database with a backup
+    migrate                     upgrade collections stored in an older format
+    reencrypt                   rewrite values not encrypted with --key-file";
+
+/// Parsed command line
+struct Args {
+    config: DatabaseConfig,
+    command: String,
+    /// Arguments following the command
+    rest: Vec<String>,
+}
+
+/// Read the 32 raw bytes of a key
+fn read_key(file: &str) -> Result<EncryptionKey, String> {
+    let bytes = std::fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+    let key: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| format!("{} must hold exactly 32 bytes", file))?;
+    Ok(EncryptionKey::from_bytes(key))
+}
+
+/// Split the global options from the command and its arguments
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut config = DatabaseConfig::default();
+    loop {
+        let arg = args.next().ok_or("missing command")?;
+        match arg.as_str() {
+            "--path" => config.path = args.next().ok_or("--path needs a value")?.into(),
+            "--env" => config.env = args.next().ok_or("--env needs a value")?,
+            "--read-only" => config.read_only = true,
+            "--key-file" => {
+                let file = args.next().ok_or("--key-file needs a value")?;
+                config.encryption_key = Some(read_key(&file)?);
+            }
+            "--previous-key-file" => {
+                let file = args.next().ok_or("--previous-key-file needs a value")?;
+                config.previous_keys.push(read_key(&file)?);
+            }
+            "--allow-plaintext" => config.allow_plaintext = true,
+            _ => {
+                return Ok(Args {
+                    config,
+                    command: arg,
+                    rest: args.collect(),
+                })
+            }
+        }
+    }
+}
+
+/// Report inconsistencies, repairing them with `--repair`
+fn verify(valentinus: &Valentinus, rest: &[String]) -> Result<ExitCode, String> {
+    let repair = match rest {
+        [] => false,
+        [flag] if flag == "--repair" => true,
+        _ => return Err(String::from("verify only accepts --repair")),
+    };
+    let report = valentinus.verify(repair).map_err(|e| e.to_string())?;
+    for issue in report.get_issues() {
+        println!("{}", issue);
+    }
+    if report.is_consistent() {
+        println!("database is consistent");
+        Ok(ExitCode::SUCCESS)
+    } else if report.is_repaired() {
+        println!("repaired {} issues", report.get_issues().len());
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Copy the database into a backup directory
+fn backup(valentinus: &Valentinus, rest: &[String]) -> Result<ExitCode, String> {
+    let (dir, compact) = match rest {
+        [dir] => (dir, false),
+        [dir, flag] if flag == "--compact" => (dir, true),
+        _ => return Err(String::from("backup needs <dir> [--compact]")),
+    };
+    valentinus.backup(dir, compact).map_err(|e| e.to_string())?;
+    println!("backed up to {}", dir);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Replace the database with a backup. Runs without opening the database
+fn restore(config: &DatabaseConfig, rest: &[String]) -> Result<ExitCode, String> {
+    let [backup] = rest else {
+        return Err(String::from("restore needs <backup>"));
+    };
+    Valentinus::restore(backup, config).map_err(|e| e.to_string())?;
+    println!("restored {}", backup);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Upgrade collections stored in an older format
+fn migrate(valentinus: &Valentinus, rest: &[String]) -> Result<ExitCode, String> {
+    if !rest.is_empty() {
+        return Err(String::from("migrate takes no arguments"));
+    }
+    let migrated = valentinus.migrate().map_err(|e| e.to_string())?;
+    println!("migrated {} collections", migrated);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Rewrite values not encrypted with the current key
+fn reencrypt(valentinus: &Valentinus, rest: &[String]) -> Result<ExitCode, String> {
+    if !rest.is_empty() {
+        return Err(String::from("reencrypt takes no arguments"));
+    }
+    let rewritten = valentinus.reencrypt().map_err(|e| e.to_string())?;
+    println!("re-encrypted {} values", rewritten);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run() -> Result<ExitCode, String> {
+    let mut args = parse_args(std::env::args().skip(1))?;
+    if args.command == "restore" {
+        return restore(&args.config, &args.rest);
+    }
+    // let the migrate command report what it upgrades, and let verify
+    // repair a database that can't be migrated yet
+    args.config.migrate_on_open = !matches!(args.command.as_str(), "migrate" | "verify");
+    let valentinus = Valentinus::open(&args.config).map_err(|e| e.to_string())?;
+    match args.command.as_str() {
+        "verify" => verify(&valentinus, &args.rest),
+        "backup" => backup(&valentinus, &args.rest),
+        "migrate" => migrate(&valentinus, &args.rest),
+        "reencrypt" => reencrypt(&valentinus, &args.rest),
+        other => Err(format!("unknown command: {}", other)),
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,269 @@
+#![deny(missing_docs)]
+
+//! Text splitters for breaking long documents into chunks at ingestion.
+
+use serde::{Deserialize, Serialize};
+
+use crate::onnx::{token_offsets, OnnxError};
+
+/// Deepest markdown heading level
+const MAX_HEADING_LEVEL: usize = 6;
+
+/// Possible errors while splitting are due to an invalid
+///
+/// splitter configuration or a failure to load the tokenizer.
+#[derive(Debug)]
+pub enum ChunkError {
+    /// Sizes must be non-zero with an overlap smaller than the size
+    InvalidSplitter,
+    /// Failure to tokenize documents
+    OnnxError(OnnxError),
+}
+
+/// Strategies for splitting a document into chunks
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Splitter {
+    /// Windows of `size` tokens from the collection's tokenizer,
+    ///
+    /// consecutive windows sharing `overlap` tokens.
+    Tokens {
+        /// Tokens per chunk
+        size: usize,
+        /// Tokens shared by consecutive chunks
+        overlap: usize,
+    },
+    /// Groups of `size` sentences, consecutive groups sharing `overlap` sentences.
+    Sentences {
+        /// Sentences per chunk
+        size: usize,
+        /// Sentences shared by consecutive chunks
+        overlap: usize,
+    },
+    /// One chunk per markdown section. Headings up to `max_level` (1-6)
+    ///
+    /// start a new section, deeper headings stay in their parent section.
+    MarkdownHeadings {
+        /// Deepest heading level that starts a new chunk
+        max_level: usize,
+    },
+}
+
+/// Split documents into chunks. Returns each chunk with the index of the
+///
+/// document it came from, in document order. Documents that yield no
+///
+/// chunks (e.g. empty strings) are kept as a single chunk.
+pub fn split_documents(
+    splitter: &Splitter,
+    documents: &[String],
+    model_path: &String,
+) -> Result<Vec<(usize, String)>, ChunkError> {
+    let spans: Vec<Vec<String>> = match splitter {
+        Splitter::Tokens { size, overlap } => {
+            validate(*size, *overlap)?;
+            let offsets = token_offsets(model_path, documents).map_err(ChunkError::OnnxError)?;
+            documents
+                .iter()
+                .zip(offsets.iter())
+                .map(|(d, o)| window_spans(d, o, *size, *overlap))
+                .collect()
+        }
+        Splitter::Sentences { size, overlap } => {
+            validate(*size, *overlap)?;
+            documents
+                .iter()
+                .map(|d| window_spans(d, &sentence_spans(d), *size, *overlap))
+                .collect()
+        }
+        Splitter::MarkdownHeadings { max_level } => {
+            if *max_level == 0 || *max_level > MAX_HEADING_LEVEL {
+                return Err(ChunkError::InvalidSplitter);
+            }
+            documents
+                .iter()
+                .map(|d| markdown_sections(d, *max_level))
+                .collect()
+        }
+    };
+    let mut chunks: Vec<(usize, String)> = Vec::new();
+    for (index, (document, document_chunks)) in documents.iter().zip(spans).enumerate() {
+        if document_chunks.is_empty() {
+            chunks.push((index, String::from(document)));
+        }
+        for chunk in document_chunks {
+            chunks.push((index, chunk));
+        }
+    }
+    Ok(chunks)
+}
+
+/// Sizes must be non-zero and larger than the overlap
+fn validate(size: usize, overlap: usize) -> Result<(), ChunkError> {
+    if size == 0 || overlap >= size {
+        return Err(ChunkError::InvalidSplitter);
+    }
+    Ok(())
+}
+
+/// Group byte ranges of `text` into windows of `size` ranges overlapping by `overlap`
+fn window_spans(text: &str, spans: &[(usize, usize)], size: usize, overlap: usize) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    if spans.is_empty() {
+        return chunks;
+    }
+    let mut start: usize = 0;
+    loop {
+        let end = usize::min(start + size, spans.len());
+        chunks.push(String::from(&text[spans[start].0..spans[end - 1].1]));
+        if end == spans.len() {
+            return chunks;
+        }
+        start += size - overlap;
+    }
+}
+
+/// Byte ranges of sentences. A sentence ends at a line break or at
+///
+/// `.`, `!` or `?` followed by whitespace or the end of the text.
+fn sentence_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if start.is_none() {
+            if c.is_whitespace() {
+                continue;
+            }
+            start = Some(i);
+        }
+        let at_break = chars.peek().is_none_or(|(_, n)| n.is_whitespace());
+        if c == '\n' || (matches!(c, '.' | '!' | '?') && at_break) {
+            let end = if c == '\n' { i } else { i + c.len_utf8() };
+            if let Some(s) = start.take() {
+                spans.push((s, s + text[s..end].trim_end().len()));
+            }
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, s + text[s..].trim_end().len()));
+    }
+    spans
+}
+
+/// Markdown heading level of a line, if it is an ATX heading
+fn heading_level(line: &str) -> Option<usize> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    if (1..=MAX_HEADING_LEVEL).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+        Some(level)
+    } else {
+        None
+    }
+}
+
+/// Split markdown into sections starting at headings up to `max_level`.
+///
+/// Headings inside fenced code blocks are ignored.
+fn markdown_sections(text: &str, max_level: usize) -> Vec<String> {
+    let mut sections: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut in_fence = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_fence = !in_fence;
+        }
+        let is_heading = !in_fence && heading_level(line).is_some_and(|l| l <= max_level);
+        if is_heading && !current.trim().is_empty() {
+            sections.push(String::from(current.trim()));
+            current.clear();
+        }
+        current.push_str(line);
+        current.push('\n');
+    }
+    if !current.trim().is_empty() {
+        sections.push(String::from(current.trim()));
+    }
+    sections
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn sentence_splitter_test() -> Result<(), ChunkError> {
+        let documents = vec![String::from(
+            "The battery is great. Range is 300 miles!\nService was slow? Yes, v1.2 helped.",
+        )];
+        let splitter = Splitter::Sentences {
+            size: 2,
+            overlap: 1,
+        };
+        let chunks = split_documents(&splitter, &documents, &String::new())?;
+        let expected: Vec<(usize, String)> = vec![
+            (0, String::from("The battery is great. Range is 300 miles!")),
+            (0, String::from("Range is 300 miles!\nService was slow?")),
+            (0, String::from("Service was slow? Yes, v1.2 helped.")),
+        ];
+        assert_eq!(chunks, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn markdown_splitter_test() -> Result<(), ChunkError> {
+        let documents = vec![
+            String::from("intro\n# One\nbody\n## Sub\nmore\n```\n# not a heading\n```\n# Two\nend"),
+            String::new(),
+        ];
+        let splitter = Splitter::MarkdownHeadings { max_level: 1 };
+        let chunks = split_documents(&splitter, &documents, &String::new())?;
+        let expected: Vec<(usize, String)> = vec![
+            (0, String::from("intro")),
+            (
+                0,
+                String::from("# One\nbody\n## Sub\nmore\n```\n# not a heading\n```"),
+            ),
+            (0, String::from("# Two\nend")),
+            (1, String::new()),
+        ];
+        assert_eq!(chunks, expected);
+        let invalid = Splitter::Sentences {
+            size: 2,
+            overlap: 2,
+        };
+        assert!(split_documents(&invalid, &documents, &String::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn token_windows_test() -> Result<(), ChunkError> {
+        // token offsets as the tokenizer reports them, subwords included
+        let text = "unbelievable range, slow service";
+        let offsets: Vec<(usize, usize)> =
+            vec![(0, 2), (2, 12), (13, 18), (18, 19), (20, 24), (25, 32)];
+        let chunks = window_spans(text, &offsets, 3, 1);
+        let expected: Vec<String> = vec![
+            String::from("unbelievable range"),
+            String::from("range, slow"),
+            String::from("slow service"),
+        ];
+        assert_eq!(chunks, expected);
+        // a window covering every token keeps the text whole
+        assert_eq!(window_spans(text, &offsets, 8, 2), vec![String::from(text)]);
+        assert!(window_spans("", &[], 3, 1).is_empty());
+        // invalid sizes are rejected before the tokenizer is loaded
+        let documents = vec![String::from(text)];
+        let invalid = Splitter::Tokens {
+            size: 0,
+            overlap: 0,
+        };
+        assert!(matches!(
+            split_documents(&invalid, &documents, &String::new()),
+            Err(ChunkError::InvalidSplitter)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+#![deny(missing_docs)]
+
+//! Optional compression of stored collections.
+//!
+//! Documents and metadata are compressed with the collection's codec.
+//!
+//! Embeddings are byte shuffled first: the `n`th byte of every float is
+//!
+//! stored together, so the similar sign and exponent bytes of normalized
+//!
+//! vectors compress well. Both steps are lossless.
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::ValentinusError;
+
+/// Compression applied to a collection when it is saved
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Compression {
+    /// Store the collection as is
+    #[default]
+    None,
+    /// Fast LZ4 block compression
+    Lz4,
+    /// Zstandard at the given level (1 to 22, 3 is a good default)
+    Zstd(i32),
+}
+
+/// Sizes of a stored collection before and after compression
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompressionStats {
+    /// Codec the collection was stored with
+    pub(crate) compression: Compression,
+    /// Bytes of documents, metadata and other fields before compression
+    pub(crate) documents_raw: u64,
+    /// Bytes of documents, metadata and other fields as stored
+    pub(crate) documents_stored: u64,
+    /// Bytes of embeddings before compression
+    pub(crate) embeddings_raw: u64,
+    /// Bytes of embeddings as stored
+    pub(crate) embeddings_stored: u64,
+}
+
+impl CompressionStats {
+    /// Codec the collection was stored with
+    pub fn get_compression(&self) -> Compression {
+        self.compression
+    }
+    /// Bytes of documents, metadata and other fields before compression
+    pub fn get_documents_raw(&self) -> u64 {
+        self.documents_raw
+    }
+    /// Bytes of documents, metadata and other fields as stored
+    pub fn get_documents_stored(&self) -> u64 {
+        self.documents_stored
+    }
+    /// Bytes of embeddings before compression
+    pub fn get_embeddings_raw(&self) -> u64 {
+        self.embeddings_raw
+    }
+    /// Bytes of embeddings as stored
+    pub fn get_embeddings_stored(&self) -> u64 {
+        self.embeddings_stored
+    }
+    /// Raw size divided by stored size, 1 when nothing was compressed
+    pub fn get_ratio(&self) -> f64 {
+        let stored: u64 = self.documents_stored + self.embeddings_stored;
+        if stored == 0 {
+            return 1.0;
+        }
+        (self.documents_raw + self.embeddings_raw) as f64 / stored as f64
+    }
+}
+
+/// Compress `data` with `compression`
+pub fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, ValentinusError> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => Ok(lz4_flex::compress(data)),
+        Compression::Zstd(level) => zstd::bulk::compress(data, level).map_err(|e| {
+            error!("failed to compress with zstd: {}", e);
+            ValentinusError::CompressionError
+        }),
+    }
+}
+
+/// Reverse `compress`. `raw_len` is the length of the original data
+pub fn decompress(
+    compression: Compression,
+    data: &[u8],
+    raw_len: usize,
+) -> Result<Vec<u8>, ValentinusError> {
+    let raw: Vec<u8> = match compression {
+        Compression::None => data.to_vec(),
+        Compression::Lz4 => lz4_flex::decompress(data, raw_len).map_err(|e| {
+            error!("failed to decompress lz4: {}", e);
+            ValentinusError::CompressionError
+        })?,
+        Compression::Zstd(_) => zstd::bulk::decompress(data, raw_len).map_err(|e| {
+            error!("failed to decompress zstd: {}", e);
+            ValentinusError::CompressionError
+        })?,
+    };
+    if raw.len() != raw_len {
+        error!(
+            "expected {} decompressed bytes, found {}",
+            raw_len,
+            raw.len()
+        );
+        return Err(ValentinusError::CompressionError);
+    }
+    Ok(raw)
+}
+
+/// Group the little endian bytes of `values` by their position in each float
+pub fn shuffle(values: &[f32]) -> Vec<u8> {
+    let n: usize = values.len();
+    let mut out: Vec<u8> = vec![0; n * 4];
+    for (i, value) in values.iter().enumerate() {
+        for (b, byte) in value.to_le_bytes().into_iter().enumerate() {
+            out[b * n + i] = byte;
+        }
+    }
+    out
+}
+
+/// Reverse `shuffle`
+pub fn unshuffle(bytes: &[u8]) -> Result<Vec<f32>, ValentinusError> {
+    if !bytes.len().is_multiple_of(4) {
+        error!("shuffled embeddings are not a whole number of floats");
+        return Err(ValentinusError::CompressionError);
+    }
+    let n: usize = bytes.len() / 4;
+    Ok((0..n)
+        .map(|i| f32::from_le_bytes([bytes[i], bytes[n + i], bytes[2 * n + i], bytes[3 * n + i]]))
+        .collect())
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn compression_round_trip_test() -> Result<(), ValentinusError> {
+        let values: Vec<f32> = (0..512).map(|i| (i as f32 * 0.37).sin()).collect();
+        let shuffled: Vec<u8> = shuffle(&values);
+        assert_eq!(unshuffle(&shuffled)?, values);
+        assert!(unshuffle(&shuffled[1..]).is_err());
+        let text: Vec<u8> = b"the quick brown fox ".repeat(64);
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd(3)] {
+            let packed: Vec<u8> = compress(compression, &text)?;
+            if compression != Compression::None {
+                assert!(packed.len() < text.len());
+            }
+            assert_eq!(decompress(compression, &packed, text.len())?, text);
+            let packed: Vec<u8> = compress(compression, &shuffled)?;
+            assert_eq!(
+                unshuffle(&decompress(compression, &packed, shuffled.len())?)?,
+                values
+            );
+        }
+        assert!(decompress(Compression::Lz4, b"garbage", 64).is_err());
+        Ok(())
+    }
+}
@@ -6,191 +6,820 @@ extern crate kn0sys_lmdb_rs as lmdb;
 
 use lmdb::*;
 use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 use sysinfo::System;
-use std::sync::LazyLock;
+
+use crate::encryption::{Cipher, EncryptionKey};
 
 /// Keys indexer constant for writing all collections keys
 pub const VALENTINUS_KEYS: &str = "keys";
 /// Views indexer constant for writing all collections view names
 pub const VALENTINUS_VIEWS: &str = "views";
+/// Format version of the database layout
+pub const VALENTINUS_FORMAT: &str = "format";
+/// Indexer of the collection keys set aside by `verify` repairs
+pub const VALENTINUS_QUARANTINE: &str = "quarantine";
 /// Key lookup
 pub const VALENTINUS_KEY: &str = "key";
 /// View lookup
 pub const VALENTINUS_VIEW: &str = "view";
 /// Ratio of map size to available memory is 20 percent
 const MAP_SIZE_MEMORY_RATIO: f32 = 0.2;
-/// Ratio of chunk size to available memory is 0.2 percent
-const CHUNK_SIZE_MEMORY_RATIO: f32 = MAP_SIZE_MEMORY_RATIO * 0.01;
+/// Default chunk size is 8 MiB
+pub const DEFAULT_CHUNK_SIZE: usize = 8 << 20;
+/// Version of the chunk header layout
+const CHUNK_HEADER_VERSION: u8 = 2;
+/// Current database format version. Databases without a recorded
+///
+/// version predate versioning and are version 1. Version 2 stores
+///
+/// collections behind a version header, version 3 optionally compresses
+///
+/// them, version 4 stores uncompressed embeddings apart and version 5
+///
+/// optionally quantizes them.
+pub const FORMAT_VERSION: u32 = 5;
 /// LDMB Environment variable
 pub const VALENTINUS_LMDB_ENV: &str = "VALENTINUS_LMDB_ENV";
+/// Map size environment variable
+const LMDB_MAP_SIZE: &str = "LMDB_MAP_SIZE";
+/// Default environment name
+const DEFAULT_LMDB_ENV: &str = "test";
+/// Directory created under the home directory by default
+const VALENTINUS_DIR: &str = ".valentinus";
+/// Default factor applied to the map size when it is full
+const DEFAULT_MAP_GROWTH_FACTOR: f64 = 2.0;
+/// Initial map size of a compacted copy
+const MIN_COPY_MAP_SIZE: u64 = 1 << 20;
+/// LMDB error code for a full map
+const MDB_MAP_FULL: c_int = -30792;
+/// LMDB error code for a map grown by another process
+const MDB_MAP_RESIZED: c_int = -30785;
 
-/// Database lock is initialized on startup in order to cache the db handle
-pub static DATABASE_LOCK: LazyLock<DatabaseEnvironment> = LazyLock::new(|| {
-    let env = std::env::var(VALENTINUS_LMDB_ENV).unwrap_or(String::from("test"));
-    DatabaseEnvironment::open(&env).unwrap()
-});
-
-
-/// The database environment for handling primary database operations.
-///
-/// By default the database will be written to /home/user/.valentinus/{ENV}/lmdb
-pub struct DatabaseEnvironment {
-    pub env: Environment,
-    pub handle: DbHandle,
+/// True for keys of collection blobs, as opposed to key-view lookups
+pub fn is_collection_key(key: &[u8]) -> bool {
+    let lookup_prefix: String = format!("{}-{}-", VALENTINUS_KEY, VALENTINUS_VIEW);
+    key.starts_with(format!("{}-", VALENTINUS_KEY).as_bytes())
+        && !key.starts_with(lookup_prefix.as_bytes())
 }
 
-impl DatabaseEnvironment {
-    /// Opens environment in specified path. The map size defaults to 20 percent
+/// Settings for opening a `DatabaseEnvironment`.
+///
+/// The database is written to `{path}/{env}`. Defaults to `$HOME/.valentinus`,
+///
+/// the `VALENTINUS_LMDB_ENV` environment (or `test`) and a map size of
+///
+/// `LMDB_MAP_SIZE` bytes (or 20 percent of available memory) which
+///
+/// doubles without limit whenever it fills up.
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    /// Parent directory of the environment
+    pub path: PathBuf,
+    /// Environment name (i.e. test, prod)
+    pub env: String,
+    /// Max environment size, i.e. size in memory/disk of all data
+    pub map_size: u64,
+    /// LMDB environment flags
+    pub flags: EnvCreateFlags,
+    /// Open the existing environment with `MDB_RDONLY`. Every write fails,
+    ///
+    /// so several query processes can safely share one environment.
+    pub read_only: bool,
+    /// Ceiling for automatic map growth. `None` grows without limit
+    pub max_map_size: Option<u64>,
+    /// Factor the map size is multiplied by when full. Must exceed 1
+    pub map_growth_factor: f64,
+    /// Upgrade collections stored in an older format when opened
+    pub migrate_on_open: bool,
+    /// Bytes per chunk when writing values. Recorded in each value's
     ///
-    /// of available memory and can be set via the `LMDB_MAP_SIZE` environment variable.
+    /// header so data reads back regardless of the size it was written with.
+    pub chunk_size: usize,
+    /// Encrypt every value with this key. `None` stores plaintext
+    pub encryption_key: Option<EncryptionKey>,
+    /// Keys older values may still be encrypted with. Keep them here while
     ///
-    /// The path of the user can be set with `LMDB_USER`.
-    pub fn open(env: &str) -> Result<Self, MdbError> {
+    /// rotating keys until `StorageBackend::reencrypt` has run.
+    pub previous_keys: Vec<EncryptionKey>,
+    /// Accept plaintext values while encrypting an existing database
+    pub allow_plaintext: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
         let s = System::new_all();
         let default_map_size: u64 =
             (s.available_memory() as f32 * MAP_SIZE_MEMORY_RATIO).floor() as u64;
-        let env_map_size: u64 = match std::env::var("LMDB_MAP_SIZE") {
+        let map_size: u64 = match std::env::var(LMDB_MAP_SIZE) {
             Err(_) => default_map_size,
             Ok(size) => size.parse::<u64>().unwrap_or(default_map_size),
         };
-        info!("setting lmdb map size to: {}", env_map_size);
-        let user: String = match std::env::var("LMDB_USER") {
-            Err(_) => std::env::var("USER").unwrap_or(String::from("user")),
-            Ok(user) => user,
+        let home: PathBuf = std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        DatabaseConfig {
+            path: home.join(VALENTINUS_DIR),
+            env: std::env::var(VALENTINUS_LMDB_ENV).unwrap_or(String::from(DEFAULT_LMDB_ENV)),
+            map_size,
+            flags: EnvCreateFlags::empty(),
+            read_only: false,
+            max_map_size: None,
+            map_growth_factor: DEFAULT_MAP_GROWTH_FACTOR,
+            migrate_on_open: true,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            encryption_key: None,
+            previous_keys: Vec::new(),
+            allow_plaintext: false,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Default configuration rooted at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        DatabaseConfig {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads a value inside the write transaction of `StorageBackend::update`
+pub type UpdateReader<'a> = dyn Fn(&[u8]) -> Result<Vec<u8>, MdbError> + 'a;
+/// Stages operations from values read inside `StorageBackend::update`
+pub type UpdateFn<'a> = dyn FnMut(&UpdateReader, &mut WriteBatch) -> Result<(), MdbError> + 'a;
+
+/// Persistence operations used by collections. LMDB (`DatabaseEnvironment`)
+///
+/// is the default, `MemoryBackend` keeps everything in memory.
+pub trait StorageBackend: Send + Sync {
+    /// Read key from the database. If it doesn't exist then
+    ///
+    /// an empty vector will be returned. Treat all empty vectors
+    ///
+    /// from database operations as failures.
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError>;
+    /// List every key written to the database. Values written empty read
+    ///
+    /// back like missing ones and backends may leave them out, i.e. LMDB
+    ///
+    /// lists a key by its first chunk and an empty value has none.
+    fn keys(&self) -> Result<Vec<Vec<u8>>, MdbError>;
+    /// Apply every operation of the batch atomically. Either all keys
+    ///
+    /// are written and deleted or, on error, none are.
+    fn commit_batch(&self, batch: &WriteBatch) -> Result<(), MdbError> {
+        self.update(batch, &mut |_, _| Ok(()))
+    }
+    /// Apply `batch` and the operations staged by `f` in one write
+    ///
+    /// transaction. `f` reads values inside that transaction, so a
+    ///
+    /// read-modify-write such as updating the collection indexers can't
+    ///
+    /// lose a concurrent update. `f` sees the values from before `batch`,
+    ///
+    /// its operations run after it, and it may run again on a retry.
+    fn update(&self, batch: &WriteBatch, f: &mut UpdateFn) -> Result<(), MdbError>;
+    /// True when every write is rejected
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Rewrite every value not encrypted with the current key, i.e. after
+    ///
+    /// rotating keys or enabling encryption. Returns the number of values
+    ///
+    /// rewritten. Backends that don't encrypt have nothing to rewrite.
+    fn reencrypt(&self) -> Result<usize, MdbError> {
+        Ok(0)
+    }
+    /// Call `f` with the value of `k`. Backends that can lend the stored
+    ///
+    /// bytes without copying them, i.e. from a memory map, do so. The
+    ///
+    /// default reads a copy.
+    fn scan(&self, k: &[u8], f: &mut dyn FnMut(&[u8])) -> Result<(), MdbError> {
+        f(&self.read(k)?);
+        Ok(())
+    }
+    /// Format version recorded in the database
+    fn format_version(&self) -> Result<u32, MdbError> {
+        let raw: Vec<u8> = self.read(VALENTINUS_FORMAT.as_bytes())?;
+        Ok(decode_format_version(&raw)?.unwrap_or(1))
+    }
+    /// Write a consistent copy of the database into the directory `dir`,
+    ///
+    /// which must exist. With `compact` free pages are omitted. Backends
+    ///
+    /// without files on disk don't support backups.
+    fn copy_to(&self, dir: &Path, _compact: bool) -> Result<(), MdbError> {
+        error!("backend can't be copied to {}", dir.display());
+        Err(MdbError::StateError(String::from(
+            "backup is not supported by this backend",
+        )))
+    }
+}
+
+/// Decode a stored format version, `None` if there is none
+fn decode_format_version(raw: &[u8]) -> Result<Option<u32>, MdbError> {
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    let bytes: [u8; 4] = raw.try_into().map_err(|_| {
+        error!("invalid database format version");
+        MdbError::StateError(String::from("invalid database format version"))
+    })?;
+    Ok(Some(u32::from_be_bytes(bytes)))
+}
+
+/// The database environment for handling primary database operations.
+///
+/// Opened from a `DatabaseConfig`, several independent environments may
+///
+/// be open in the same process. Writes that fill the map grow it by
+///
+/// `map_growth_factor` (up to `max_map_size`) and are retried.
+pub struct DatabaseEnvironment {
+    env: Environment,
+    handle: DbHandle,
+    /// Held shared by every transaction and exclusively while resizing the map
+    txn_lock: RwLock<()>,
+    max_map_size: Option<u64>,
+    map_growth_factor: f64,
+    chunk_size: usize,
+    read_only: bool,
+    cipher: Cipher,
+}
+
+impl DatabaseEnvironment {
+    /// Opens the environment at `{config.path}/{config.env}`, creating
+    ///
+    /// the directory if it doesn't exist.
+    pub fn open(config: &DatabaseConfig) -> Result<Self, MdbError> {
+        if config.map_growth_factor <= 1.0 {
+            error!("map growth factor must be greater than 1");
+            return Err(MdbError::StateError(String::from(
+                "map growth factor must be greater than 1",
+            )));
+        }
+        if config.chunk_size == 0 {
+            error!("chunk size must be greater than 0");
+            return Err(MdbError::StateError(String::from(
+                "chunk size must be greater than 0",
+            )));
+        }
+        let path: PathBuf = config.path.join(&config.env);
+        let read_only: bool =
+            config.read_only || config.flags.contains(EnvCreateFlags::EnvCreateReadOnly);
+        let flags: EnvCreateFlags = if read_only {
+            config.flags | EnvCreateFlags::EnvCreateReadOnly
+        } else {
+            config.flags
         };
-        info!("$LMDB_USER={}", user);
-        info!("excecuting lmdb open");
-        let file_path: String = format!("/home/{}/.{}/", user, "valentinus");
+        info!("setting lmdb map size to: {}", config.map_size);
+        info!("excecuting lmdb open at {}", path.display());
         let env: Environment = EnvBuilder::new()
-            .map_size(env_map_size)
-            .open(format!("{}/{}", file_path, env), 0o777)
-            .unwrap_or_else(|_| panic!("could not open LMDB at {}", file_path));
-        let default: Result<DbHandle, MdbError> = env.get_default_db(DbFlags::empty());
-        if default.is_err() {
-            panic!("could not set db handle")
-        }
-        let handle: DbHandle = default?;
-        Ok(DatabaseEnvironment {
+            .map_size(config.map_size)
+            .flags(flags)
+            .open(&path, 0o777)
+            .inspect_err(|_| error!("could not open LMDB at {}", path.display()))?;
+        let handle: DbHandle = env
+            .get_default_db(DbFlags::empty())
+            .inspect_err(|_| error!("could not set db handle"))?;
+        let db = DatabaseEnvironment {
             env,
             handle,
-        })
+            txn_lock: RwLock::new(()),
+            max_map_size: config.max_map_size,
+            map_growth_factor: config.map_growth_factor,
+            chunk_size: config.chunk_size,
+            read_only,
+            cipher: Cipher::new(
+                config.encryption_key.clone(),
+                config.previous_keys.clone(),
+                config.allow_plaintext,
+            ),
+        };
+        match db.stored_format_version()? {
+            Some(version) if version > FORMAT_VERSION => {
+                error!("unsupported database format version {}", version);
+                return Err(MdbError::StateError(format!(
+                    "unsupported database format version {}",
+                    version
+                )));
+            }
+            // only a new database starts at the current version
+            None if !read_only && db.env.stat()?.ms_entries == 0 => {
+                let mut batch = WriteBatch::new();
+                batch.put(VALENTINUS_FORMAT.as_bytes(), &FORMAT_VERSION.to_be_bytes());
+                db.commit_batch(&batch)?;
+            }
+            _ => {}
+        }
+        Ok(db)
     }
 
-    /// Write a key/value pair to the database. It is not possible to
+    /// Format version recorded in the database, `None` if there is none
+    fn stored_format_version(&self) -> Result<Option<u32>, MdbError> {
+        let raw: Vec<u8> = self.with_map_growth(|| {
+            let reader: ReadonlyTransaction = self.env.get_reader()?;
+            let db: Database = reader.bind(&self.handle);
+            read_chunks(&db, VALENTINUS_FORMAT.as_bytes())
+        })?;
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        decode_format_version(&self.cipher.open(VALENTINUS_FORMAT.as_bytes(), &raw)?)
+    }
+
+    /// Run a database operation, growing the map and retrying when it
     ///
-    /// write with empty keys.
-    fn write(e: &Environment, h: &DbHandle, k: &Vec<u8>, v: &Vec<u8>) -> Result<(), MdbError> {
-        info!("excecuting lmdb write");
-        if k.is_empty() {
-            error!("can't write empty key");
+    /// is full, or adopting the new size when another process grew it.
+    fn with_map_growth<T>(
+        &self,
+        mut op: impl FnMut() -> Result<T, MdbError>,
+    ) -> Result<T, MdbError> {
+        loop {
+            let result = {
+                let _guard = self.txn_lock.read().unwrap_or_else(|e| e.into_inner());
+                op()
+            };
+            match result {
+                Err(MdbError::Other(MDB_MAP_FULL, _)) => self.grow_map()?,
+                Err(MdbError::Other(MDB_MAP_RESIZED, _)) => self.set_map_size(0)?,
+                r => return r,
+            }
+        }
+    }
+
+    /// Encrypt the values put by `batch`, in order
+    fn seal_batch(&self, batch: &WriteBatch) -> Result<Vec<Vec<u8>>, MdbError> {
+        if batch.ops.iter().any(|op| op.key().is_empty()) {
+            error!("can't write or delete empty key");
             return Err(MdbError::NotFound);
         }
-        let new_txn = e.new_transaction()?;
-        let txn = new_txn;
-        {
-            let db: Database = txn.bind(h);
-            let pair: Vec<(&Vec<u8>, &Vec<u8>)> = vec![(k, v)];
-            for &(key, value) in pair.iter() {
-                db.set(key, value)
-                    .unwrap_or_else(|_| error!("failed to set key: {:?}", k));
+        batch
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                BatchOp::Put(k, v) | BatchOp::PutUnchunked(k, v) => Some(self.cipher.seal(k, v)),
+                BatchOp::Delete(_) => None,
+            })
+            .collect()
+    }
+
+    /// Apply `batch` in a write transaction with its `sealed` values
+    fn apply_batch(
+        &self,
+        db: &Database,
+        batch: &WriteBatch,
+        sealed: &[Vec<u8>],
+    ) -> Result<(), MdbError> {
+        let mut values = sealed.iter();
+        for op in &batch.ops {
+            match op {
+                BatchOp::Put(k, _) => {
+                    let v: &Vec<u8> = values.next().ok_or(MdbError::Panic)?;
+                    delete_chunks(db, k)?;
+                    put_chunks(db, k, v, self.chunk_size)?;
+                }
+                BatchOp::PutUnchunked(k, _) => {
+                    let v: &Vec<u8> = values.next().ok_or(MdbError::Panic)?;
+                    delete_chunks(db, k)?;
+                    put_chunks(db, k, v, v.len().max(1))?;
+                }
+                BatchOp::Delete(k) => delete_chunks(db, k)?,
             }
         }
-        txn.commit()
+        Ok(())
     }
-    /// Read key from the database. If it doesn't exist then
-    ///
-    /// an empty vector will be returned. Treat all empty vectors
+
+    /// Grow the map by the growth factor, bounded by the configured ceiling
+    fn grow_map(&self) -> Result<(), MdbError> {
+        let current: u64 = self.env.info()?.me_mapsize as u64;
+        let size: u64 = self.grown_size(current)?;
+        info!("growing lmdb map size from {} to {}", current, size);
+        self.set_map_size(size)
+    }
+
+    /// Map size after growing `current` once, bounded by the configured ceiling
+    fn grown_size(&self, current: u64) -> Result<u64, MdbError> {
+        let mut size: u64 = (current as f64 * self.map_growth_factor).ceil() as u64;
+        if let Some(max) = self.max_map_size {
+            if current >= max {
+                error!("lmdb map size ceiling of {} bytes reached", max);
+                return Err(MdbError::Other(
+                    MDB_MAP_FULL,
+                    format!("map size ceiling of {} bytes reached", max),
+                ));
+            }
+            size = size.min(max);
+        }
+        Ok(size)
+    }
+
+    /// Set the map size once no transactions are active in this process.
     ///
-    /// from database operations as failures.
-    pub fn read(e: &Environment, h: &DbHandle, k: &Vec<u8>) -> Result<Vec<u8>, MdbError> {
+    /// A size of zero adopts the size currently used by the environment.
+    fn set_map_size(&self, size: u64) -> Result<(), MdbError> {
+        let _guard = self.txn_lock.write().unwrap_or_else(|e| e.into_inner());
+        self.env.set_mapsize(size as usize)
+    }
+}
+
+impl StorageBackend for DatabaseEnvironment {
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
         info!("excecuting lmdb read");
         // don't try and read empty keys
         if k.is_empty() {
             error!("can't read empty key");
             return Err(MdbError::NotFound);
         }
-        let get_reader = e.get_reader();
-        let reader: ReadonlyTransaction = get_reader?;
-        let db: Database = reader.bind(h);
-        let mut result: Vec<u8> = Vec::new();
-        for num_writes in 0..usize::MAX {
-            let mut new_key: Vec<u8> = k.to_vec();
-            let mut key_count: Vec<u8> = (num_writes).to_be_bytes().to_vec();
-            new_key.append(&mut key_count);
-            let mut r = db.get::<Vec<u8>>(&new_key).unwrap_or_default();
-            if r.is_empty() {
-                break;
-            }
-            result.append(&mut r);
+        let result: Vec<u8> = self.with_map_growth(|| {
+            let reader: ReadonlyTransaction = self.env.get_reader()?;
+            let db: Database = reader.bind(&self.handle);
+            read_chunks(&db, k)
+        })?;
+        if result.is_empty() {
+            error!("failed to read key {:?} from db", k);
+            return Ok(result);
         }
-        {
-            if result.is_empty() {
-                error!("failed to read key {:?} from db", k);
+        self.cipher.open(k, &result)
+    }
+    fn keys(&self) -> Result<Vec<Vec<u8>>, MdbError> {
+        info!("excecuting lmdb key scan");
+        self.with_map_growth(|| {
+            let reader: ReadonlyTransaction = self.env.get_reader()?;
+            let db: Database = reader.bind(&self.handle);
+            let first_chunk: [u8; 8] = 0u64.to_be_bytes();
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            for cursor in db.iter()? {
+                let raw: Vec<u8> = cursor.get_key();
+                if raw.len() > first_chunk.len() && raw.ends_with(&first_chunk) {
+                    keys.push(raw[..raw.len() - first_chunk.len()].to_vec());
+                }
             }
+            Ok(keys)
+        })
+    }
+    fn update(&self, batch: &WriteBatch, f: &mut UpdateFn) -> Result<(), MdbError> {
+        info!("excecuting lmdb batch of {} operations", batch.ops.len());
+        if self.read_only {
+            error!("can't write to a read-only database");
+            return Err(MdbError::StateError(String::from("database is read-only")));
         }
-        Ok(result)
+        // encrypt once, not on every retry
+        let sealed: Vec<Vec<u8>> = self.seal_batch(batch)?;
+        self.with_map_growth(|| {
+            let txn = self.env.new_transaction()?;
+            {
+                let db: Database = txn.bind(&self.handle);
+                let mut staged = WriteBatch::new();
+                {
+                    let read = |k: &[u8]| -> Result<Vec<u8>, MdbError> {
+                        let raw: Vec<u8> = read_chunks(&db, k)?;
+                        if raw.is_empty() {
+                            return Ok(raw);
+                        }
+                        self.cipher.open(k, &raw)
+                    };
+                    f(&read, &mut staged)?;
+                }
+                let staged_sealed: Vec<Vec<u8>> = self.seal_batch(&staged)?;
+                self.apply_batch(&db, batch, &sealed)?;
+                self.apply_batch(&db, &staged, &staged_sealed)?;
+            }
+            // dropping the transaction on error aborts it
+            txn.commit()
+        })
+    }
+    fn is_read_only(&self) -> bool {
+        self.read_only
     }
-    /// Deletes a key/value pair from the database
-    pub fn delete(e: &Environment, h: &DbHandle, k: &[u8]) -> Result<(), MdbError> {
-        info!("excecuting lmdb delete");
+    fn scan(&self, k: &[u8], f: &mut dyn FnMut(&[u8])) -> Result<(), MdbError> {
+        info!("excecuting lmdb scan");
         if k.is_empty() {
-            error!("can't delete empty key");
+            error!("can't read empty key");
             return Err(MdbError::NotFound);
         }
-        let new_txn = e.new_transaction();
-        let txn = new_txn?;
-        let get_reader = e.get_reader();
-        let reader: ReadonlyTransaction = get_reader?;
-        let db_reader: Database = reader.bind(h);
-        {
-            let db = txn.bind(h);
+        // encrypted values can only be lent once decrypted
+        let lent: bool = !self.cipher.is_enabled()
+            && self.with_map_growth(|| {
+                let reader: ReadonlyTransaction = self.env.get_reader()?;
+                let db: Database = reader.bind(&self.handle);
+                let header: ChunkHeader = match read_header(&db, k)? {
+                    Some(header) if header.count == 1 => header,
+                    _ => return Ok(false),
+                };
+                let v: &[u8] = match db.get::<&[u8]>(&chunk_key(k, 0)) {
+                    Err(MdbError::NotFound) => return Err(corrupted(k, "missing chunk")),
+                    v => v?,
+                };
+                if self.cipher.is_stale(v) {
+                    return Ok(false);
+                }
+                if v.len() as u64 != header.total_len {
+                    return Err(corrupted(k, "length doesn't match the header"));
+                }
+                if header
+                    .checksum
+                    .is_some_and(|checksum| checksum != crc32fast::hash(v))
+                {
+                    return Err(corrupted(k, "checksum mismatch"));
+                }
+                f(v);
+                Ok(true)
+            })?;
+        if !lent {
+            f(&self.read(k)?);
+        }
+        Ok(())
+    }
+    fn reencrypt(&self) -> Result<usize, MdbError> {
+        info!("re-encrypting lmdb values");
+        let mut rewritten: usize = 0;
+        for k in self.keys()? {
+            // values in a single chunk may be lent by `scan`, keep them whole
+            let (raw, unchunked): (Vec<u8>, bool) = self.with_map_growth(|| {
+                let reader: ReadonlyTransaction = self.env.get_reader()?;
+                let db: Database = reader.bind(&self.handle);
+                let count: Option<u64> = read_header(&db, &k)?.map(|h| h.count);
+                Ok((read_chunks(&db, &k)?, count == Some(1)))
+            })?;
+            if raw.is_empty() || !self.cipher.is_stale(&raw) {
+                continue;
+            }
+            let mut batch = WriteBatch::new();
+            if unchunked {
+                batch.put_unchunked(&k, &self.cipher.open(&k, &raw)?);
+            } else {
+                batch.put(&k, &self.cipher.open(&k, &raw)?);
+            }
+            self.commit_batch(&batch)?;
+            rewritten += 1;
+        }
+        info!("re-encrypted {} values", rewritten);
+        Ok(rewritten)
+    }
+    fn format_version(&self) -> Result<u32, MdbError> {
+        Ok(self.stored_format_version()?.unwrap_or(1))
+    }
+    fn copy_to(&self, dir: &Path, compact: bool) -> Result<(), MdbError> {
+        info!("copying lmdb environment to {}", dir.display());
+        if !compact {
+            return self.with_map_growth(|| self.env.copy_to_path(dir));
+        }
+        // start from the pages in use, the copy grows its map when full
+        let stat = self.env.stat()?;
+        let pages: u64 =
+            (stat.ms_branch_pages + stat.ms_leaf_pages + stat.ms_overflow_pages) as u64;
+        let map_size: u64 = (pages * stat.ms_psize as u64).max(MIN_COPY_MAP_SIZE);
+        let target: Environment = EnvBuilder::new()
+            .map_size(map_size)
+            .open(dir, 0o777)
+            .inspect_err(|_| error!("could not open LMDB at {}", dir.display()))?;
+        let target_handle: DbHandle = target.get_default_db(DbFlags::empty())?;
+        let copy = |target_size: &mut u64| -> Result<(), MdbError> {
+            let reader: ReadonlyTransaction = self.env.get_reader()?;
+            let db: Database = reader.bind(&self.handle);
+            let write_all = || -> Result<(), MdbError> {
+                let txn = target.new_transaction()?;
+                {
+                    let target_db: Database = txn.bind(&target_handle);
+                    for cursor in db.iter()? {
+                        let k: &[u8] = cursor.get_key();
+                        let v: &[u8] = cursor.get_value();
+                        target_db.set(&k, &v)?;
+                    }
+                }
+                txn.commit()
+            };
+            // the target grows on its own, a full target must not grow the source
+            loop {
+                match write_all() {
+                    Err(MdbError::Other(MDB_MAP_FULL, _)) => {
+                        let size: u64 = self.grown_size(*target_size).map_err(|_| {
+                            MdbError::StateError(String::from("backup map size ceiling reached"))
+                        })?;
+                        info!("growing backup map size from {} to {}", target_size, size);
+                        target.set_mapsize(size as usize)?;
+                        *target_size = size;
+                    }
+                    r => return r,
+                }
+            }
+        };
+        let mut target_size: u64 = map_size;
+        self.with_map_growth(|| copy(&mut target_size))?;
+        target.sync(true)
+    }
+}
+
+/// Operation staged in a `WriteBatch`
+pub enum BatchOp {
+    /// Write the value to the key
+    Put(Vec<u8>, Vec<u8>),
+    /// Write the value to the key in one piece, so `StorageBackend::scan`
+    ///
+    /// can lend it without copying
+    PutUnchunked(Vec<u8>, Vec<u8>),
+    /// Delete the key
+    Delete(Vec<u8>),
+}
 
-            for num_writes in 0..usize::MAX {
-                let mut new_key: Vec<u8> = k.to_vec();
-                let mut key_count: Vec<u8> = num_writes.to_be_bytes().to_vec();
-                new_key.append(&mut key_count);
-                let r = db_reader.get::<Vec<u8>>(&new_key).unwrap_or_default();
+impl BatchOp {
+    /// Key the operation applies to
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Put(k, _) | BatchOp::PutUnchunked(k, _) | BatchOp::Delete(k) => k,
+        }
+    }
+}
+
+/// Puts and deletes applied atomically by `StorageBackend::commit_batch`.
+///
+/// Operations run in the order they were added, so a put after a delete
+///
+/// of the same key rewrites it.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Stage a write of `v` to `k`, replacing any previous value
+    pub fn put(&mut self, k: &[u8], v: &[u8]) {
+        self.ops.push(BatchOp::Put(k.to_vec(), v.to_vec()));
+    }
+    /// Stage a write of `v` to `k` in one piece, i.e. for values scanned
+    ///
+    /// in place by queries
+    pub fn put_unchunked(&mut self, k: &[u8], v: &[u8]) {
+        self.ops.push(BatchOp::PutUnchunked(k.to_vec(), v.to_vec()));
+    }
+    /// Stage a delete of `k`
+    pub fn delete(&mut self, k: &[u8]) {
+        self.ops.push(BatchOp::Delete(k.to_vec()));
+    }
+    /// Staged operations in the order they were added
+    pub fn get_operations(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+/// Header stored under the bare key of every chunked value
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct ChunkHeader {
+    version: u8,
+    chunk_size: u64,
+    count: u64,
+    total_len: u64,
+    /// CRC-32 of the value, `None` for version 1 headers written without one
+    checksum: Option<u32>,
+}
+
+/// Chunk header layout before checksums
+#[derive(Deserialize)]
+struct ChunkHeaderV1 {
+    version: u8,
+    chunk_size: u64,
+    count: u64,
+    total_len: u64,
+}
+
+/// Log and build the error for a value that doesn't match its header
+fn corrupted(k: &[u8], reason: &str) -> MdbError {
+    error!("value of key {:?} is corrupted: {}", k, reason);
+    MdbError::Corrupted
+}
+
+/// Key of the `n`th chunk of `k`
+fn chunk_key(k: &[u8], n: u64) -> Vec<u8> {
+    let mut key: Vec<u8> = k.to_vec();
+    key.extend_from_slice(&n.to_be_bytes());
+    key
+}
+
+/// Read the header of `k`, `None` for missing or headerless legacy values
+fn read_header(db: &Database, k: &[u8]) -> Result<Option<ChunkHeader>, MdbError> {
+    let raw: Vec<u8> = match db.get::<Vec<u8>>(&k) {
+        Ok(raw) => raw,
+        Err(MdbError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let header: Option<ChunkHeader> = match raw.first() {
+        Some(1) => bincode::deserialize::<ChunkHeaderV1>(&raw)
+            .ok()
+            .map(|v1| ChunkHeader {
+                version: v1.version,
+                chunk_size: v1.chunk_size,
+                count: v1.count,
+                total_len: v1.total_len,
+                checksum: None,
+            }),
+        _ => bincode::deserialize(&raw).ok(),
+    };
+    let header: ChunkHeader = header.ok_or_else(|| corrupted(k, "invalid chunk header"))?;
+    if header.version > CHUNK_HEADER_VERSION {
+        error!("unsupported chunk header version {}", header.version);
+        return Err(MdbError::StateError(format!(
+            "unsupported chunk header version {}",
+            header.version
+        )));
+    }
+    Ok(Some(header))
+}
+
+/// Read every chunk of `k`, checking its length and checksum. Values
+///
+/// written before chunk headers existed are read by probing chunk keys
+///
+/// until one is missing.
+fn read_chunks(db: &Database, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+    let mut result: Vec<u8> = Vec::new();
+    match read_header(db, k)? {
+        Some(header) => {
+            for n in 0..header.count {
+                let mut r = match db.get::<Vec<u8>>(&chunk_key(k, n)) {
+                    Err(MdbError::NotFound) => return Err(corrupted(k, "missing chunk")),
+                    r => r?,
+                };
+                result.append(&mut r);
+            }
+            if result.len() as u64 != header.total_len {
+                return Err(corrupted(k, "length doesn't match the header"));
+            }
+            if header
+                .checksum
+                .is_some_and(|checksum| checksum != crc32fast::hash(&result))
+            {
+                return Err(corrupted(k, "checksum mismatch"));
+            }
+        }
+        None => {
+            for n in 0..u64::MAX {
+                let mut r = db.get::<Vec<u8>>(&chunk_key(k, n)).unwrap_or_default();
                 if r.is_empty() {
                     break;
                 }
-                db.del(&new_key)
-                    .unwrap_or_else(|_| error!("failed to delete"));
+                result.append(&mut r);
             }
         }
-        txn.commit()
-    }
-}
-
-/// Write chunks to the database. This function uses one percent
-///
-/// of the map size . Setting the map_size to a low value
-///
-/// will cause degraded performance.
-pub fn write_chunks(e: &Environment, h: &DbHandle, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
-    let s = System::new_all();
-    let chunk_size = (s.available_memory() as f32 * CHUNK_SIZE_MEMORY_RATIO) as usize;
-    let mut writes: usize = 1;
-    let mut index: usize = 0;
-    let length = v.len();
-    loop {
-        let mut old_key: Vec<u8> = k.to_vec();
-        let mut append: Vec<u8> = (writes - 1).to_be_bytes().to_vec();
-        old_key.append(&mut append);
-        if length > chunk_size && (length - index > chunk_size) {
-            // write chunks until the last value which is smaller than chunk_size
-            let _ = DatabaseEnvironment::write(
-                e,
-                h,
-                &old_key,
-                &v[index..(chunk_size * writes)].to_vec(),
-            );
-            index += chunk_size;
-            writes += 1;
-        } else {
-            DatabaseEnvironment::write(e, h, &old_key, &v[index..length].to_vec())?;
-            return Ok(());
+    }
+    Ok(result)
+}
+
+/// Write `v` under `k` in chunks of at most `chunk_size` bytes
+fn put_chunks(db: &Database, k: &[u8], v: &[u8], chunk_size: usize) -> Result<(), MdbError> {
+    let chunks: Vec<&[u8]> = v.chunks(chunk_size).collect();
+    let header = ChunkHeader {
+        version: CHUNK_HEADER_VERSION,
+        chunk_size: chunk_size as u64,
+        count: chunks.len() as u64,
+        total_len: v.len() as u64,
+        checksum: Some(crc32fast::hash(v)),
+    };
+    let b_header: Vec<u8> = bincode::serialize(&header)
+        .map_err(|_| MdbError::StateError(String::from("failed to encode chunk header")))?;
+    db.set(&k, &b_header)
+        .inspect_err(|_| error!("failed to set header for key: {:?}", k))?;
+    for (n, chunk) in chunks.into_iter().enumerate() {
+        db.set(&chunk_key(k, n as u64), &chunk)
+            .inspect_err(|_| error!("failed to set key: {:?}", k))?;
+    }
+    Ok(())
+}
+
+/// Delete the header and every chunk written under `k`. Trailing chunks
+///
+/// beyond the header's count, left by legacy writes, are removed too.
+fn delete_chunks(db: &Database, k: &[u8]) -> Result<(), MdbError> {
+    // a corrupted header still lets the chunks be probed and deleted
+    let count: u64 = match read_header(db, k) {
+        Ok(header) => header.map(|h| h.count).unwrap_or_default(),
+        Err(MdbError::Corrupted) => 0,
+        Err(e) => return Err(e),
+    };
+    match db.del(&k) {
+        Ok(()) | Err(MdbError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+    for n in 0..u64::MAX {
+        match db.del(&chunk_key(k, n)) {
+            Ok(()) => {}
+            Err(MdbError::NotFound) if n >= count => break,
+            Err(MdbError::NotFound) => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
 }
 
 // Tests
@@ -202,18 +831,362 @@ mod tests {
 
     use rand::RngCore;
 
+    /// Write a single key/value pair
+    fn put(db: &DatabaseEnvironment, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+        let mut batch = WriteBatch::new();
+        batch.put(k, v);
+        db.commit_batch(&batch)
+    }
+
     #[test]
     fn environment_test() -> Result<(), MdbError> {
-        let db = &DATABASE_LOCK;
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let db = &DatabaseEnvironment::open(&DatabaseConfig::new(&path))?;
         const DATA_SIZE_10MB: usize = 10000000;
         let mut data = vec![0u8; DATA_SIZE_10MB];
         rand::rng().fill_bytes(&mut data);
         let k = "test-key".as_bytes();
         let expected = &data.to_vec();
-        write_chunks(&db.env, &db.handle, &Vec::from(k), &Vec::from(data))?;
-        let actual = DatabaseEnvironment::read(&db.env, &db.handle, &Vec::from(k));
+        put(db, k, &data)?;
+        let actual = db.read(k);
         assert_eq!(expected.to_vec(), actual?);
-        let _ = DatabaseEnvironment::delete(&db.env, &db.handle, &Vec::from(k));
+        let mut batch = WriteBatch::new();
+        batch.delete(k);
+        db.commit_batch(&batch)?;
+        assert!(db.read(k)?.is_empty());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn map_growth_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        const MAP_SIZE_1MB: u64 = 1 << 20;
+        let config = DatabaseConfig {
+            map_size: MAP_SIZE_1MB,
+            max_map_size: Some(MAP_SIZE_1MB * 16),
+            ..DatabaseConfig::new(&path)
+        };
+        let db = &DatabaseEnvironment::open(&config)?;
+        let data = vec![7u8; 4 * MAP_SIZE_1MB as usize];
+        put(db, b"grow-key", &data)?;
+        assert!(db.env.info()?.me_mapsize as u64 > MAP_SIZE_1MB);
+        // a compacted copy grows its own map, not the source's
+        let grown: usize = db.env.info()?.me_mapsize;
+        std::fs::create_dir_all(path.join("copy")).map_err(|_| MdbError::InvalidPath)?;
+        db.copy_to(&path.join("copy"), true)?;
+        assert_eq!(db.env.info()?.me_mapsize, grown);
+        let copy = DatabaseEnvironment::open(&DatabaseConfig {
+            env: String::from("copy"),
+            ..config.clone()
+        })?;
+        assert_eq!(copy.read(b"grow-key")?, data);
+        // the ceiling stops growth
+        let too_big = vec![7u8; 32 * MAP_SIZE_1MB as usize];
+        assert!(put(db, b"too-big-key", &too_big).is_err());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn commit_batch_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        const MAP_SIZE_1MB: u64 = 1 << 20;
+        let config = DatabaseConfig {
+            map_size: MAP_SIZE_1MB,
+            max_map_size: Some(MAP_SIZE_1MB * 4),
+            ..DatabaseConfig::new(&path)
+        };
+        let db = &DatabaseEnvironment::open(&config)?;
+        put(db, b"old-key", b"old")?;
+        let mut batch = WriteBatch::new();
+        batch.put(b"new-key", b"new");
+        batch.delete(b"old-key");
+        db.commit_batch(&batch)?;
+        assert_eq!(db.read(b"new-key")?, b"new".to_vec());
+        assert!(db.read(b"old-key")?.is_empty());
+        // a failing operation rolls back the whole batch
+        let mut batch = WriteBatch::new();
+        batch.delete(b"new-key");
+        batch.put(b"too-big-key", &vec![7u8; 8 * MAP_SIZE_1MB as usize]);
+        assert!(db.commit_batch(&batch).is_err());
+        assert_eq!(db.read(b"new-key")?, b"new".to_vec());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn update_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let db = &DatabaseEnvironment::open(&DatabaseConfig::new(&path))?;
+        let increment = |read: &UpdateReader, staged: &mut WriteBatch| {
+            let raw: Vec<u8> = read(b"counter")?;
+            let count: u64 = raw.try_into().map(u64::from_be_bytes).unwrap_or_default();
+            staged.put(b"counter", &(count + 1).to_be_bytes());
+            Ok(())
+        };
+        // concurrent read-modify-writes don't lose increments
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        for _ in 0..25 {
+                            db.update(&WriteBatch::new(), &mut |read, staged| {
+                                increment(read, staged)
+                            })?;
+                        }
+                        Ok::<(), MdbError>(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|w| w.join().unwrap_or(Err(MdbError::Panic)))
+        })?;
+        assert_eq!(db.read(b"counter")?, 100u64.to_be_bytes().to_vec());
+        // staged operations see the values before the batch and run after it
+        let mut batch = WriteBatch::new();
+        batch.put(b"counter", &0u64.to_be_bytes());
+        db.update(&batch, &mut |read, staged| increment(read, staged))?;
+        assert_eq!(db.read(b"counter")?, 101u64.to_be_bytes().to_vec());
+        // an error from the closure aborts the batch too
+        let mut batch = WriteBatch::new();
+        batch.delete(b"counter");
+        let failed = db.update(&batch, &mut |_, _| Err(MdbError::NotFound));
+        assert!(failed.is_err());
+        assert!(!db.read(b"counter")?.is_empty());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn encryption_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let plain = DatabaseConfig::new(&path);
+        let old = EncryptionKey::generate();
+        let new = EncryptionKey::generate();
+        {
+            let db = DatabaseEnvironment::open(&plain)?;
+            put(&db, b"k", b"customer review")?;
+        }
+        let encrypted = DatabaseConfig {
+            encryption_key: Some(old.clone()),
+            ..plain.clone()
+        };
+        // plaintext is rejected until the database is encrypted
+        assert!(DatabaseEnvironment::open(&encrypted).is_err());
+        {
+            let db = DatabaseEnvironment::open(&DatabaseConfig {
+                allow_plaintext: true,
+                ..encrypted.clone()
+            })?;
+            assert_eq!(db.reencrypt()?, 2);
+            assert_eq!(db.reencrypt()?, 0);
+            // free pages may still hold plaintext, a compacted copy doesn't
+            let compacted = path.join("compacted");
+            std::fs::create_dir_all(&compacted).map_err(|_| MdbError::InvalidPath)?;
+            db.copy_to(&compacted, true)?;
+            let data: Vec<u8> = std::fs::read(compacted.join("data.mdb")).unwrap_or_default();
+            assert!(!data.is_empty());
+            assert!(!data.windows(15).any(|w| w == b"customer review"));
+        }
+        assert!(DatabaseEnvironment::open(&plain).is_err());
+        {
+            let db = DatabaseEnvironment::open(&encrypted)?;
+            assert_eq!(db.read(b"k")?, b"customer review".to_vec());
+            let mut batch = WriteBatch::new();
+            batch.put_unchunked(b"unchunked", &[7u8; 64]);
+            db.commit_batch(&batch)?;
+        }
+        // rotate to a new key
+        let rotated = DatabaseConfig {
+            encryption_key: Some(new.clone()),
+            ..plain.clone()
+        };
+        assert!(DatabaseEnvironment::open(&rotated).is_err());
+        {
+            let db = DatabaseEnvironment::open(&DatabaseConfig {
+                previous_keys: vec![old],
+                chunk_size: 4,
+                ..rotated.clone()
+            })?;
+            assert_eq!(db.read(b"k")?, b"customer review".to_vec());
+            assert_eq!(db.reencrypt()?, 3);
+            // values stored in one piece stay in one piece
+            let reader: ReadonlyTransaction = db.env.get_reader()?;
+            let header = read_header(&reader.bind(&db.handle), b"unchunked")?;
+            assert_eq!(header.map(|h| h.count), Some(1));
+        }
+        let db = DatabaseEnvironment::open(&rotated)?;
+        assert_eq!(db.read(b"k")?, b"customer review".to_vec());
+        assert!(DatabaseEnvironment::open(&encrypted).is_err());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig {
+            chunk_size: 4,
+            ..DatabaseConfig::new(&path)
+        };
+        let db = &DatabaseEnvironment::open(&config)?;
+        let set = |k: &[u8], v: &[u8]| -> Result<(), MdbError> {
+            let txn = db.env.new_transaction()?;
+            txn.bind(&db.handle).set(&k, &v)?;
+            txn.commit()
+        };
+        put(db, b"flipped", b"0123456789")?;
+        set(&chunk_key(b"flipped", 1), b"4577")?;
+        assert!(matches!(db.read(b"flipped"), Err(MdbError::Corrupted)));
+        put(db, b"truncated", b"0123456789")?;
+        {
+            let txn = db.env.new_transaction()?;
+            txn.bind(&db.handle).del(&chunk_key(b"truncated", 1))?;
+            txn.commit()?;
+        }
+        assert!(matches!(db.read(b"truncated"), Err(MdbError::Corrupted)));
+        set(b"bad-header", b"\x02")?;
+        assert!(matches!(db.read(b"bad-header"), Err(MdbError::Corrupted)));
+        // corrupted values can still be overwritten and deleted
+        put(db, b"flipped", b"fixed")?;
+        assert_eq!(db.read(b"flipped")?, b"fixed".to_vec());
+        let mut batch = WriteBatch::new();
+        batch.delete(b"truncated");
+        batch.delete(b"bad-header");
+        db.commit_batch(&batch)?;
+        assert!(db.read(b"truncated")?.is_empty());
+        // version 1 headers carry no checksum
+        let v1: Vec<u8> = bincode::serialize(&(1u8, 4u64, 1u64, 2u64)).unwrap_or_default();
+        set(b"v1", &v1)?;
+        set(&chunk_key(b"v1", 0), b"ok")?;
+        assert_eq!(db.read(b"v1")?, b"ok".to_vec());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig {
+            chunk_size: 4,
+            ..DatabaseConfig::new(&path)
+        };
+        let value: &[u8] = b"0123456789";
+        let scanned = |db: &DatabaseEnvironment, k: &[u8]| -> Result<Vec<u8>, MdbError> {
+            let mut seen: Vec<u8> = Vec::new();
+            db.scan(k, &mut |v| seen.extend_from_slice(v))?;
+            Ok(seen)
+        };
+        {
+            let db = &DatabaseEnvironment::open(&config)?;
+            let mut batch = WriteBatch::new();
+            batch.put_unchunked(b"whole", value);
+            batch.put(b"chunked", value);
+            db.commit_batch(&batch)?;
+            let reader: ReadonlyTransaction = db.env.get_reader()?;
+            let header: Option<ChunkHeader> = read_header(&reader.bind(&db.handle), b"whole")?;
+            assert_eq!(header.map(|h| h.count), Some(1));
+            drop(reader);
+            // lent in place or read in chunks, the value is the same
+            assert_eq!(scanned(db, b"whole")?, value.to_vec());
+            assert_eq!(scanned(db, b"chunked")?, value.to_vec());
+            assert_eq!(db.read(b"whole")?, value.to_vec());
+            let txn = db.env.new_transaction()?;
+            txn.bind(&db.handle)
+                .set(&chunk_key(b"whole", 0), &&b"0123456780"[..])?;
+            txn.commit()?;
+            assert!(matches!(scanned(db, b"whole"), Err(MdbError::Corrupted)));
+        }
+        // encrypted values are decrypted before they are scanned
+        let encrypted = DatabaseConfig {
+            env: String::from("sealed"),
+            encryption_key: Some(EncryptionKey::generate()),
+            ..config
+        };
+        let db = &DatabaseEnvironment::open(&encrypted)?;
+        let mut batch = WriteBatch::new();
+        batch.put_unchunked(b"sealed", value);
+        db.commit_batch(&batch)?;
+        assert_eq!(scanned(db, b"sealed")?, value.to_vec());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        {
+            let db = DatabaseEnvironment::open(&config)?;
+            put(&db, b"k", b"v")?;
+        }
+        let read_only = DatabaseConfig {
+            read_only: true,
+            ..config.clone()
+        };
+        let db = DatabaseEnvironment::open(&read_only)?;
+        assert!(db.is_read_only());
+        assert_eq!(db.read(b"k")?, b"v".to_vec());
+        assert_eq!(db.format_version()?, FORMAT_VERSION);
+        assert!(put(&db, b"k", b"changed").is_err());
+        assert_eq!(db.read(b"k")?, b"v".to_vec());
+        // a read-only open never creates the environment
+        let missing = DatabaseConfig {
+            env: String::from("missing"),
+            ..read_only
+        };
+        assert!(DatabaseEnvironment::open(&missing).is_err());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_header_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig {
+            chunk_size: 4,
+            ..DatabaseConfig::new(&path)
+        };
+        {
+            let db = &DatabaseEnvironment::open(&config)?;
+            // legacy values have no header and may have more chunks than a new write
+            {
+                let txn = db.env.new_transaction()?;
+                {
+                    let lmdb = txn.bind(&db.handle);
+                    for n in 0..5u64 {
+                        lmdb.set(&chunk_key(b"k", n), &&b"ab"[..])?;
+                    }
+                }
+                txn.commit()?;
+            }
+            assert_eq!(db.read(b"k")?, b"ababababab".to_vec());
+            put(db, b"k", b"0123456789")?;
+            let reader = db.env.get_reader()?;
+            let lmdb = reader.bind(&db.handle);
+            let expected = ChunkHeader {
+                version: CHUNK_HEADER_VERSION,
+                chunk_size: 4,
+                count: 3,
+                total_len: 10,
+                checksum: Some(crc32fast::hash(b"0123456789")),
+            };
+            assert_eq!(read_header(&lmdb, b"k")?, Some(expected));
+            assert!(lmdb.get::<Vec<u8>>(&chunk_key(b"k", 3)).is_err());
+            drop(reader);
+            // overwriting with a shorter value removes stale chunks
+            put(db, b"k", b"xy")?;
+            let reader = db.env.get_reader()?;
+            let lmdb = reader.bind(&db.handle);
+            assert!(lmdb.get::<Vec<u8>>(&chunk_key(b"k", 1)).is_err());
+            drop(reader);
+        }
+        // values read back with any configured chunk size
+        let db = &DatabaseEnvironment::open(&DatabaseConfig::new(&path))?;
+        assert_eq!(db.read(b"k")?, b"xy".to_vec());
+        let _ = std::fs::remove_dir_all(path);
         Ok(())
     }
 }
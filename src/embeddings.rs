@@ -46,19 +46,28 @@
 //!     for i in 0..documents.len() {
 //!         ids.push(format!("id{}", i));
 //!     }
+//!     let valentinus = Valentinus::open(&DatabaseConfig::default())?;
 //!     let model_path = String::from("all-Mini-LM-L6-v2_onnx");
 //!     let model_type = ModelType::AllMiniLmL6V2;
 //!     let name = String::from("test_collection");
 //!     let expected: Vec<String> = documents.clone();
-//!     let mut ec: EmbeddingCollection =
-//!         EmbeddingCollection::new(documents, metadata, ids, name, model_type, model_path)?;
+//!     let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+//!         &valentinus,
+//!         documents,
+//!         metadata,
+//!         ids,
+//!         name,
+//!         model_type,
+//!         model_path,
+//!     )?;
 //!     let created_docs: &Vec<String> = ec.get_documents();
 //!     assert_eq!(expected, created_docs.to_vec());
 //!     // save collection to db
-//!     ec.save()?;
+//!     ec.save(&valentinus)?;
 //!     // query the collection
 //!     let query_string: &String = &String::from("Find the best reviews.");
 //!     let result: CosineQueryResult = EmbeddingCollection::cosine_query(
+//!         &valentinus,
 //!         String::from(query_string),
 //!         String::from(ec.get_view()),
 //!         10,
@@ -77,6 +86,7 @@
 //!     assert!(v_rating.map_err(|_| ValentinusError::TestError)?["Rating"].as_u64().unwrap_or(0) > rating_filter);
 //!     assert_eq!(v_year.map_err(|_| ValentinusError::TestError)?["Year"].as_u64().unwrap_or(0), year_filter);
 //!     let no_filter_result: CosineQueryResult = EmbeddingCollection::cosine_query(
+//!         &valentinus,
 //!         String::from(query_string),
 //!         String::from(ec.get_view()),
 //!         5,
@@ -84,7 +94,7 @@
 //!     )?;
 //!     assert_eq!(no_filter_result.get_docs().len(), 5);
 //!     // remove collection from db
-//!     EmbeddingCollection::delete(String::from(ec.get_view()))?;
+//!     EmbeddingCollection::delete(&valentinus, String::from(ec.get_view()))?;
 //!     Ok(())
 //! }
 //! ```
@@ -96,13 +106,36 @@ use ndarray::*;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
 use std::sync::LazyLock;
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::{database::*, md2f::filter_where, onnx::*};
+use crate::{
+    chunking::split_documents, database::*, export::*, ivf::*, md2f::filter_where, migrate::*,
+    mmr::*, onnx::*, quantization::*, scan::*, vectors::*,
+};
 use log::*;
 
+pub use crate::chunking::{ChunkError, Splitter};
+pub use crate::compression::{Compression, CompressionStats};
+pub use crate::database::{
+    BatchOp, DatabaseConfig, StorageBackend, UpdateFn, UpdateReader, WriteBatch,
+};
+pub use crate::encryption::EncryptionKey;
+pub use crate::ivf::IvfPqConfig;
+pub use crate::memory::MemoryBackend;
+pub use kn0sys_lmdb_rs::EnvCreateFlags;
+pub use crate::onnx::{
+    EmbedderConfig, ExecutionProvider, LongDocumentStrategy, OptimizationLevel,
+    TruncationDirection, WindowPooling,
+};
+pub use crate::quantization::{quantization_recall, Quantization};
+pub use crate::scan::exact_scores;
+pub use crate::verify::{Inconsistency, VerifyReport};
+
 /// Views naming restriction. Required to be alphanumeric/unederscore
 static VIEWS_NAMING_CHECK: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new("^[a-zA-Z0-9_]+$").expect("regex should be valid")
@@ -113,7 +146,7 @@ static VIEWS_NAMING_CHECK: LazyLock<Regex> = LazyLock::new(|| {
 /// Be sure to set `VALENTINUS_CUSTOM_DIM` environment
 ///
 /// variable to the number of dimensions for that model.
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub enum ModelType {
     /// AllMiniLmL12V2 model
     AllMiniLmL12V2,
@@ -132,7 +165,7 @@ pub struct KeyViewIndexer {
 
 impl KeyViewIndexer {
     /// Used to create a new indexer.
-    fn new(v: &[String]) -> KeyViewIndexer {
+    pub(crate) fn new(v: &[String]) -> KeyViewIndexer {
         KeyViewIndexer { values: v.to_vec() }
     }
     /// Accessor for values of the indexer
@@ -147,6 +180,8 @@ pub struct CosineQueryResult {
     documents: Vec<String>,
     similarities: Vec<f32>,
     metadata: Vec<Vec<String>>,
+    /// Id of the document each result was chunked from
+    parents: Vec<String>,
 }
 
 impl CosineQueryResult {
@@ -160,6 +195,7 @@ impl CosineQueryResult {
             documents,
             similarities,
             metadata,
+            ..Default::default()
         }
     }
     /// Get documents from a query result.
@@ -174,32 +210,95 @@ impl CosineQueryResult {
     pub fn get_metadata(&self) -> &Vec<Vec<String>> {
         &self.metadata
     }
+    /// Get parent document ids from a query result.
+    pub fn get_parents(&self) -> &Vec<String> {
+        &self.parents
+    }
+    /// Collapse chunk results back to their parent documents, keeping
+    ///
+    /// the most similar chunk of each parent in order of first appearance.
+    pub fn collapse_to_parents(self) -> CosineQueryResult {
+        if self.parents.len() != self.documents.len() {
+            return self;
+        }
+        let mut best: Vec<usize> = Vec::new();
+        let mut seen: HashMap<&String, usize> = HashMap::new();
+        for (index, parent) in self.parents.iter().enumerate() {
+            match seen.get(parent) {
+                Some(b) => {
+                    if self.similarities[index] > self.similarities[best[*b]] {
+                        best[*b] = index;
+                    }
+                }
+                None => {
+                    seen.insert(parent, best.len());
+                    best.push(index);
+                }
+            }
+        }
+        CosineQueryResult {
+            documents: best.iter().map(|i| self.documents[*i].clone()).collect(),
+            similarities: best.iter().map(|i| self.similarities[*i]).collect(),
+            metadata: best.iter().map(|i| self.metadata[*i].clone()).collect(),
+            parents: best.iter().map(|i| self.parents[*i].clone()).collect(),
+        }
+    }
 }
 
 /// Error handling enum for valentinus
 #[derive(Debug, Error)]
 pub enum ValentinusError {
+    /// Backup could not be written or restored
+    #[error("Backup error: {0}")]
+    BackupError(String),
     /// Bincode failure to serialize/desearilaize
     #[error("deserialization error")]
     BincodeError,
+    /// Stored collection could not be compressed or decompressed
+    #[error("Compression error")]
+    CompressionError,
+    /// Stored value failed its checksum or is truncated
+    #[error("Corrupted value")]
+    CorruptionError,
+    /// Failure to split documents into chunks
+    #[error("Chunking error")]
+    ChunkError(ChunkError),
     /// Cosine query failure
     #[error("Cosine query failure")]
     CosineError,
     /// LMDB bindings error
     #[error("LMDB error: {0}")]
     DatabaseError(MdbError),
+    /// Export is malformed or of an unsupported version
+    #[error("Invalid collection export")]
+    ExportError,
+    /// IVF-PQ index missing, stale or invalid
+    #[error("Index error. Train the index, or retrain it once the collection changed")]
+    IndexError,
+    /// Filesystem failure
+    #[error("I/O error: {0}")]
+    IoError(std::io::Error),
     /// View name must contain alphanumerics, underscores and be unique
     #[error("Invalid view name. View name must contain alphanumerics, underscores and be unique")]
     InvalidViewName,
     /// Failure to filter metadata
     #[error("Metadata filter error")]
     Md2fsError,
+    /// Stored data is of an unsupported format version
+    #[error("Unsupported format version {0}")]
+    FormatVersionError(u32),
     /// Failure in nearest query
     #[error("Nearest neighbors query failure")]
     NearestError,
     /// Failure to generate embeddings in the onnx moduler
     #[error("ONNX error")]
     OnnxError(OnnxError),
+    /// Quantized embeddings don't match the query
+    #[error("Quantization error")]
+    QuantizationError,
+    /// Write attempted on a database opened read-only
+    #[error("Database is read-only")]
+    ReadOnly,
     /// Failure to save new collection to the database
     #[error("Failed to save collection")]
     SaveError,
@@ -208,6 +307,128 @@ pub enum ValentinusError {
     TestError,
 }
 
+impl From<MdbError> for ValentinusError {
+    fn from(e: MdbError) -> Self {
+        match e {
+            MdbError::Corrupted => ValentinusError::CorruptionError,
+            e => ValentinusError::DatabaseError(e),
+        }
+    }
+}
+
+/// Handle to an open valentinus database. Open one with
+///
+/// `Valentinus::open` and pass it to every `EmbeddingCollection`
+///
+/// operation. Handles on different paths are fully independent.
+pub struct Valentinus {
+    pub(crate) db: Box<dyn StorageBackend>,
+}
+
+impl Valentinus {
+    /// Open (or create) the LMDB database described by `config`
+    pub fn open(config: &DatabaseConfig) -> Result<Valentinus, ValentinusError> {
+        let db = DatabaseEnvironment::open(config).map_err(ValentinusError::from)?;
+        let valentinus = Valentinus::with_backend(db);
+        if config.migrate_on_open && !valentinus.is_read_only() && valentinus.needs_migration()? {
+            valentinus.migrate()?;
+        }
+        Ok(valentinus)
+    }
+    /// Keep collections in memory only. Nothing is written to disk and
+    ///
+    /// every handle is independent, which suits tests and scratch work.
+    pub fn in_memory() -> Valentinus {
+        Valentinus::with_backend(MemoryBackend::new())
+    }
+    /// Store collections in a custom `StorageBackend`
+    pub fn with_backend(backend: impl StorageBackend + 'static) -> Valentinus {
+        Valentinus {
+            db: Box::new(backend),
+        }
+    }
+    /// True when the database was opened with `DatabaseConfig::read_only`
+    pub fn is_read_only(&self) -> bool {
+        self.db.is_read_only()
+    }
+    /// Fail with `ReadOnly` before a write to a read-only database
+    fn check_writable(&self) -> Result<(), ValentinusError> {
+        if self.is_read_only() {
+            error!("can't write to a read-only database");
+            return Err(ValentinusError::ReadOnly);
+        }
+        Ok(())
+    }
+    /// True when the database was written in an older format
+    pub fn needs_migration(&self) -> Result<bool, ValentinusError> {
+        let version: u32 = self.db.format_version().map_err(ValentinusError::from)?;
+        Ok(version < FORMAT_VERSION)
+    }
+    /// Upgrade collections stored in an older format. Runs on open unless
+    ///
+    /// `DatabaseConfig::migrate_on_open` is unset. Returns the number of
+    ///
+    /// collections upgraded.
+    pub fn migrate(&self) -> Result<usize, ValentinusError> {
+        self.check_writable()?;
+        crate::migrate::migrate(self.db.as_ref())
+    }
+    /// Rewrite every value not encrypted with `DatabaseConfig::encryption_key`,
+    ///
+    /// after rotating keys or encrypting an existing database. Returns the
+    ///
+    /// number of values rewritten.
+    pub fn reencrypt(&self) -> Result<usize, ValentinusError> {
+        self.check_writable()?;
+        self.db.reencrypt().map_err(ValentinusError::from)
+    }
+    /// Check that the `keys`, `views` and key-view lookups agree with the
+    ///
+    /// stored collections. Set `repair` to rebuild them from the decodable
+    ///
+    /// collections. Undecodable ones and duplicates of a view are kept on
+    ///
+    /// disk but quarantined, see `VerifyReport::get_quarantined`.
+    pub fn verify(&self, repair: bool) -> Result<VerifyReport, ValentinusError> {
+        if repair {
+            self.check_writable()?;
+        }
+        crate::verify::verify(self.db.as_ref(), repair)
+    }
+    /// Write a consistent copy of the live database into the directory
+    ///
+    /// `dir`. Set `compact` to omit free pages from the copy.
+    pub fn backup(&self, dir: impl AsRef<Path>, compact: bool) -> Result<(), ValentinusError> {
+        crate::backup::backup(self.db.as_ref(), dir.as_ref(), compact)
+    }
+    /// Stream a consistent copy of the live database to `writer`
+    pub fn backup_to_writer(
+        &self,
+        writer: &mut impl Write,
+        compact: bool,
+    ) -> Result<(), ValentinusError> {
+        crate::backup::backup_to_writer(self.db.as_ref(), writer, compact)
+    }
+    /// Replace the database described by `config` with a backup directory
+    ///
+    /// (or its `data.mdb`). The backup must open with a supported format
+    ///
+    /// version. Close every handle on the database before restoring.
+    pub fn restore(
+        backup: impl AsRef<Path>,
+        config: &DatabaseConfig,
+    ) -> Result<(), ValentinusError> {
+        crate::backup::restore(backup.as_ref(), config)
+    }
+    /// Restore a backup streamed by `backup_to_writer`
+    pub fn restore_from_reader(
+        reader: &mut impl Read,
+        config: &DatabaseConfig,
+    ) -> Result<(), ValentinusError> {
+        crate::backup::restore_from_reader(reader, config)
+    }
+}
+
 /// Want to write a collection to the db?
 ///
 /// Look no further. Use `EmbeddingCollection::new()`
@@ -218,26 +439,39 @@ pub enum ValentinusError {
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct EmbeddingCollection {
     /// Ideally an array of &str slices mapped to a vector
-    documents: Vec<String>,
+    pub(crate) documents: Vec<String>,
     /// What separates us from the other dbs. Embeddings are set when saving
-    embeddings: Array2<f32>,
+    pub(crate) embeddings: Array2<f32>,
     /// Genres mapped to their perspective document by index
-    metadata: Vec<Vec<String>>,
+    pub(crate) metadata: Vec<Vec<String>>,
     /// Path to model.onnx and tokenizer.json
-    model_path: String,
+    pub(crate) model_path: String,
     /// model type
-    model_type: ModelType,
+    pub(crate) model_type: ModelType,
+    /// Tokenization and inference settings used for documents and queries
+    pub(crate) embedder: EmbedderConfig,
+    /// Compression applied when the collection is saved
+    pub(crate) compression: Compression,
+    /// Quantization of the embeddings for the first query pass
+    pub(crate) quantization: Quantization,
+    /// Candidates of the quantized first pass rescored with the full embeddings
+    pub(crate) rescore: usize,
+    /// Store only the quantized embeddings when nothing is rescored
+    pub(crate) quantized_only: bool,
     /// Ids for each document
-    ids: Vec<String>,
+    pub(crate) ids: Vec<String>,
+    /// Parent document id of each chunk. Empty unless `chunk_documents` was used
+    pub(crate) parents: Vec<String>,
     /// Key for the collection itself. Keys are recorded as `keys` as a `Vec<String>`
-    key: String,
+    pub(crate) key: String,
     /// View name for convenice sake. Lookup is recorded in `views` as a `Vec<String>`
-    view: String,
+    pub(crate) view: String,
 }
 
 impl EmbeddingCollection {
     /// Create a new collection of unstructured data. Must be saved with the `save` method
     pub fn new(
+        valentinus: &Valentinus,
         documents: Vec<String>,
         metadata: Vec<Vec<String>>,
         ids: Vec<String>,
@@ -253,14 +487,16 @@ impl EmbeddingCollection {
             return Err(ValentinusError::InvalidViewName);
         }
         // check if  the views name is unique
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
         let views_lookup: Vec<u8> = Vec::from(VALENTINUS_VIEWS.as_bytes());
-        let views = DatabaseEnvironment::read(&db.env, &db.handle, &views_lookup)
-            .map_err(ValentinusError::DatabaseError)?;
+        let views = db.read(&views_lookup).map_err(ValentinusError::from)?;
         if !views.is_empty() {
             let view_indexer: KeyViewIndexer =
                 bincode::deserialize(&views[..]).map_err(|_| ValentinusError::BincodeError)?;
-            if view_indexer.values.contains(&name) {
+            if view_indexer
+                .values
+                .contains(&format!("{}-{}", VALENTINUS_VIEW, name))
+            {
                 error!("view name must be unique");
                 return Err(ValentinusError::InvalidViewName);
             }
@@ -282,43 +518,140 @@ impl EmbeddingCollection {
         Ok(ec)
     }
     /// Save a collection to the database. Error if the key already exists.
-    pub fn save(&mut self) -> Result<(), ValentinusError> {
+    ///
+    /// The collection and its index entries are written in one transaction.
+    pub fn save(&mut self, valentinus: &Valentinus) -> Result<(), ValentinusError> {
         info!("saving new embedding collection: {}", self.view);
-        self.set_key_indexes()?;
-        self.set_kv_index()?;
-        self.set_view_indexes()?;
+        valentinus.check_writable()?;
         // set the embeddings
         let mut embeddings: Array2<f32> = Default::default();
         info!("initialized embeddings: {}", embeddings.len());
-        embeddings = batch_embeddings(&self.model_path, &self.documents).unwrap_or_default();
+        embeddings = batch_embeddings(&self.model_path, &self.documents, &self.embedder)
+            .unwrap_or_default();
         self.set_embeddings(embeddings);
-        let collection: Vec<u8> = bincode::serialize(&self).unwrap_or_default();
-        if collection.is_empty() {
-            error!("failed to save collection: {}", &self.key);
-            return Err(ValentinusError::SaveError);
-        }
+        self.write(valentinus)
+    }
+    /// Write the collection and its index entries in one transaction
+    fn write(&self, valentinus: &Valentinus) -> Result<(), ValentinusError> {
+        valentinus.check_writable()?;
+        let mut batch = WriteBatch::new();
+        self.set_kv_index(&mut batch);
         let key = &self.key;
         let b_key = Vec::from(key.as_bytes());
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
-        write_chunks(&db.env, &db.handle, &b_key, &collection)
-            .map_err(ValentinusError::DatabaseError)?;
+        stage_collection(&mut batch, &b_key, self).map_err(|_| {
+            error!("failed to save collection: {}", &self.key);
+            ValentinusError::SaveError
+        })?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        // the indexers are read and rewritten in the same transaction
+        db.update(&batch, &mut |read, staged| {
+            stage_indexer_insert(read, VALENTINUS_KEYS, &self.key, staged)?;
+            stage_indexer_insert(read, VALENTINUS_VIEWS, &self.view, staged)
+        })
+        .map_err(ValentinusError::from)?;
         Ok(())
     }
+    /// Export a collection as JSON Lines, documented in `export.rs`, so it
+    ///
+    /// can be imported on another machine, valentinus version or store.
+    pub fn export(
+        valentinus: &Valentinus,
+        view_name: String,
+        writer: &mut impl Write,
+    ) -> Result<(), ValentinusError> {
+        info!("exporting {} embedding collection", view_name);
+        let collection: EmbeddingCollection = find(valentinus, None, Some(view_name))?;
+        let prefix: String = format!("{}-", VALENTINUS_VIEW);
+        let name: &str = collection
+            .view
+            .strip_prefix(&prefix)
+            .unwrap_or(&collection.view);
+        let header = ExportHeader {
+            format: String::from(EXPORT_FORMAT),
+            version: EXPORT_VERSION,
+            name: String::from(name),
+            model_type: collection.model_type,
+            model_path: collection.model_path,
+            embedder: collection.embedder,
+            dimensions: collection.embeddings.ncols(),
+            count: collection.documents.len(),
+        };
+        let records = collection
+            .documents
+            .into_iter()
+            .zip(collection.embeddings.axis_iter(Axis(0)))
+            .enumerate()
+            .map(|(index, (document, embedding))| ExportRecord {
+                id: collection.ids.get(index).cloned().unwrap_or_default(),
+                document,
+                metadata: collection.metadata.get(index).cloned().unwrap_or_default(),
+                parent: collection.parents.get(index).cloned(),
+                embedding: embedding.to_vec(),
+            });
+        write_export(writer, &header, records)
+    }
+    /// Import a collection written by `export`, keeping its embeddings.
+    ///
+    /// The view is named after the exported collection unless `name` is set.
+    pub fn import(
+        valentinus: &Valentinus,
+        reader: impl BufRead,
+        name: Option<String>,
+    ) -> Result<EmbeddingCollection, ValentinusError> {
+        valentinus.check_writable()?;
+        let (header, records) = read_export(reader)?;
+        info!("importing {} documents", records.len());
+        let chunked: bool = records.iter().any(|r| r.parent.is_some());
+        let rows: usize = records.len();
+        let mut documents: Vec<String> = Vec::with_capacity(rows);
+        let mut metadata: Vec<Vec<String>> = Vec::with_capacity(rows);
+        let mut ids: Vec<String> = Vec::with_capacity(rows);
+        let mut parents: Vec<String> = Vec::new();
+        let mut values: Vec<f32> =
+            Vec::with_capacity(records.iter().map(|r| r.embedding.len()).sum());
+        for record in records {
+            if chunked {
+                parents.push(record.parent.unwrap_or_else(|| record.id.clone()));
+            }
+            documents.push(record.document);
+            metadata.push(record.metadata);
+            ids.push(record.id);
+            values.extend(record.embedding);
+        }
+        let embeddings: Array2<f32> = Array2::from_shape_vec((rows, header.dimensions), values)
+            .map_err(|_| ValentinusError::ExportError)?;
+        let mut collection = EmbeddingCollection::new(
+            valentinus,
+            documents,
+            metadata,
+            ids,
+            name.unwrap_or(header.name),
+            header.model_type,
+            header.model_path,
+        )?;
+        collection.parents = parents;
+        collection.embedder = header.embedder;
+        collection.set_embeddings(embeddings);
+        collection.write(valentinus)?;
+        Ok(collection)
+    }
     /// Fetch all known keys or views in the database.
     ///
     /// By default the database will return keys. Set the
     ///
     /// views argument to `true` to fetch all the views.
-    pub fn fetch_collection_keys(views: bool) -> Result<KeyViewIndexer, ValentinusError> {
+    pub fn fetch_collection_keys(
+        valentinus: &Valentinus,
+        views: bool,
+    ) -> Result<KeyViewIndexer, ValentinusError> {
         let mut b_key: Vec<u8> = Vec::from(VALENTINUS_KEYS.as_bytes());
         if views {
             info!("setting search to views");
             b_key = Vec::from(VALENTINUS_VIEWS.as_bytes());
         }
         info!("fetching keys embedding collection");
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
-        let keys = DatabaseEnvironment::read(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        let keys = db.read(&b_key).map_err(ValentinusError::from)?;
         let indexer: KeyViewIndexer = bincode::deserialize(&keys[..]).unwrap_or_default();
         Ok(indexer)
     }
@@ -328,131 +661,309 @@ impl EmbeddingCollection {
     ///
     /// Let `f_where` be a valid ```Vec<&str>``` of JSON strings to filter on. Valid
     ///
-    /// filter operations are eq,gt,gte,lt,lte and in for string arrays. Configure
+    /// filter operations are eq,gt,gte,lt,lte and in for string arrays. Inference
+    ///
+    /// threads and providers are taken from the collection's `EmbedderConfig`.
+    ///
+    /// Quantized collections report approximate similarities, or only the
     ///
-    /// parallel threads with `ONNX_PARALLEL_THREADS=X`
+    /// rescored candidates when `set_rescore` is used.
     pub fn cosine_query(
+        valentinus: &Valentinus,
         query_string: String,
         view_name: String,
         num_results: usize,
         f_where: Option<Vec<String>>,
     ) -> Result<CosineQueryResult, ValentinusError> {
-        let is_filtering = f_where.is_some();
-        info!("querying {} embedding collection", view_name);
-        let collection: EmbeddingCollection = find(None, Some(view_name))?;
-        let qv_string = vec![query_string];
-        let qv_output = batch_embeddings(&collection.model_path, &qv_string);
-        if qv_output.is_err() {
-            error!("failed to generate embeddings for query vector");
-            return Err(ValentinusError::CosineError);
-        }
-        let qv = qv_output.unwrap_or_default();
-        let cv = collection.embeddings;
-        let docs = collection.documents;
-        info!("calculating cosine similarity");
-        let mut r_docs: Vec<String> = Vec::new();
-        let mut r_sims: Vec<f32> = Vec::new();
-        let mut r_meta: Vec<Vec<String>> = Vec::new();
-        let query = qv.index_axis(Axis(0), 0);
-        for (cv, sentence) in cv.axis_iter(Axis(0)).zip(docs.iter()) {
-            let index: Option<usize> = docs.iter().rposition(|x| x == sentence);
-            let raw_f: &Vec<String> = &f_where.clone().unwrap_or_default();
-            let raw_m: &Vec<String> = &collection.metadata[index.unwrap_or_default()];
-            let f_where: bool =
-                filter_where(raw_f, raw_m).map_err(|_| ValentinusError::Md2fsError)?;
-            if !is_filtering || f_where {
-                // Calculate cosine similarity against the 'query' sentence.
-                let dot_product: f32 = query.iter().zip(cv.iter()).map(|(a, b)| a * b).sum();
-                if dot_product > 0.0 {
-                    r_docs.push(String::from(sentence));
-                    r_sims.push(dot_product);
-                    r_meta.push(raw_m.to_vec());
-                }
-            }
-        }
-        if r_docs.len() < num_results || num_results == 0 {
-            Ok(CosineQueryResult::create(r_docs, r_sims, r_meta))
-        } else {
-            Ok(CosineQueryResult::create(
-                r_docs[0..num_results].to_vec(),
-                r_sims[0..num_results].to_vec(),
-                r_meta[0..num_results].to_vec(),
-            ))
+        let (collection, _, scored) = query_scores(valentinus, query_string, view_name, f_where)?;
+        Ok(cosine_result(&collection, scored, num_results, None))
+    }
+    /// Cosine query that only returns the `num_results` most similar
+    ///
+    /// documents with a similarity of at least `min_score`, most similar
+    ///
+    /// first. Use a threshold to tell when nothing in the collection is
+    ///
+    /// relevant.
+    pub fn cosine_query_min_score(
+        valentinus: &Valentinus,
+        query_string: String,
+        view_name: String,
+        num_results: usize,
+        f_where: Option<Vec<String>>,
+        min_score: f32,
+    ) -> Result<CosineQueryResult, ValentinusError> {
+        let (collection, _, mut scored) =
+            query_scores(valentinus, query_string, view_name, f_where)?;
+        sort_by_score(&mut scored);
+        let min_score: Option<f32> = Some(min_score);
+        Ok(cosine_result(&collection, scored, num_results, min_score))
+    }
+    /// Range search: every document within cosine distance `max_distance`
+    ///
+    /// of the query, i.e. with a similarity of at least `1 - max_distance`,
+    ///
+    /// most similar first. Useful for finding near duplicates.
+    pub fn range_query(
+        valentinus: &Valentinus,
+        query_string: String,
+        view_name: String,
+        max_distance: f32,
+        f_where: Option<Vec<String>>,
+    ) -> Result<CosineQueryResult, ValentinusError> {
+        let (collection, _, mut scored) =
+            query_scores(valentinus, query_string, view_name, f_where)?;
+        sort_by_score(&mut scored);
+        let min_score: Option<f32> = Some(1.0 - max_distance);
+        Ok(cosine_result(&collection, scored, 0, min_score))
+    }
+    /// Cosine query reranked by maximal marginal relevance, so near
+    ///
+    /// duplicates don't crowd out other results. The `pool` most similar
+    ///
+    /// documents, all with `pool=0`, are reranked with their stored
+    ///
+    /// embeddings: `lambda=1.0` keeps the similarity order and lower values
+    ///
+    /// trade similarity for diversity. Returns up to `num_results` documents
+    ///
+    /// in the reranked order with their similarity to the query.
+    pub fn mmr_query(
+        valentinus: &Valentinus,
+        query_string: String,
+        view_name: String,
+        num_results: usize,
+        f_where: Option<Vec<String>>,
+        lambda: f32,
+        pool: usize,
+    ) -> Result<CosineQueryResult, ValentinusError> {
+        let (collection, placement, mut scored) =
+            query_scores(valentinus, query_string, view_name, f_where)?;
+        let reranked = mmr_rerank(
+            valentinus,
+            &collection,
+            placement,
+            &mut scored,
+            lambda,
+            pool,
+            num_results,
+        )?;
+        Ok(cosine_result(&collection, reranked, 0, None))
+    }
+    /// Cosine query many query strings at once. The queries are embedded
+    ///
+    /// in one batch and the collection is read once, then each is answered
+    ///
+    /// as by `cosine_query`, with one result per query in the same order.
+    pub fn batch_query(
+        valentinus: &Valentinus,
+        queries: &[String],
+        view_name: String,
+        num_results: usize,
+        f_where: Option<Vec<String>>,
+    ) -> Result<Vec<CosineQueryResult>, ValentinusError> {
+        info!("batch querying {} embedding collection", view_name);
+        let (collection, placement) = lookup(valentinus, None, Some(view_name))?;
+        if queries.is_empty() {
+            return Ok(Vec::new());
         }
+        let qv: Array2<f32> =
+            batch_embeddings(&collection.model_path, queries, &collection.embedder).map_err(
+                |_| {
+                    error!("failed to generate embeddings for query vectors");
+                    ValentinusError::CosineError
+                },
+            )?;
+        info!("calculating cosine similarity of {} queries", queries.len());
+        let rows: Vec<usize> = filter_rows(&collection, f_where)?;
+        let batched = score_queries(valentinus, &collection, placement, qv.view(), &rows)?;
+        Ok(batched
+            .into_iter()
+            .map(|scored| cosine_result(&collection, scored, num_results, None))
+            .collect())
     }
     /// Calculate the nearest vector using KdTree with eclidean distance.
     ///
     /// Returns `usize` index of the document matching the nearest embedding.
+    ///
+    /// Quantized collections return the most similar rescored candidate, or
+    ///
+    /// the best of the first pass when nothing is rescored.
     pub fn nearest_query(
+        valentinus: &Valentinus,
         query_string: String,
         view_name: String,
     ) -> Result<usize, ValentinusError> {
         info!("querying {} embedding collection for nearest", view_name);
-        let collection: EmbeddingCollection = find(None, Some(view_name))?;
+        let (collection, placement) = lookup(valentinus, None, Some(view_name))?;
         let qv_string = vec![query_string];
-        let qv_output = batch_embeddings(&collection.model_path, &qv_string);
+        let qv_output =
+            batch_embeddings(&collection.model_path, &qv_string, &collection.embedder);
         if qv_output.is_err() {
             error!("failed to generate embeddings for query vector");
             return Err(ValentinusError::NearestError);
         }
         let qv = qv_output.unwrap_or_default();
-        let cv = collection.embeddings;
         info!("computing nearest embedding");
-        // Kdtree using Euclidean distance
-        let nn = CommonNearestNeighbour::KdTree
-            .batch(&cv, L2Dist)
-            .map_err(|_| ValentinusError::NearestError)?;
-        // Compute the nearest point to the query vector
-        let nearest = nn
-            .k_nearest(qv.index_axis(Axis(0), 0), 1)
-            .map_err(|_| ValentinusError::NearestError)?;
-        let location = cv
-            .axis_iter(Axis(0))
-            .position(|x| x.to_vec() == nearest[0].0.to_vec());
+        if collection.quantization != Quantization::None {
+            let rows: Vec<usize> = (0..collection.documents.len()).collect();
+            let scored: Vec<(usize, f32)> = score_rows(
+                valentinus,
+                &collection,
+                placement,
+                qv.index_axis(Axis(0), 0),
+                &rows,
+            )?;
+            return scored
+                .into_iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(index, _)| index)
+                .ok_or(ValentinusError::NearestError);
+        }
+        let location = scan_embeddings(valentinus, &collection, placement, |cv| {
+            // Kdtree using Euclidean distance
+            let nn = CommonNearestNeighbour::KdTree
+                .batch(&cv, L2Dist)
+                .map_err(|_| ValentinusError::NearestError)?;
+            // Compute the nearest point to the query vector
+            let nearest = nn
+                .k_nearest(qv.index_axis(Axis(0), 0), 1)
+                .map_err(|_| ValentinusError::NearestError)?;
+            Ok(cv
+                .axis_iter(Axis(0))
+                .position(|x| x.to_vec() == nearest[0].0.to_vec()))
+        })?;
         if location.is_none() {
             log::error!("could not compute nearest");
             return Err(ValentinusError::NearestError);
         }
         Ok(location.unwrap_or_default())
     }
-    /// Delete a collection from the database
-    pub fn delete(view_name: String) -> Result<(), ValentinusError> { 
+    /// Delete a collection from the database. The collection and its
+    ///
+    /// index entries are removed in one transaction.
+    pub fn delete(valentinus: &Valentinus, view_name: String) -> Result<(), ValentinusError> {
         info!("deleting {} embedding collection", view_name);
-        let collection: EmbeddingCollection = find(None, Some(String::from(&view_name)))?;
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
+        valentinus.check_writable()?;
+        let collection: EmbeddingCollection =
+            find(valentinus, None, Some(String::from(&view_name)))?;
+        let mut batch = WriteBatch::new();
         let s_key = String::from(&collection.key);
         let b_key: Vec<u8> = Vec::from(s_key.as_bytes());
-        DatabaseEnvironment::delete(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
-        // update collections keys
-        let b_keys: Vec<u8> = Vec::from(VALENTINUS_KEYS.as_bytes());
-        let v_keys: Vec<u8> = Vec::from(VALENTINUS_VIEWS.as_bytes());
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
-        let all_keys = DatabaseEnvironment::read(&db.env, &db.handle, &b_keys)
-            .map_err(ValentinusError::DatabaseError)?;
-        let all_views = DatabaseEnvironment::read(&db.env, &db.handle, &v_keys)
-            .map_err(ValentinusError::DatabaseError)?;
-        let mut keys_indexer: KeyViewIndexer = bincode::deserialize(&all_keys[..]).unwrap_or_default();
-        let mut views_indexer: KeyViewIndexer = bincode::deserialize(&all_views[..]).unwrap_or_default();
-        let key_del_index = keys_indexer.values.iter().position(|x| x == &collection.key).unwrap();
-        keys_indexer.values.remove(key_del_index);
-        let views_del_index = views_indexer.values.iter().position(|x| x == &view_name).unwrap();
-        views_indexer.values.remove(views_del_index);
-        // reset the indexers
-        let b_keys_indexer: Vec<u8> =
-            bincode::serialize(&keys_indexer).map_err(|_| ValentinusError::BincodeError)?;
-        let b_views_indexer: Vec<u8> =
-            bincode::serialize(&views_indexer).map_err(|_| ValentinusError::BincodeError)?;
-        DatabaseEnvironment::delete(&db.env, &db.handle, &b_keys)
-            .map_err(ValentinusError::DatabaseError)?;
-        DatabaseEnvironment::delete(&db.env, &db.handle, &v_keys)
-            .map_err(ValentinusError::DatabaseError)?;
-        write_chunks(&db.env, &db.handle, &b_keys, &b_keys_indexer)
-            .map_err(ValentinusError::DatabaseError)?;
-        write_chunks(&db.env, &db.handle, &v_keys, &b_views_indexer)
-            .map_err(ValentinusError::DatabaseError)?;
+        batch.delete(&b_key);
+        batch.delete(vectors_key(&s_key).as_bytes());
+        batch.delete(quantized_key(&s_key).as_bytes());
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        stage_index_delete(db, &s_key, &mut batch)?;
+        let kv_lookup_key: String = format!("{}-{}", VALENTINUS_KEY, view_name);
+        batch.delete(kv_lookup_key.as_bytes());
+        // update collections keys in the same transaction
+        db.update(&batch, &mut |read, staged| {
+            stage_indexer_remove(read, VALENTINUS_KEYS, &collection.key, staged)?;
+            stage_indexer_remove(read, VALENTINUS_VIEWS, &view_name, staged)
+        })
+        .map_err(ValentinusError::from)?;
         Ok(())
     }
+    /// Train an IVF-PQ index over the embeddings of a collection for
+    ///
+    /// `ivf_query`, replacing any previous index.
+    pub fn train_ivf_pq(
+        valentinus: &Valentinus,
+        view_name: String,
+        config: &IvfPqConfig,
+    ) -> Result<(), ValentinusError> {
+        info!("training IVF-PQ index of {}", view_name);
+        valentinus.check_writable()?;
+        let collection: EmbeddingCollection = find(valentinus, None, Some(view_name))?;
+        let (raw_head, raw_lists) = encode_index(collection.embeddings.view(), config)?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        let mut batch = WriteBatch::new();
+        stage_index_delete(db, &collection.key, &mut batch)?;
+        batch.put(ivf_key(&collection.key).as_bytes(), &raw_head);
+        for (n, raw) in raw_lists.iter().enumerate() {
+            batch.put_unchunked(ivf_list_key(&collection.key, n).as_bytes(), raw);
+        }
+        db.commit_batch(&batch).map_err(ValentinusError::from)
+    }
+    /// Retrain the IVF-PQ index of a collection with the settings it was
+    ///
+    /// trained with, i.e. once its embeddings drifted from the centroids.
+    pub fn retrain_ivf_pq(
+        valentinus: &Valentinus,
+        view_name: String,
+    ) -> Result<(), ValentinusError> {
+        let (collection, _) = lookup(valentinus, None, Some(String::from(&view_name)))?;
+        let config: IvfPqConfig = read_index(valentinus, &collection)?.get_config().clone();
+        EmbeddingCollection::train_ivf_pq(valentinus, view_name, &config)
+    }
+    /// True when the IVF-PQ index of a collection wasn't trained on its
+    ///
+    /// current embeddings and should be retrained
+    pub fn is_ivf_pq_stale(
+        valentinus: &Valentinus,
+        view_name: String,
+    ) -> Result<bool, ValentinusError> {
+        let collection: EmbeddingCollection = find(valentinus, None, Some(view_name))?;
+        Ok(read_index(valentinus, &collection)?.is_stale(collection.embeddings.view()))
+    }
+    /// Query the IVF-PQ index of a collection trained by `train_ivf_pq`.
+    ///
+    /// Only the `nprobe` lists nearest to the query are scanned; more lists
+    ///
+    /// find more neighbours but read more. Returns up to `num_results`
+    ///
+    /// documents, most similar first, with the cosine similarity of unit
+    ///
+    /// embeddings approximated from the encoded distance.
+    pub fn ivf_query(
+        valentinus: &Valentinus,
+        query_string: String,
+        view_name: String,
+        num_results: usize,
+        nprobe: usize,
+    ) -> Result<CosineQueryResult, ValentinusError> {
+        info!("querying IVF-PQ index of {}", view_name);
+        let (collection, _) = lookup(valentinus, None, Some(view_name))?;
+        let qv_string = vec![query_string];
+        let qv: Array2<f32> =
+            batch_embeddings(&collection.model_path, &qv_string, &collection.embedder).map_err(
+                |_| {
+                    error!("failed to generate embeddings for query vector");
+                    ValentinusError::CosineError
+                },
+            )?;
+        let found: Vec<(usize, f32)> =
+            ivf_search(valentinus, &collection, qv.row(0), num_results, nprobe)?;
+        let mut r_docs: Vec<String> = Vec::new();
+        let mut r_sims: Vec<f32> = Vec::new();
+        let mut r_meta: Vec<Vec<String>> = Vec::new();
+        let mut r_parents: Vec<String> = Vec::new();
+        for (index, similarity) in found {
+            r_docs.push(String::from(&collection.documents[index]));
+            r_sims.push(similarity);
+            r_meta.push(collection.metadata[index].to_vec());
+            r_parents.push(collection.parent_of(index));
+        }
+        let mut result = CosineQueryResult::create(r_docs, r_sims, r_meta);
+        result.parents = r_parents;
+        Ok(result)
+    }
+    /// Sizes of a stored collection before and after compression, read
+    ///
+    /// without decompressing its documents or embeddings.
+    pub fn compression_stats(
+        valentinus: &Valentinus,
+        view_name: String,
+    ) -> Result<CompressionStats, ValentinusError> {
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        let kv_lookup: String = format!("{}-{}", VALENTINUS_KEY, view_name);
+        let key: Vec<u8> = db
+            .read(kv_lookup.as_bytes())
+            .map_err(ValentinusError::from)?;
+        let collection: Vec<u8> = db.read(&key).map_err(ValentinusError::from)?;
+        collection_stats(&collection)
+    }
     /// Getter for documents
     pub fn get_documents(&self) -> &Vec<String> {
         &self.documents
@@ -465,6 +976,48 @@ impl EmbeddingCollection {
     pub fn get_ids(&self) -> &Vec<String> {
         &self.ids
     }
+    /// Getter for parent ids of chunked documents
+    pub fn get_parents(&self) -> &Vec<String> {
+        &self.parents
+    }
+    /// Split the documents into chunks. Must be called before `save`.
+    ///
+    /// Each chunk inherits the metadata of its document, gets the id
+    ///
+    /// `{parent id}-{chunk number}` and records the parent id.
+    pub fn chunk_documents(&mut self, splitter: &Splitter) -> Result<(), ValentinusError> {
+        info!("chunking {} documents", self.documents.len());
+        let chunks = split_documents(splitter, &self.documents, &self.model_path)
+            .map_err(ValentinusError::ChunkError)?;
+        let mut documents: Vec<String> = Vec::new();
+        let mut metadata: Vec<Vec<String>> = Vec::new();
+        let mut ids: Vec<String> = Vec::new();
+        let mut parents: Vec<String> = Vec::new();
+        // numbered per parent, so chunking again doesn't repeat ids
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for (index, chunk) in chunks {
+            let parent = self.parent_of(index);
+            let count = counts.entry(String::from(&parent)).or_default();
+            ids.push(format!("{}-{}", parent, count));
+            *count += 1;
+            metadata.push(self.metadata.get(index).cloned().unwrap_or_default());
+            parents.push(parent);
+            documents.push(chunk);
+        }
+        self.documents = documents;
+        self.metadata = metadata;
+        self.ids = ids;
+        self.parents = parents;
+        Ok(())
+    }
+    /// Parent id of a document, or its own id if it was not chunked
+    fn parent_of(&self, index: usize) -> String {
+        self.parents
+            .get(index)
+            .or(self.ids.get(index))
+            .cloned()
+            .unwrap_or_default()
+    }
     /// Getter for key
     pub fn get_key(&self) -> &String {
         &self.key
@@ -473,99 +1026,464 @@ impl EmbeddingCollection {
     pub fn get_view(&self) -> &String {
         &self.view
     }
+    /// Getter for the embedder configuration
+    pub fn get_embedder_config(&self) -> &EmbedderConfig {
+        &self.embedder
+    }
+    /// Setter for the embedder configuration. Set before `save` so that
+    ///
+    /// documents and later queries are tokenized the same way.
+    pub fn set_embedder_config(&mut self, config: EmbedderConfig) {
+        self.embedder = config;
+    }
+    /// Getter for compression
+    pub fn get_compression(&self) -> Compression {
+        self.compression
+    }
+    /// Setter for the compression used by `save`. Documents and metadata
+    ///
+    /// are compressed with the codec, embeddings are byte shuffled first.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    /// Getter for quantization
+    pub fn get_quantization(&self) -> Quantization {
+        self.quantization
+    }
+    /// Setter for the quantization used by `save`. Queries score the
+    ///
+    /// quantized embeddings first, see `set_rescore`.
+    pub fn set_quantization(&mut self, quantization: Quantization) {
+        self.quantization = quantization;
+    }
+    /// Getter for the number of rescored candidates
+    pub fn get_rescore(&self) -> usize {
+        self.rescore
+    }
+    /// Setter for the number of candidates of the quantized first pass
+    ///
+    /// rescored with the full embeddings. With 0 only the quantized scores
+    ///
+    /// are used.
+    pub fn set_rescore(&mut self, rescore: usize) {
+        self.rescore = rescore;
+    }
+    /// True when only the quantized embeddings are stored
+    pub fn is_quantized_only(&self) -> bool {
+        self.quantized_only
+    }
+    /// Opt in to dropping the full embeddings of a quantized collection
+    ///
+    /// that isn't rescored, which saves the most space. They can't be
+    ///
+    /// recovered and `find` returns approximations. Ignored without a
+    ///
+    /// quantization or with rescoring, which need the full embeddings.
+    pub fn set_quantized_only(&mut self, quantized_only: bool) {
+        self.quantized_only = quantized_only;
+    }
     /// Setter for embeddings
     fn set_embeddings(&mut self, embeddings: Array2<f32>) {
         self.embeddings = embeddings;
     }
-    /// Sets the list of views in the database
-    fn set_view_indexes(&self) -> Result<(), ValentinusError> {
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
-        let b_key: Vec<u8> = Vec::from(VALENTINUS_VIEWS.as_bytes());
-        // get the current indexes
-        let b_keys: Vec<u8> = DatabaseEnvironment::read(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
-        let kv_index: KeyViewIndexer = bincode::deserialize(&b_keys[..]).unwrap_or_default();
-        let mut current_keys: Vec<String> = Vec::new();
-        if !kv_index.values.is_empty() {
-            for i in kv_index.values {
-                current_keys.push(i);
-            }
-        }
-        // set the new index
-        current_keys.push(String::from(&self.view));
-        let v_indexer: KeyViewIndexer = KeyViewIndexer::new(&current_keys);
-        let b_v_indexer: Vec<u8> =
-            bincode::serialize(&v_indexer).map_err(|_| ValentinusError::BincodeError)?;
-        DatabaseEnvironment::delete(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
-        write_chunks(&db.env, &db.handle, &b_key, &b_v_indexer)
-            .map_err(ValentinusError::DatabaseError)?;
-        Ok(())
-    }
-    /// Sets the lists of keys in the database
-    fn set_key_indexes(&self) -> Result<(), ValentinusError> {
-        // set the keys indexer
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
-        let b_key: Vec<u8> = Vec::from(VALENTINUS_KEYS.as_bytes());
-        // get the current indexes
-        let b_keys: Vec<u8> = DatabaseEnvironment::read(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
-        let kv_index: KeyViewIndexer = bincode::deserialize(&b_keys[..]).unwrap_or_default();
-        let mut current_keys: Vec<String> = Vec::new();
-        if !kv_index.values.is_empty() {
-            for i in kv_index.values {
-                current_keys.push(i);
-            }
-        }
-        // set the new index
-        current_keys.push(String::from(&self.key));
-        let k_indexer: KeyViewIndexer = KeyViewIndexer::new(&current_keys);
-        let b_k_indexer: Vec<u8> =
-            bincode::serialize(&k_indexer).map_err(|_| ValentinusError::BincodeError)?;
-        write_chunks(&db.env, &db.handle, &b_key, &b_k_indexer)
-            .map_err(ValentinusError::DatabaseError)?;
-        Ok(())
-    }
-    /// Sets key-to-view lookups
-    fn set_kv_index(&self) -> Result<(), ValentinusError> {
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
+    /// Stages key-to-view lookups
+    fn set_kv_index(&self, batch: &mut WriteBatch) {
         let kv_lookup_key: String = format!("{}-{}", VALENTINUS_KEY, self.view);
         let b_kv_lookup_key: Vec<u8> = Vec::from(kv_lookup_key.as_bytes());
         let kv_lookup_value: String = String::from(&self.key);
         let b_v_indexer: Vec<u8> = Vec::from(kv_lookup_value.as_bytes());
-        write_chunks(&db.env, &db.handle, &b_kv_lookup_key, &b_v_indexer)
-            .map_err(ValentinusError::DatabaseError)?;
-        Ok(())
+        batch.put(&b_kv_lookup_key, &b_v_indexer);
     }
 }
 
 /// Look up a collection by key or view. If both key and view are passed,
 ///
 /// then key lookup will override the latter.
-pub fn find(key: Option<String>, view: Option<String>) -> Result<EmbeddingCollection, ValentinusError> {
+pub fn find(
+    valentinus: &Valentinus,
+    key: Option<String>,
+    view: Option<String>,
+) -> Result<EmbeddingCollection, ValentinusError> {
+    let (mut result, placement) = lookup(valentinus, key, view)?;
+    let db: &dyn StorageBackend = valentinus.db.as_ref();
+    match placement {
+        Placement::Inline => {}
+        Placement::Detached => {
+            let raw: Vec<u8> = db
+                .read(vectors_key(&result.key).as_bytes())
+                .map_err(ValentinusError::from)?;
+            result.embeddings = view_vectors(&raw)?.into_owned();
+        }
+        Placement::Quantized => {
+            let raw: Vec<u8> = db
+                .read(quantized_key(&result.key).as_bytes())
+                .map_err(ValentinusError::from)?;
+            result.embeddings = view_quantized(&raw)?.dequantize();
+        }
+    }
+    Ok(result)
+}
+
+/// Look up a collection like `find`, leaving embeddings that aren't
+///
+/// inline empty. Returns where they are.
+fn lookup(
+    valentinus: &Valentinus,
+    key: Option<String>,
+    view: Option<String>,
+) -> Result<(EmbeddingCollection, Placement), ValentinusError> {
     if key.is_some() {
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
         let s_key = key.unwrap_or_default();
         let b_key: Vec<u8> = Vec::from(s_key.as_bytes());
-        let collection: Vec<u8> = DatabaseEnvironment::read(&db.env, &db.handle, &b_key)
-            .map_err(ValentinusError::DatabaseError)?;
-        let result: EmbeddingCollection =
-            bincode::deserialize(&collection[..]).map_err(|_| ValentinusError::BincodeError)?;
-        Ok(result)
+        let collection: Vec<u8> = db.read(&b_key).map_err(ValentinusError::from)?;
+        decode_stored(&collection)
     } else {
         info!("performing key view lookup");
-        let db: &DatabaseEnvironment = &DATABASE_LOCK;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
         let s_view = view.unwrap_or_default();
         let kv_lookup: String = format!("{}-{}", VALENTINUS_KEY, s_view);
         let b_kv_lookup: Vec<u8> = Vec::from(kv_lookup.as_bytes());
-        let key: Vec<u8> = DatabaseEnvironment::read(&db.env, &db.handle, &b_kv_lookup)
-            .map_err(ValentinusError::DatabaseError)?;
-        let collection: Vec<u8> = DatabaseEnvironment::read(&db.env, &db.handle, &key)
-            .map_err(ValentinusError::DatabaseError)?;
-        let result: EmbeddingCollection =
-            bincode::deserialize(&collection[..]).map_err(|_| ValentinusError::BincodeError)?;
-        Ok(result)
+        let key: Vec<u8> = db.read(&b_kv_lookup).map_err(ValentinusError::from)?;
+        let collection: Vec<u8> = db.read(&key).map_err(ValentinusError::from)?;
+        decode_stored(&collection)
+    }
+}
+
+/// Stage `value` into the indexer stored under `indexer_key`, as read
+///
+/// inside `StorageBackend::update`. Values already listed aren't repeated.
+fn stage_indexer_insert(
+    read: &UpdateReader,
+    indexer_key: &str,
+    value: &str,
+    batch: &mut WriteBatch,
+) -> Result<(), MdbError> {
+    let raw: Vec<u8> = read(indexer_key.as_bytes())?;
+    let mut indexer: KeyViewIndexer = bincode::deserialize(&raw[..]).unwrap_or_default();
+    if indexer.values.iter().any(|v| v == value) {
+        return Ok(());
+    }
+    indexer.values.push(String::from(value));
+    let b_indexer: Vec<u8> = bincode::serialize(&indexer).map_err(|_| MdbError::Panic)?;
+    batch.put(indexer_key.as_bytes(), &b_indexer);
+    Ok(())
+}
+
+/// Stage removing `value` from the indexer stored under `indexer_key`, as
+///
+/// read inside `StorageBackend::update`. Errors when it isn't listed, i.e.
+///
+/// the indexers are out of sync and need `Valentinus::verify` repairs.
+fn stage_indexer_remove(
+    read: &UpdateReader,
+    indexer_key: &str,
+    value: &str,
+    batch: &mut WriteBatch,
+) -> Result<(), MdbError> {
+    let raw: Vec<u8> = read(indexer_key.as_bytes())?;
+    let mut indexer: KeyViewIndexer = bincode::deserialize(&raw[..]).unwrap_or_default();
+    let Some(index) = indexer.values.iter().position(|v| v == value) else {
+        error!("{} is missing from the {} indexer", value, indexer_key);
+        return Err(MdbError::StateError(format!(
+            "{} is missing from the {} indexer, repair with verify",
+            value, indexer_key
+        )));
+    };
+    indexer.values.remove(index);
+    let b_indexer: Vec<u8> = bincode::serialize(&indexer).map_err(|_| MdbError::Panic)?;
+    batch.put(indexer_key.as_bytes(), &b_indexer);
+    Ok(())
+}
+
+/// Read the IVF-PQ index of a collection from `lookup`
+fn read_index(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+) -> Result<IvfPqHead, ValentinusError> {
+    let db: &dyn StorageBackend = valentinus.db.as_ref();
+    let raw: Vec<u8> = db
+        .read(ivf_key(&collection.key).as_bytes())
+        .map_err(ValentinusError::from)?;
+    if raw.is_empty() {
+        error!("collection {} has no IVF-PQ index", collection.key);
+        return Err(ValentinusError::IndexError);
+    }
+    let head: IvfPqHead = decode_head(&raw)?;
+    if head.get_rows() != collection.documents.len() as u64 {
+        error!("IVF-PQ index of {} is stale", collection.key);
+        return Err(ValentinusError::IndexError);
+    }
+    Ok(head)
+}
+
+/// The `k` rows of a collection from `lookup` nearest to `query` in its
+///
+/// IVF-PQ index, with their approximate similarity, most similar first
+fn ivf_search(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    query: ArrayView1<f32>,
+    k: usize,
+    nprobe: usize,
+) -> Result<Vec<(usize, f32)>, ValentinusError> {
+    let head: IvfPqHead = read_index(valentinus, collection)?;
+    let lists: usize = head.get_lists();
+    debug!("probing {} of {} lists", nprobe.clamp(1, lists), lists);
+    let mut found: Vec<(usize, f32)> = Vec::new();
+    for list in head.probe(query, nprobe)? {
+        let key: String = ivf_list_key(&collection.key, list);
+        found.extend(scan_value(valentinus, &key, |raw| {
+            head.scan_list(query, list, raw)
+        })?);
+    }
+    // drop rows past the collection before keeping the nearest `k`
+    found.retain(|(row, _)| *row < collection.documents.len());
+    found.sort_by(|a, b| a.1.total_cmp(&b.1));
+    if k != 0 {
+        found.truncate(k);
     }
+    Ok(found
+        .into_iter()
+        .map(|(row, d)| (row, 1.0 - d / 2.0))
+        .collect())
+}
+
+/// Run `f` over the value of `key`, lent in place where the backend can
+fn scan_value<T>(
+    valentinus: &Valentinus,
+    key: &str,
+    f: impl FnOnce(&[u8]) -> Result<T, ValentinusError>,
+) -> Result<T, ValentinusError> {
+    let mut f = Some(f);
+    let mut result: Option<Result<T, ValentinusError>> = None;
+    let db: &dyn StorageBackend = valentinus.db.as_ref();
+    db.scan(key.as_bytes(), &mut |raw| {
+        if let Some(f) = f.take() {
+            result = Some(f(raw));
+        }
+    })
+    .map_err(ValentinusError::from)?;
+    result.unwrap_or(Err(ValentinusError::CorruptionError))
+}
+
+/// Run `f` over the full embeddings of a collection from `lookup`
+fn scan_embeddings<T>(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    placement: Placement,
+    f: impl FnOnce(ArrayView2<f32>) -> Result<T, ValentinusError>,
+) -> Result<T, ValentinusError> {
+    match placement {
+        Placement::Inline => f(collection.embeddings.view()),
+        Placement::Detached => scan_value(valentinus, &vectors_key(&collection.key), |raw| {
+            f(view_vectors(raw)?.view())
+        }),
+        Placement::Quantized => {
+            error!(
+                "collection {} only stores quantized embeddings",
+                collection.key
+            );
+            Err(ValentinusError::QuantizationError)
+        }
+    }
+}
+
+/// Rows of a collection whose metadata matches the `f_where` filter
+fn filter_rows(
+    collection: &EmbeddingCollection,
+    f_where: Option<Vec<String>>,
+) -> Result<Vec<usize>, ValentinusError> {
+    let Some(raw_f) = f_where else {
+        return Ok((0..collection.documents.len()).collect());
+    };
+    let mut rows: Vec<usize> = Vec::new();
+    for (index, raw_m) in collection.metadata.iter().enumerate() {
+        if filter_where(&raw_f, raw_m).map_err(|_| ValentinusError::Md2fsError)? {
+            rows.push(index);
+        }
+    }
+    Ok(rows)
+}
+
+/// Rows of the embeddings of a collection from `lookup`, approximated when
+///
+/// only the quantized embeddings are stored
+fn select_embeddings(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    placement: Placement,
+    rows: &[usize],
+) -> Result<Array2<f32>, ValentinusError> {
+    if placement == Placement::Quantized {
+        return scan_value(valentinus, &quantized_key(&collection.key), |raw| {
+            Ok(view_quantized(raw)?.dequantize().select(Axis(0), rows))
+        });
+    }
+    scan_embeddings(valentinus, collection, placement, |cv| {
+        Ok(cv.select(Axis(0), rows))
+    })
+}
+
+/// Rerank scored rows of a collection from `lookup` by maximal marginal
+///
+/// relevance, keeping the best `num_results` of the `pool` most similar
+fn mmr_rerank(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    placement: Placement,
+    scored: &mut Vec<(usize, f32)>,
+    lambda: f32,
+    pool: usize,
+    num_results: usize,
+) -> Result<Vec<(usize, f32)>, ValentinusError> {
+    scored.retain(|(_, similarity)| *similarity > 0.0);
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    if pool != 0 {
+        scored.truncate(pool);
+    }
+    let rows: Vec<usize> = scored.iter().map(|(r, _)| *r).collect();
+    let relevance: Vec<f32> = scored.iter().map(|(_, s)| *s).collect();
+    let candidates: Array2<f32> = select_embeddings(valentinus, collection, placement, &rows)?;
+    let k: usize = match num_results {
+        0 => rows.len(),
+        n => n,
+    };
+    info!("reranking {} candidates by MMR", rows.len());
+    Ok(mmr(candidates.view(), &relevance, lambda, k)?
+        .into_iter()
+        .map(|i| scored[i])
+        .collect())
+}
+
+/// Rows of a collection with their similarity to a query
+type ScoredRows = Vec<(usize, f32)>;
+
+/// Embed `query_string` and score the rows of the `view_name` collection
+///
+/// matching `f_where` against it, by row
+fn query_scores(
+    valentinus: &Valentinus,
+    query_string: String,
+    view_name: String,
+    f_where: Option<Vec<String>>,
+) -> Result<(EmbeddingCollection, Placement, ScoredRows), ValentinusError> {
+    info!("querying {} embedding collection", view_name);
+    let (collection, placement) = lookup(valentinus, None, Some(view_name))?;
+    let qv_string = vec![query_string];
+    let qv_output = batch_embeddings(&collection.model_path, &qv_string, &collection.embedder);
+    if qv_output.is_err() {
+        error!("failed to generate embeddings for query vector");
+        return Err(ValentinusError::CosineError);
+    }
+    let qv = qv_output.unwrap_or_default();
+    info!("calculating cosine similarity");
+    let query = qv.index_axis(Axis(0), 0);
+    let rows: Vec<usize> = filter_rows(&collection, f_where)?;
+    // Calculate cosine similarity against the 'query' sentence.
+    let scored = score_rows(valentinus, &collection, placement, query, &rows)?;
+    Ok((collection, placement, scored))
+}
+
+/// Order scored rows most similar first, ties by row
+fn sort_by_score(scored: &mut ScoredRows) {
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+}
+
+/// Result of a cosine query from the scored rows of a collection, keeping
+///
+/// the first `num_results` in the order given with a similarity of at least
+///
+/// `min_score`, or positive without one
+fn cosine_result(
+    collection: &EmbeddingCollection,
+    scored: Vec<(usize, f32)>,
+    num_results: usize,
+    min_score: Option<f32>,
+) -> CosineQueryResult {
+    let mut r_docs: Vec<String> = Vec::new();
+    let mut r_sims: Vec<f32> = Vec::new();
+    let mut r_meta: Vec<Vec<String>> = Vec::new();
+    let mut r_parents: Vec<String> = Vec::new();
+    for (index, dot_product) in scored {
+        if num_results != 0 && r_docs.len() == num_results {
+            break;
+        }
+        let keep: bool = match min_score {
+            Some(min_score) => dot_product >= min_score,
+            None => dot_product > 0.0,
+        };
+        if keep {
+            r_docs.push(String::from(&collection.documents[index]));
+            r_sims.push(dot_product);
+            r_meta.push(collection.metadata[index].to_vec());
+            r_parents.push(collection.parent_of(index));
+        }
+    }
+    let mut result = CosineQueryResult::create(r_docs, r_sims, r_meta);
+    result.parents = r_parents;
+    result
+}
+
+/// `score_rows` for every row of `queries`, reading the embeddings of
+///
+/// the collection once when they're scored exactly
+fn score_queries(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    placement: Placement,
+    queries: ArrayView2<f32>,
+    rows: &[usize],
+) -> Result<Vec<Vec<(usize, f32)>>, ValentinusError> {
+    if collection.quantization == Quantization::None {
+        return scan_embeddings(valentinus, collection, placement, |cv| {
+            queries
+                .axis_iter(Axis(0))
+                .map(|query| score_rows_exact(cv, query, rows))
+                .collect()
+        });
+    }
+    queries
+        .axis_iter(Axis(0))
+        .map(|query| score_rows(valentinus, collection, placement, query, rows))
+        .collect()
+}
+
+/// Similarity of `query` to each of `rows` of a collection from `lookup`,
+///
+/// by row. Quantized collections are scored approximately and the best
+///
+/// `rescore` candidates, if any, rescored with the full embeddings.
+fn score_rows(
+    valentinus: &Valentinus,
+    collection: &EmbeddingCollection,
+    placement: Placement,
+    query: ArrayView1<f32>,
+    rows: &[usize],
+) -> Result<Vec<(usize, f32)>, ValentinusError> {
+    if collection.quantization == Quantization::None {
+        return scan_embeddings(valentinus, collection, placement, |cv| {
+            score_rows_exact(cv, query, rows)
+        });
+    }
+    let approximate: Vec<f32> = scan_value(valentinus, &quantized_key(&collection.key), |raw| {
+        view_quantized(raw)?.scores(query)
+    })?;
+    let mut scored: Vec<(usize, f32)> = rows
+        .iter()
+        .filter(|r| **r < approximate.len())
+        .map(|r| (*r, approximate[*r]))
+        .collect();
+    if collection.rescore > 0 {
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(collection.rescore);
+        let candidates: Vec<usize> = scored.iter().map(|(r, _)| *r).collect();
+        scored = scan_embeddings(valentinus, collection, placement, |cv| {
+            score_rows_exact(cv, query, &candidates)
+        })?;
+        scored.sort_by_key(|(r, _)| *r);
+    }
+    Ok(scored)
 }
 
 #[cfg(test)]
@@ -616,19 +1534,28 @@ mod tests {
         for i in 0..documents.len() {
             ids.push(format!("id{}", i));
         }
+        let valentinus = Valentinus::in_memory();
         let model_path = String::from("all-MiniLM-L6-v2_onnx");
         let model_type = ModelType::AllMiniLmL6V2;
         let name = String::from("test_collection");
         let expected: Vec<String> = documents.clone();
-        let mut ec: EmbeddingCollection =
-            EmbeddingCollection::new(documents, metadata, ids, name, model_type, model_path)?;
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            documents,
+            metadata,
+            ids,
+            name,
+            model_type,
+            model_path,
+        )?;
         let created_docs: &Vec<String> = ec.get_documents();
         assert_eq!(expected, created_docs.to_vec());
         // save collection to db
-        ec.save()?;
+        ec.save(&valentinus)?;
         // query the collection
         let query_string: &String = &String::from("Find the best reviews.");
         let result: CosineQueryResult = EmbeddingCollection::cosine_query(
+            &valentinus,
             String::from(query_string),
             String::from(ec.get_view()),
             10,
@@ -657,6 +1584,7 @@ mod tests {
             year_filter
         );
         let no_filter_result: CosineQueryResult = EmbeddingCollection::cosine_query(
+            &valentinus,
             String::from(query_string),
             String::from(ec.get_view()),
             5,
@@ -664,7 +1592,7 @@ mod tests {
         )?;
         assert_eq!(no_filter_result.get_docs().len(), 5);
         // remove collection from db
-        EmbeddingCollection::delete(String::from(ec.get_view()))?;
+        EmbeddingCollection::delete(&valentinus, String::from(ec.get_view()))?;
         Ok(())
     }
 
@@ -694,9 +1622,11 @@ mod tests {
         }
         let name = String::from("test_collection");
         let expected: Vec<String> = documents.clone();
+        let valentinus = Valentinus::in_memory();
         let model_path = String::from("all-MiniLM-L6-v2_onnx");
         let model_type = ModelType::AllMiniLmL6V2;
         let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
             documents.clone(),
             vec![metadata],
             ids,
@@ -707,14 +1637,614 @@ mod tests {
         let created_docs: &Vec<String> = ec.get_documents();
         assert_eq!(expected, created_docs.to_vec());
         // save collection to db
-        ec.save()?;
+        ec.save(&valentinus)?;
         // query the collection
         let query_string: String = String::from("Find me some delicious food!");
-        let result: usize =
-            EmbeddingCollection::nearest_query(query_string, String::from(ec.get_view()))?;
+        let result: usize = EmbeddingCollection::nearest_query(
+            &valentinus,
+            query_string,
+            String::from(ec.get_view()),
+        )?;
         assert_eq!(documents.clone()[result], documents[3]);
         // remove collection from db
-        EmbeddingCollection::delete(String::from(ec.get_view()))?;
+        EmbeddingCollection::delete(&valentinus, String::from(ec.get_view()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_documents_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            vec![
+                String::from("Range is great. Charging is slow. Seats are firm."),
+                String::from("Short review."),
+            ],
+            vec![vec![String::from(r#"{"Year": 2017}"#)], vec![]],
+            vec![String::from("a"), String::from("b")],
+            String::from("chunked"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        let splitter = Splitter::Sentences {
+            size: 2,
+            overlap: 1,
+        };
+        ec.chunk_documents(&splitter)?;
+        let documents: Vec<&str> = vec![
+            "Range is great. Charging is slow.",
+            "Charging is slow. Seats are firm.",
+            "Short review.",
+        ];
+        assert_eq!(ec.get_documents(), &documents);
+        // chunks are numbered per parent and inherit its metadata
+        assert_eq!(ec.get_ids(), &vec!["a-0", "a-1", "b-0"]);
+        assert_eq!(ec.get_parents(), &vec!["a", "a", "b"]);
+        assert_eq!(ec.get_metadata()[1], vec![r#"{"Year": 2017}"#]);
+        assert!(ec.get_metadata()[2].is_empty());
+        // chunking again keeps linking to the original documents
+        ec.chunk_documents(&Splitter::Sentences {
+            size: 1,
+            overlap: 0,
+        })?;
+        assert_eq!(ec.get_ids(), &vec!["a-0", "a-1", "a-2", "a-3", "b-0"]);
+        assert_eq!(ec.get_parents(), &vec!["a", "a", "a", "a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn collapse_to_parents_test() {
+        let mut result = CosineQueryResult::create(
+            vec![
+                String::from("a-0"),
+                String::from("b-0"),
+                String::from("a-1"),
+            ],
+            vec![0.4, 0.5, 0.9],
+            vec![vec![], vec![], vec![String::from("best")]],
+        );
+        result.parents = vec![String::from("a"), String::from("b"), String::from("a")];
+        // one result per parent, the best chunk in the first chunk's place
+        let collapsed = result.collapse_to_parents();
+        assert_eq!(collapsed.get_docs(), &vec!["a-1", "b-0"]);
+        assert_eq!(collapsed.get_similarities(), &vec![0.9, 0.5]);
+        assert_eq!(collapsed.get_metadata()[0], vec!["best"]);
+        assert_eq!(collapsed.get_parents(), &vec!["a", "b"]);
+        // results without parents are returned as they are
+        let plain = CosineQueryResult::create(vec![String::from("x")], vec![0.1], vec![vec![]]);
+        assert_eq!(plain.collapse_to_parents().get_docs(), &vec!["x"]);
+    }
+
+    #[test]
+    fn unique_view_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let new = |name: &str| {
+            EmbeddingCollection::new(
+                &valentinus,
+                vec![String::from("doc")],
+                vec![vec![]],
+                vec![String::from("id0")],
+                String::from(name),
+                ModelType::AllMiniLmL6V2,
+                String::from("all-MiniLM-L6-v2_onnx"),
+            )
+        };
+        let mut ec: EmbeddingCollection = new("taken")?;
+        ec.set_embeddings(array![[0.6, 0.8]]);
+        ec.write(&valentinus)?;
+        // names are compared with the prefixed views in the indexer
+        assert!(matches!(
+            new("taken"),
+            Err(ValentinusError::InvalidViewName)
+        ));
+        assert!(new("taken_2").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn export_import_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            vec![String::from("first doc"), String::from("second doc")],
+            vec![vec![String::from(r#"{"Year": 2017}"#)], vec![]],
+            vec![String::from("id0"), String::from("id1")],
+            String::from("exported"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(array![[0.6, 0.8, 0.0], [0.0, 0.28, 0.96]]);
+        ec.write(&valentinus)?;
+        let mut exported: Vec<u8> = Vec::new();
+        EmbeddingCollection::export(&valentinus, String::from(ec.get_view()), &mut exported)?;
+        let imported = EmbeddingCollection::import(
+            &valentinus,
+            &exported[..],
+            Some(String::from("imported")),
+        )?;
+        let found = find(&valentinus, None, Some(String::from(imported.get_view())))?;
+        assert_eq!(found.get_documents(), ec.get_documents());
+        assert_eq!(found.get_metadata(), ec.get_metadata());
+        assert_eq!(found.get_ids(), ec.get_ids());
+        assert_eq!(found.embeddings, ec.embeddings);
+        // the exported name is already taken
+        assert!(EmbeddingCollection::import(&valentinus, &exported[..], None).is_err());
+        let newer = String::from_utf8_lossy(&exported).replacen(
+            r#""version":1"#,
+            r#""version":99"#,
+            1,
+        );
+        assert!(EmbeddingCollection::import(&valentinus, newer.as_bytes(), None).is_err());
+        // record counts that don't match the header are rejected
+        for count in [r#""count":1"#, r#""count":18446744073709551615"#] {
+            let miscounted = String::from_utf8_lossy(&exported).replacen(r#""count":2"#, count, 1);
+            assert!(matches!(
+                EmbeddingCollection::import(
+                    &valentinus,
+                    miscounted.as_bytes(),
+                    Some(String::from("miscounted"))
+                ),
+                Err(ValentinusError::ExportError)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compression_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let documents: Vec<String> = (0..64)
+            .map(|i| format!("review {} of a car with a long range battery", i))
+            .collect();
+        let rows: usize = documents.len();
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((rows, 8), |(i, j)| ((i * 8 + j) as f32 * 0.01).cos());
+        for (name, compression) in [
+            ("raw", Compression::None),
+            ("lz4", Compression::Lz4),
+            ("zstd", Compression::Zstd(3)),
+        ] {
+            let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+                &valentinus,
+                documents.clone(),
+                vec![vec![String::from(r#"{"Year": 2017}"#)]; rows],
+                (0..rows).map(|i| format!("id{}", i)).collect(),
+                String::from(name),
+                ModelType::AllMiniLmL6V2,
+                String::from("all-MiniLM-L6-v2_onnx"),
+            )?;
+            ec.set_compression(compression);
+            ec.set_embeddings(embeddings.clone());
+            ec.write(&valentinus)?;
+            let found = find(&valentinus, None, Some(String::from(ec.get_view())))?;
+            assert_eq!(found.get_documents(), &documents);
+            assert_eq!(found.get_metadata(), ec.get_metadata());
+            assert_eq!(found.embeddings, embeddings);
+            assert_eq!(found.get_compression(), compression);
+            let stats =
+                EmbeddingCollection::compression_stats(&valentinus, String::from(ec.get_view()))?;
+            assert_eq!(stats.get_compression(), compression);
+            assert_eq!(stats.get_embeddings_raw(), (rows * 8 * 4) as u64);
+            if compression == Compression::None {
+                assert_eq!(stats.get_ratio(), 1.0);
+            } else {
+                assert!(stats.get_documents_stored() < stats.get_documents_raw());
+                assert!(stats.get_ratio() > 1.0);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn detached_embeddings_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", Uuid::new_v4()));
+        let valentinus = Valentinus::open(&DatabaseConfig::new(&path))?;
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((16, 4), |(i, j)| ((i * 4 + j) as f32 * 0.1).sin());
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..16).map(|i| format!("doc {}", i)).collect(),
+            vec![vec![]; 16],
+            (0..16).map(|i| format!("id{}", i)).collect(),
+            String::from("detached"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(embeddings.clone());
+        ec.write(&valentinus)?;
+        let view: String = String::from(ec.get_view());
+        let vectors: String = vectors_key(ec.get_key());
+        // queries scan the stored embeddings without decoding the collection
+        let (stored, placement) = lookup(&valentinus, None, Some(String::from(&view)))?;
+        assert_eq!(placement, Placement::Detached);
+        assert_eq!(stored.embeddings.nrows(), 0);
+        let scanned: Array2<f32> =
+            scan_embeddings(&valentinus, &stored, placement, |cv| Ok(cv.to_owned()))?;
+        assert_eq!(scanned, embeddings);
+        assert_eq!(
+            find(&valentinus, None, Some(String::from(&view)))?.embeddings,
+            embeddings
+        );
+        assert!(valentinus.verify(false)?.is_consistent());
+        // compressed collections keep their embeddings inline
+        ec.set_compression(Compression::Lz4);
+        ec.write(&valentinus)?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        assert!(db.read(vectors.as_bytes())?.is_empty());
+        assert_eq!(
+            lookup(&valentinus, None, Some(String::from(&view)))?.1,
+            Placement::Inline
+        );
+        ec.set_compression(Compression::None);
+        ec.write(&valentinus)?;
+        EmbeddingCollection::delete(&valentinus, view)?;
+        assert!(db.read(vectors.as_bytes())?.is_empty());
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn quantized_collection_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((32, 16), |(i, j)| ((i * 16 + j) as f32 * 0.37).sin() / 2.0);
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..32).map(|i| format!("doc {}", i)).collect(),
+            vec![vec![]; 32],
+            (0..32).map(|i| format!("id{}", i)).collect(),
+            String::from("quantized"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(embeddings.clone());
+        ec.set_quantization(Quantization::Int8);
+        ec.write(&valentinus)?;
+        let view: String = String::from(ec.get_view());
+        let rows: Vec<usize> = (0..32).collect();
+        let query = embeddings.row(5);
+        // the full embeddings are kept unless dropping them is opted in to
+        let (_, placement) = lookup(&valentinus, None, Some(String::from(&view)))?;
+        assert_eq!(placement, Placement::Detached);
+        assert_eq!(
+            find(&valentinus, None, Some(String::from(&view)))?.embeddings,
+            embeddings
+        );
+        ec.set_quantized_only(true);
+        ec.write(&valentinus)?;
+        // only the int8 codes are stored
+        let (stored, placement) = lookup(&valentinus, None, Some(String::from(&view)))?;
+        assert_eq!(placement, Placement::Quantized);
+        assert_eq!(stored.get_quantization(), Quantization::Int8);
+        assert!(stored.is_quantized_only());
+        let scored = score_rows(&valentinus, &stored, placement, query, &rows)?;
+        assert_eq!(scored.len(), 32);
+        let best = scored
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|s| s.0);
+        assert_eq!(best, Some(5));
+        let approximate = find(&valentinus, None, Some(String::from(&view)))?.embeddings;
+        assert!((&approximate - &embeddings).iter().all(|d| d.abs() < 0.01));
+        let stats = EmbeddingCollection::compression_stats(&valentinus, String::from(&view))?;
+        assert_eq!(stats.get_embeddings_raw(), 32 * 16 * 4);
+        assert!(stats.get_embeddings_stored() < stats.get_embeddings_raw() / 3);
+        // binary codes with the best candidates rescored exactly
+        ec.set_quantization(Quantization::Binary);
+        ec.set_rescore(4);
+        ec.write(&valentinus)?;
+        let (stored, placement) = lookup(&valentinus, None, Some(String::from(&view)))?;
+        assert_eq!(placement, Placement::Detached);
+        let scored = score_rows(&valentinus, &stored, placement, query, &rows)?;
+        assert_eq!(scored.len(), 4);
+        assert!(scored.contains(&(5, query.dot(&query))));
+        assert_eq!(
+            find(&valentinus, None, Some(String::from(&view)))?.embeddings,
+            embeddings
+        );
+        ec.set_quantization(Quantization::None);
+        ec.write(&valentinus)?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        assert!(db.read(quantized_key(ec.get_key()).as_bytes())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn ivf_pq_collection_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let mut embeddings: Array2<f32> =
+            Array2::from_shape_fn((64, 16), |(i, j)| ((i * 16 + j) as f32 * 0.37).sin());
+        for mut row in embeddings.axis_iter_mut(Axis(0)) {
+            let norm: f32 = row.dot(&row).sqrt();
+            row.mapv_inplace(|v| v / norm);
+        }
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..64).map(|i| format!("doc {}", i)).collect(),
+            vec![vec![]; 64],
+            (0..64).map(|i| format!("id{}", i)).collect(),
+            String::from("ivf"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(embeddings.clone());
+        ec.write(&valentinus)?;
+        let view: String = String::from(ec.get_view());
+        assert!(EmbeddingCollection::retrain_ivf_pq(&valentinus, String::from(&view)).is_err());
+        let config = IvfPqConfig {
+            lists: 4,
+            subspaces: 4,
+            ..Default::default()
+        };
+        EmbeddingCollection::train_ivf_pq(&valentinus, String::from(&view), &config)?;
+        let is_ivf_pq_stale = EmbeddingCollection::is_ivf_pq_stale;
+        assert!(!is_ivf_pq_stale(&valentinus, String::from(&view))?);
+        let (stored, _) = lookup(&valentinus, None, Some(String::from(&view)))?;
+        // probing every list finds the query first
+        let found = ivf_search(&valentinus, &stored, embeddings.row(5), 3, 4)?;
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0].0, 5);
+        assert!(found[0].1 > 0.9);
+        // changed embeddings leave the index stale until retrained
+        ec.set_embeddings(embeddings.mapv(|v| -v));
+        ec.write(&valentinus)?;
+        assert!(is_ivf_pq_stale(&valentinus, String::from(&view))?);
+        EmbeddingCollection::retrain_ivf_pq(&valentinus, String::from(&view))?;
+        assert!(!is_ivf_pq_stale(&valentinus, String::from(&view))?);
+        // deleting the collection deletes its index
+        EmbeddingCollection::delete(&valentinus, String::from(&view))?;
+        let db: &dyn StorageBackend = valentinus.db.as_ref();
+        let prefix: String = ivf_key(ec.get_key());
+        assert!(!db.keys()?.iter().any(|k| k.starts_with(prefix.as_bytes())));
+        Ok(())
+    }
+
+    #[test]
+    fn batch_scoring_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((40, 8), |(i, j)| ((i * 8 + j) as f32 * 0.61).sin());
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..40).map(|i| format!("doc {}", i)).collect(),
+            (0..40)
+                .map(|i| vec![format!(r#"{{"Rating": {}}}"#, i % 5)])
+                .collect(),
+            (0..40).map(|i| format!("id{}", i)).collect(),
+            String::from("batch"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(embeddings.clone());
+        ec.write(&valentinus)?;
+        let view: String = String::from(ec.get_view());
+        let queries = embeddings.select(Axis(0), &[3, 18, 29]);
+        let f_where = Some(vec![String::from(r#"{ "Rating": {"gt": 2} }"#)]);
+        for quantization in [Quantization::None, Quantization::Int8] {
+            ec.set_quantization(quantization);
+            ec.write(&valentinus)?;
+            let (stored, placement) = lookup(&valentinus, None, Some(String::from(&view)))?;
+            let rows: Vec<usize> = filter_rows(&stored, f_where.clone())?;
+            assert_eq!(rows.len(), 16);
+            assert!(rows.iter().all(|r| r % 5 > 2));
+            // one pass answers each query as a single query would
+            let batched = score_queries(&valentinus, &stored, placement, queries.view(), &rows)?;
+            assert_eq!(batched.len(), 3);
+            for (query, scored) in queries.axis_iter(Axis(0)).zip(&batched) {
+                assert_eq!(
+                    scored,
+                    &score_rows(&valentinus, &stored, placement, query, &rows)?
+                );
+            }
+            let result = cosine_result(&stored, batched[1].clone(), 0, None);
+            assert!(result.get_docs().contains(&String::from("doc 18")));
+            assert!(result.get_similarities().iter().all(|s| *s > 0.0));
+            let truncated = cosine_result(&stored, batched[1].clone(), 4, None);
+            assert_eq!(truncated.get_docs().len(), 4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn score_threshold_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..5).map(|i| format!("doc {}", i)).collect(),
+            vec![vec![]; 5],
+            (0..5).map(|i| format!("id{}", i)).collect(),
+            String::from("threshold"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        let scored: Vec<(usize, f32)> = vec![(0, 0.2), (1, -0.4), (2, 0.95), (3, 0.0), (4, 0.9)];
+        // without a threshold only positive similarities are kept
+        let result = cosine_result(&ec, scored.clone(), 0, None);
+        assert_eq!(result.get_similarities(), &vec![0.2, 0.95, 0.9]);
+        let result = cosine_result(&ec, scored.clone(), 0, Some(0.9));
+        assert_eq!(result.get_docs(), &vec!["doc 2", "doc 4"]);
+        // thresholded and range queries sort before keeping the best
+        let mut sorted: Vec<(usize, f32)> = scored;
+        sort_by_score(&mut sorted);
+        assert_eq!(
+            sorted.iter().map(|(i, _)| *i).collect::<Vec<usize>>(),
+            vec![2, 4, 0, 3, 1]
+        );
+        let result = cosine_result(&ec, sorted.clone(), 1, Some(-1.0));
+        assert_eq!(result.get_docs(), &vec!["doc 2"]);
+        let result = cosine_result(&ec, sorted, 0, Some(1.0 - 0.8));
+        assert_eq!(result.get_similarities(), &vec![0.95, 0.9, 0.2]);
+        // ties keep the row order
+        let mut tied: Vec<(usize, f32)> = vec![(3, 0.5), (1, 0.5), (2, 0.7)];
+        sort_by_score(&mut tied);
+        assert_eq!(tied, vec![(2, 0.7), (1, 0.5), (3, 0.5)]);
+        Ok(())
+    }
+
+    #[test]
+    fn mmr_rerank_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        // documents 0-2 repeat one complaint, 3 and 4 differ
+        let embeddings: Array2<f32> = array![
+            [1.0, 0.0, 0.0, 0.0],
+            [0.99, 0.14, 0.0, 0.0],
+            [0.99, 0.0, 0.14, 0.0],
+            [0.6, 0.0, 0.0, 0.8],
+            [0.0, 0.0, 1.0, 0.0],
+        ];
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            (0..5).map(|i| format!("doc {}", i)).collect(),
+            vec![vec![]; 5],
+            (0..5).map(|i| format!("id{}", i)).collect(),
+            String::from("mmr"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        ec.set_embeddings(embeddings.clone());
+        let query = embeddings.row(0);
+        for quantization in [Quantization::None, Quantization::Int8] {
+            ec.set_quantization(quantization);
+            ec.write(&valentinus)?;
+            let view: Option<String> = Some(String::from(ec.get_view()));
+            let (stored, placement) = lookup(&valentinus, None, view)?;
+            let rows: Vec<usize> = (0..5).collect();
+            let scored = score_rows(&valentinus, &stored, placement, query, &rows)?;
+            let rerank = |lambda: f32, pool: usize, k: usize| {
+                let mut candidates: Vec<(usize, f32)> = scored.clone();
+                let reranked = mmr_rerank(
+                    &valentinus,
+                    &stored,
+                    placement,
+                    &mut candidates,
+                    lambda,
+                    pool,
+                    k,
+                )?;
+                Ok::<Vec<usize>, ValentinusError>(reranked.iter().map(|(r, _)| *r).collect())
+            };
+            // similarity order, then the duplicates give way to document 3
+            assert_eq!(rerank(1.0, 0, 3)?, vec![0, 1, 2]);
+            assert_eq!(rerank(0.3, 0, 3)?[..2], [0, 3]);
+            // orthogonal document 4 is never a candidate, and the pool bounds them
+            assert_eq!(rerank(0.3, 0, 0)?.len(), 4);
+            assert_eq!(rerank(0.3, 2, 0)?, vec![0, 1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_write_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", Uuid::new_v4()));
+        let valentinus = Valentinus::open(&DatabaseConfig::new(&path))?;
+        // every concurrent save lands in the indexers
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|i| {
+                    let valentinus = &valentinus;
+                    scope.spawn(move || {
+                        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+                            valentinus,
+                            vec![format!("doc {}", i)],
+                            vec![vec![]],
+                            vec![format!("id{}", i)],
+                            format!("concurrent{}", i),
+                            ModelType::AllMiniLmL6V2,
+                            String::from("all-MiniLM-L6-v2_onnx"),
+                        )?;
+                        ec.set_embeddings(array![[0.6, 0.8]]);
+                        ec.write(valentinus)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|w| w.join().unwrap_or(Err(ValentinusError::TestError)))
+        })?;
+        let keys = EmbeddingCollection::fetch_collection_keys(&valentinus, false)?;
+        let views = EmbeddingCollection::fetch_collection_keys(&valentinus, true)?;
+        assert_eq!((keys.values.len(), views.values.len()), (4, 4));
+        // deleting a collection missing from the indexers errors
+        let view = |i: usize| format!("{}-concurrent{}", VALENTINUS_VIEW, i);
+        let (stored, _) = lookup(&valentinus, None, Some(view(0)))?;
+        let remaining: Vec<String> = keys
+            .values
+            .into_iter()
+            .filter(|k| k != stored.get_key())
+            .collect();
+        let mut batch = WriteBatch::new();
+        let b_keys: Vec<u8> = bincode::serialize(&KeyViewIndexer::new(&remaining))
+            .map_err(|_| ValentinusError::BincodeError)?;
+        batch.put(VALENTINUS_KEYS.as_bytes(), &b_keys);
+        valentinus.db.commit_batch(&batch)?;
+        assert!(EmbeddingCollection::delete(&valentinus, view(0)).is_err());
+        assert!(find(&valentinus, None, Some(view(0))).is_ok());
+        EmbeddingCollection::delete(&valentinus, view(1))?;
+        let views = EmbeddingCollection::fetch_collection_keys(&valentinus, true)?;
+        assert_eq!(views.values.len(), 3);
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        let mut exported: Vec<u8> = Vec::new();
+        let view: String = {
+            let valentinus = Valentinus::open(&config)?;
+            let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+                &valentinus,
+                vec![String::from("first doc")],
+                vec![vec![]],
+                vec![String::from("id0")],
+                String::from("shared"),
+                ModelType::AllMiniLmL6V2,
+                String::from("all-MiniLM-L6-v2_onnx"),
+            )?;
+            ec.set_embeddings(array![[0.6, 0.8, 0.0]]);
+            ec.write(&valentinus)?;
+            EmbeddingCollection::export(&valentinus, String::from(ec.get_view()), &mut exported)?;
+            String::from(ec.get_view())
+        };
+        let valentinus = Valentinus::open(&DatabaseConfig {
+            read_only: true,
+            ..config
+        })?;
+        assert!(valentinus.is_read_only());
+        let found = find(&valentinus, None, Some(String::from(&view)))?;
+        assert_eq!(found.get_ids(), &vec![String::from("id0")]);
+        assert!(valentinus.verify(false)?.is_consistent());
+        let mut ec: EmbeddingCollection = EmbeddingCollection::new(
+            &valentinus,
+            vec![String::from("new doc")],
+            vec![vec![]],
+            vec![String::from("id1")],
+            String::from("rejected"),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )?;
+        assert!(matches!(
+            ec.save(&valentinus),
+            Err(ValentinusError::ReadOnly)
+        ));
+        assert!(matches!(
+            EmbeddingCollection::import(&valentinus, &exported[..], Some(String::from("copy"))),
+            Err(ValentinusError::ReadOnly)
+        ));
+        assert!(matches!(
+            EmbeddingCollection::delete(&valentinus, view),
+            Err(ValentinusError::ReadOnly)
+        ));
+        assert!(matches!(
+            valentinus.migrate(),
+            Err(ValentinusError::ReadOnly)
+        ));
+        assert!(matches!(
+            valentinus.verify(true),
+            Err(ValentinusError::ReadOnly)
+        ));
+        let _ = std::fs::remove_dir_all(path);
         Ok(())
     }
 }
@@ -0,0 +1,207 @@
+#![deny(missing_docs)]
+
+//! Authenticated encryption of stored values.
+//!
+//! Encrypted values are stored as `VENC`, a version byte, the 8 byte id of
+//!
+//! the key, a 24 byte nonce and the XChaCha20-Poly1305 ciphertext. The
+//!
+//! database key is authenticated alongside, so values can't be swapped
+//!
+//! between keys. Database keys themselves are not encrypted.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use kn0sys_lmdb_rs::MdbError;
+use log::*;
+use sha2::{Digest, Sha256};
+
+/// Marks an encrypted value
+const ENCRYPTED_MAGIC: &[u8; 4] = b"VENC";
+/// Version of the encrypted value layout
+const ENCRYPTION_VERSION: u8 = 1;
+/// Length of a key id
+const KEY_ID_LEN: usize = 8;
+/// Length of an XChaCha20 nonce
+const NONCE_LEN: usize = 24;
+/// Length of everything before the ciphertext
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + KEY_ID_LEN + NONCE_LEN;
+/// Domain separation for key ids
+const KEY_ID_CONTEXT: &[u8] = b"valentinus-key-id";
+
+/// 256 bit key for encrypting values at rest. Never printed by `Debug`.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey({:02x?})", self.id())
+    }
+}
+
+impl EncryptionKey {
+    /// Use the 32 bytes in `key`, i.e. loaded from a secret store
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        EncryptionKey(key)
+    }
+    /// Generate a random key. Store it safely, data can't be read without it
+    pub fn generate() -> Self {
+        EncryptionKey(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+    /// Raw bytes of the key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    /// Id recorded with every value encrypted with the key
+    fn id(&self) -> [u8; KEY_ID_LEN] {
+        let digest = Sha256::new()
+            .chain_update(KEY_ID_CONTEXT)
+            .chain_update(self.0)
+            .finalize();
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        id
+    }
+}
+
+/// Encrypts values with the current key and decrypts values written with
+///
+/// it or a previous one
+pub struct Cipher {
+    current: Option<EncryptionKey>,
+    previous: Vec<EncryptionKey>,
+    allow_plaintext: bool,
+}
+
+/// Log and build the error for a value that can't be encrypted or decrypted
+fn cipher_error(msg: &str) -> MdbError {
+    error!("{}", msg);
+    MdbError::StateError(String::from(msg))
+}
+
+impl Cipher {
+    /// Encrypt with `current` when set. Values encrypted with `previous`
+    ///
+    /// keys stay readable, as do plaintext values if `allow_plaintext`.
+    pub fn new(
+        current: Option<EncryptionKey>,
+        previous: Vec<EncryptionKey>,
+        allow_plaintext: bool,
+    ) -> Self {
+        Cipher {
+            current,
+            previous,
+            allow_plaintext,
+        }
+    }
+    /// True when values are written encrypted
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+    /// Encrypt the value `v` of key `k`. Plaintext without a current key
+    pub fn seal(&self, k: &[u8], v: &[u8]) -> Result<Vec<u8>, MdbError> {
+        let Some(key) = &self.current else {
+            return Ok(v.to_vec());
+        };
+        let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
+        let nonce: XNonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext: Vec<u8> = cipher
+            .encrypt(&nonce, Payload { msg: v, aad: k })
+            .map_err(|_| cipher_error("failed to encrypt value"))?;
+        let mut sealed: Vec<u8> = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(ENCRYPTED_MAGIC);
+        sealed.push(ENCRYPTION_VERSION);
+        sealed.extend_from_slice(&key.id());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+    /// Decrypt the stored value `raw` of key `k`
+    pub fn open(&self, k: &[u8], raw: &[u8]) -> Result<Vec<u8>, MdbError> {
+        let Some(id) = key_id(raw) else {
+            if self.is_enabled() && !self.allow_plaintext {
+                return Err(cipher_error("value is not encrypted"));
+            }
+            return Ok(raw.to_vec());
+        };
+        if raw[ENCRYPTED_MAGIC.len()] > ENCRYPTION_VERSION {
+            return Err(cipher_error("unsupported encryption version"));
+        }
+        let key: &EncryptionKey = self
+            .current
+            .iter()
+            .chain(self.previous.iter())
+            .find(|key| key.id() == id)
+            .ok_or_else(|| cipher_error("value is encrypted with an unknown key"))?;
+        let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
+        let nonce = XNonce::from_slice(&raw[HEADER_LEN - NONCE_LEN..HEADER_LEN]);
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &raw[HEADER_LEN..],
+                    aad: k,
+                },
+            )
+            .map_err(|_| cipher_error("value failed authentication"))
+    }
+    /// True when `raw` isn't encrypted with the current key (or is
+    ///
+    /// encrypted while there is none) and should be rewritten
+    pub fn is_stale(&self, raw: &[u8]) -> bool {
+        match (&self.current, key_id(raw)) {
+            (Some(key), Some(id)) => key.id() != id,
+            (Some(_), None) => true,
+            (None, id) => id.is_some(),
+        }
+    }
+}
+
+/// Id of the key `raw` is encrypted with, `None` for plaintext
+fn key_id(raw: &[u8]) -> Option<[u8; KEY_ID_LEN]> {
+    if raw.len() < HEADER_LEN || !raw.starts_with(ENCRYPTED_MAGIC) {
+        return None;
+    }
+    let start: usize = ENCRYPTED_MAGIC.len() + 1;
+    raw[start..start + KEY_ID_LEN].try_into().ok()
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn cipher_test() -> Result<(), MdbError> {
+        let old = EncryptionKey::generate();
+        let new = EncryptionKey::generate();
+        let sealed: Vec<u8> =
+            Cipher::new(Some(old.clone()), vec![], false).seal(b"k", b"secret")?;
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        // rotated, the old key still decrypts
+        let rotated = Cipher::new(Some(new.clone()), vec![old.clone()], false);
+        assert_eq!(rotated.open(b"k", &sealed)?, b"secret".to_vec());
+        assert!(rotated.is_stale(&sealed));
+        assert!(!rotated.is_stale(&rotated.seal(b"k", b"secret")?));
+        // wrong key, wrong database key, tampering and plaintext all fail
+        assert!(Cipher::new(Some(new), vec![], false)
+            .open(b"k", &sealed)
+            .is_err());
+        assert!(rotated.open(b"other", &sealed).is_err());
+        let mut tampered: Vec<u8> = sealed.clone();
+        let last: usize = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(rotated.open(b"k", &tampered).is_err());
+        assert!(rotated.open(b"k", b"plain").is_err());
+        assert!(Cipher::new(None, vec![], false)
+            .open(b"k", &sealed)
+            .is_err());
+        // plaintext is accepted while encrypting an existing database
+        let migrating = Cipher::new(Some(old), vec![], true);
+        assert_eq!(migrating.open(b"k", b"plain")?, b"plain".to_vec());
+        assert!(migrating.is_stale(b"plain"));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+#![deny(missing_docs)]
+
+//! Portable export format for collections.
+//!
+//! An export is UTF-8 JSON Lines. The first line is a header:
+//!
+//! ```json
+//! {"format":"valentinus-collection","version":1,"name":"reviews",
+//!  "model_type":"AllMiniLmL6V2","model_path":"all-MiniLM-L6-v2_onnx",
+//!  "embedder":{...},"dimensions":384,"count":2}
+//! ```
+//!
+//! followed by `count` records, one per document, in collection order:
+//!
+//! ```json
+//! {"id":"id0","document":"...","metadata":["{\"Year\": 2017}"],"parent":"doc0","embedding":[0.1,...]}
+//! ```
+//!
+//! `parent` is omitted for documents that were not chunked. Every embedding
+//!
+//! has `dimensions` values and is L2 normalized.
+
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::{ModelType, ValentinusError};
+use crate::onnx::EmbedderConfig;
+
+/// Identifies a valentinus export
+pub const EXPORT_FORMAT: &str = "valentinus-collection";
+/// Current export format version
+pub const EXPORT_VERSION: u32 = 1;
+
+/// First line of an export
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportHeader {
+    /// Always `EXPORT_FORMAT`
+    pub format: String,
+    /// Export format version
+    pub version: u32,
+    /// View name without the internal prefix
+    pub name: String,
+    /// Model the embeddings were generated with
+    pub model_type: ModelType,
+    /// Path to model.onnx and tokenizer.json
+    pub model_path: String,
+    /// Tokenization and inference settings
+    #[serde(default)]
+    pub embedder: EmbedderConfig,
+    /// Length of each embedding
+    pub dimensions: usize,
+    /// Number of records following the header
+    pub count: usize,
+}
+
+/// One document of an export
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportRecord {
+    /// Document id
+    pub id: String,
+    /// Document text
+    pub document: String,
+    /// Metadata filters of the document
+    #[serde(default)]
+    pub metadata: Vec<String>,
+    /// Id of the document this chunk was split from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Embedding of the document
+    pub embedding: Vec<f32>,
+}
+
+/// Write one JSON line
+fn write_line(writer: &mut impl Write, value: &impl Serialize) -> Result<(), ValentinusError> {
+    serde_json::to_writer(&mut *writer, value).map_err(|_| ValentinusError::ExportError)?;
+    writer.write_all(b"\n").map_err(ValentinusError::IoError)
+}
+
+/// Write a header and its records
+pub fn write_export(
+    writer: &mut impl Write,
+    header: &ExportHeader,
+    records: impl Iterator<Item = ExportRecord>,
+) -> Result<(), ValentinusError> {
+    write_line(writer, header)?;
+    for record in records {
+        write_line(writer, &record)?;
+    }
+    writer.flush().map_err(ValentinusError::IoError)
+}
+
+/// Read and validate an export
+pub fn read_export(
+    reader: impl BufRead,
+) -> Result<(ExportHeader, Vec<ExportRecord>), ValentinusError> {
+    let mut lines = reader
+        .lines()
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()));
+    let first: String = lines
+        .next()
+        .ok_or(ValentinusError::ExportError)?
+        .map_err(ValentinusError::IoError)?;
+    let header: ExportHeader =
+        serde_json::from_str(&first).map_err(|_| ValentinusError::ExportError)?;
+    if header.format != EXPORT_FORMAT || header.version > EXPORT_VERSION {
+        log::error!(
+            "unsupported export {} version {}",
+            header.format,
+            header.version
+        );
+        return Err(ValentinusError::ExportError);
+    }
+    // the header isn't trusted to size anything
+    let mut records: Vec<ExportRecord> = Vec::new();
+    for line in lines {
+        if records.len() == header.count {
+            log::error!("export has more than {} records", header.count);
+            return Err(ValentinusError::ExportError);
+        }
+        let line: String = line.map_err(ValentinusError::IoError)?;
+        let record: ExportRecord =
+            serde_json::from_str(&line).map_err(|_| ValentinusError::ExportError)?;
+        if record.embedding.len() != header.dimensions {
+            log::error!("embedding of {} has the wrong dimensions", record.id);
+            return Err(ValentinusError::ExportError);
+        }
+        records.push(record);
+    }
+    if records.len() != header.count {
+        log::error!("expected {} records, found {}", header.count, records.len());
+        return Err(ValentinusError::ExportError);
+    }
+    Ok((header, records))
+}
@@ -0,0 +1,543 @@
+#![deny(missing_docs)]
+
+//! Inverted file index with product quantization (IVF-PQ).
+//!
+//! A k-means coarse quantizer assigns every embedding to a list, and the
+//!
+//! residual from the list's centroid is encoded as one byte per subspace.
+//!
+//! The centroids and codebooks are stored under `ivf_key`, each list apart
+//!
+//! under `ivf_list_key` so queries only read the `nprobe` lists they scan.
+//!
+//! Lists are stored as a big endian `u64` count, the little endian `u32`
+//!
+//! row of every entry and its codes.
+
+use kn0sys_lmdb_rs::MdbError;
+use log::*;
+use ndarray::{Array2, ArrayView1, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+
+use crate::database::*;
+use crate::embeddings::ValentinusError;
+
+/// Prefix of the keys IVF-PQ indexes are stored under
+pub const VALENTINUS_IVF: &str = "ivf";
+/// Most centroids per subspace, so codes fit in a byte
+const MAX_SUBSPACE_CENTROIDS: usize = 256;
+/// Length of the count of a list
+const LIST_HEADER_LEN: usize = 8;
+
+/// Settings for training an IVF-PQ index
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IvfPqConfig {
+    /// Number of inverted lists. 0 uses the square root of the number of
+    ///
+    /// embeddings
+    pub lists: usize,
+    /// Number of subspaces, i.e. bytes per encoded embedding. Must divide
+    ///
+    /// the dimensions of the embeddings
+    pub subspaces: usize,
+    /// Rounds of k-means when training
+    pub iterations: usize,
+    /// Seed of the initial centroids, so training is reproducible
+    pub seed: u64,
+}
+
+impl Default for IvfPqConfig {
+    fn default() -> Self {
+        IvfPqConfig {
+            lists: 0,
+            subspaces: 8,
+            iterations: 20,
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// Key of the IVF-PQ index of the collection stored under `collection_key`
+pub fn ivf_key(collection_key: &str) -> String {
+    format!("{}-{}", VALENTINUS_IVF, collection_key)
+}
+
+/// Key of list `n` of the IVF-PQ index of the collection stored under `collection_key`
+pub fn ivf_list_key(collection_key: &str, n: usize) -> String {
+    format!("{}-list-{}", ivf_key(collection_key), n)
+}
+
+/// Stage deletes of the IVF-PQ index of the collection stored under `key`.
+///
+/// The lists are named by its head, or found by a key scan when the head
+///
+/// can't be read.
+pub fn stage_index_delete(
+    db: &dyn StorageBackend,
+    key: &str,
+    batch: &mut WriteBatch,
+) -> Result<(), ValentinusError> {
+    let head: String = ivf_key(key);
+    let lists: Option<usize> = match db.read(head.as_bytes()) {
+        Ok(raw) if raw.is_empty() => return Ok(()),
+        Ok(raw) => decode_head(&raw).ok().map(|h| h.get_lists()),
+        Err(MdbError::Corrupted) => None,
+        Err(e) => return Err(ValentinusError::from(e)),
+    };
+    batch.delete(head.as_bytes());
+    match lists {
+        Some(lists) => {
+            for n in 0..lists {
+                batch.delete(ivf_list_key(key, n).as_bytes());
+            }
+        }
+        // without a readable head the lists are found by their prefix
+        None => {
+            let list_prefix: String = format!("{}-list-", head);
+            for k in db.keys().map_err(ValentinusError::from)? {
+                if k.starts_with(list_prefix.as_bytes()) {
+                    batch.delete(&k);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Centroids and codebooks of a trained index
+#[derive(Deserialize, Serialize)]
+pub struct IvfPqHead {
+    /// Settings the index was trained with
+    config: IvfPqConfig,
+    rows: u64,
+    dimensions: usize,
+    /// CRC-32 of the embeddings the index was trained on
+    fingerprint: u32,
+    /// Coarse centroids, one row per list
+    centroids: Array2<f32>,
+    /// Centroids of every subspace, `subspaces * 256` rows of
+    ///
+    /// `dimensions / subspaces` values
+    codebooks: Array2<f32>,
+    /// Centroids per subspace
+    subspace_centroids: usize,
+}
+
+/// Entries of one inverted list
+struct InvertedList {
+    rows: Vec<u32>,
+    codes: Vec<u8>,
+}
+
+/// CRC-32 of `embeddings`, to tell when an index is stale
+pub fn fingerprint(embeddings: ArrayView2<f32>) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(embeddings.nrows() as u64).to_be_bytes());
+    for value in embeddings.iter() {
+        hasher.update(&value.to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Next value of a SplitMix64 generator
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z: u64 = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Squared euclidean distance
+fn distance(a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Index of the centroid nearest to `point`
+fn nearest(centroids: ArrayView2<f32>, point: ArrayView1<f32>) -> usize {
+    centroids
+        .axis_iter(Axis(0))
+        .map(|c| distance(c, point))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i)
+        .unwrap_or_default()
+}
+
+/// Lloyd's k-means of the rows of `points` into `k` centroids, starting
+///
+/// from distinct rows picked with `seed`
+fn kmeans(points: ArrayView2<f32>, k: usize, iterations: usize, seed: u64) -> Array2<f32> {
+    let n: usize = points.nrows();
+    let mut state: u64 = seed;
+    // partial Fisher-Yates shuffle picks k distinct rows
+    let mut order: Vec<usize> = (0..n).collect();
+    for i in 0..k {
+        let j: usize = i + (splitmix64(&mut state) % (n - i) as u64) as usize;
+        order.swap(i, j);
+    }
+    let mut centroids: Array2<f32> = points.select(Axis(0), &order[..k]);
+    for _ in 0..iterations {
+        let mut sums: Array2<f32> = Array2::zeros(centroids.dim());
+        let mut counts: Vec<usize> = vec![0; k];
+        for point in points.axis_iter(Axis(0)) {
+            let c: usize = nearest(centroids.view(), point);
+            let mut sum = sums.row_mut(c);
+            sum += &point;
+            counts[c] += 1;
+        }
+        // empty clusters keep their centroid
+        for (c, count) in counts.into_iter().enumerate() {
+            if count > 0 {
+                centroids.row_mut(c).assign(&(&sums.row(c) / count as f32));
+            }
+        }
+    }
+    centroids
+}
+
+/// Train an index over `embeddings`. Returns the head and the encoded lists
+fn train(
+    embeddings: ArrayView2<f32>,
+    config: &IvfPqConfig,
+) -> Result<(IvfPqHead, Vec<InvertedList>), ValentinusError> {
+    let (n, dimensions) = embeddings.dim();
+    if n == 0 || config.subspaces == 0 || dimensions % config.subspaces != 0 {
+        error!(
+            "can't train IVF-PQ with {} subspaces over {} embeddings of {} dimensions",
+            config.subspaces, n, dimensions
+        );
+        return Err(ValentinusError::IndexError);
+    }
+    if n > u32::MAX as usize {
+        error!("IVF-PQ lists address at most {} embeddings", u32::MAX);
+        return Err(ValentinusError::IndexError);
+    }
+    let lists: usize = match config.lists {
+        0 => (n as f64).sqrt().round() as usize,
+        lists => lists,
+    }
+    .clamp(1, n);
+    info!("training IVF-PQ with {} lists over {} embeddings", lists, n);
+    let centroids: Array2<f32> = kmeans(embeddings, lists, config.iterations, config.seed);
+    let assignments: Vec<usize> = embeddings
+        .axis_iter(Axis(0))
+        .map(|e| nearest(centroids.view(), e))
+        .collect();
+    let mut residuals: Array2<f32> = embeddings.to_owned();
+    for (mut residual, list) in residuals.axis_iter_mut(Axis(0)).zip(&assignments) {
+        residual -= &centroids.row(*list);
+    }
+    let width: usize = dimensions / config.subspaces;
+    let subspace_centroids: usize = n.min(MAX_SUBSPACE_CENTROIDS);
+    let mut codebooks: Array2<f32> = Array2::zeros((config.subspaces * subspace_centroids, width));
+    let mut codes: Array2<u8> = Array2::zeros((n, config.subspaces));
+    for s in 0..config.subspaces {
+        let sub: ArrayView2<f32> = residuals.slice(ndarray::s![.., s * width..(s + 1) * width]);
+        let seed: u64 = config.seed.wrapping_add(s as u64 + 1);
+        let book: Array2<f32> = kmeans(sub, subspace_centroids, config.iterations, seed);
+        for (row, point) in sub.axis_iter(Axis(0)).enumerate() {
+            codes[[row, s]] = nearest(book.view(), point) as u8;
+        }
+        codebooks
+            .slice_mut(ndarray::s![
+                s * subspace_centroids..(s + 1) * subspace_centroids,
+                ..
+            ])
+            .assign(&book);
+    }
+    let mut inverted: Vec<InvertedList> = (0..lists)
+        .map(|_| InvertedList {
+            rows: Vec::new(),
+            codes: Vec::new(),
+        })
+        .collect();
+    for (row, list) in assignments.into_iter().enumerate() {
+        inverted[list].rows.push(row as u32);
+        inverted[list].codes.extend(codes.row(row).iter());
+    }
+    let head = IvfPqHead {
+        config: config.clone(),
+        rows: n as u64,
+        dimensions,
+        fingerprint: fingerprint(embeddings),
+        centroids,
+        codebooks,
+        subspace_centroids,
+    };
+    Ok((head, inverted))
+}
+
+/// Train an index over `embeddings`. Returns the encoded head and lists,
+///
+/// to be stored under `ivf_key` and `ivf_list_key`
+pub fn encode_index(
+    embeddings: ArrayView2<f32>,
+    config: &IvfPqConfig,
+) -> Result<(Vec<u8>, Vec<Vec<u8>>), ValentinusError> {
+    let (head, lists) = train(embeddings, config)?;
+    let raw_head: Vec<u8> = bincode::serialize(&head).map_err(|_| ValentinusError::BincodeError)?;
+    let raw_lists: Vec<Vec<u8>> = lists
+        .into_iter()
+        .map(|list| {
+            let mut raw: Vec<u8> =
+                Vec::with_capacity(LIST_HEADER_LEN + list.rows.len() * 4 + list.codes.len());
+            raw.extend_from_slice(&(list.rows.len() as u64).to_be_bytes());
+            for row in list.rows {
+                raw.extend_from_slice(&row.to_le_bytes());
+            }
+            raw.extend(list.codes);
+            raw
+        })
+        .collect();
+    Ok((raw_head, raw_lists))
+}
+
+/// Decode a head stored by `encode_index`
+pub fn decode_head(raw: &[u8]) -> Result<IvfPqHead, ValentinusError> {
+    bincode::deserialize(raw).map_err(|_| {
+        error!("invalid IVF-PQ index");
+        ValentinusError::IndexError
+    })
+}
+
+impl IvfPqHead {
+    /// Settings the index was trained with
+    pub fn get_config(&self) -> &IvfPqConfig {
+        &self.config
+    }
+    /// Number of embeddings the index was trained on
+    pub fn get_rows(&self) -> u64 {
+        self.rows
+    }
+    /// Number of inverted lists
+    pub fn get_lists(&self) -> usize {
+        self.centroids.nrows()
+    }
+    /// True unless the index was trained on exactly `embeddings`
+    pub fn is_stale(&self, embeddings: ArrayView2<f32>) -> bool {
+        self.rows != embeddings.nrows() as u64 || self.fingerprint != fingerprint(embeddings)
+    }
+    /// Lists of the `nprobe` centroids nearest to `query`, nearest first
+    pub fn probe(
+        &self,
+        query: ArrayView1<f32>,
+        nprobe: usize,
+    ) -> Result<Vec<usize>, ValentinusError> {
+        if query.len() != self.dimensions {
+            error!(
+                "query has {} dimensions, the index {}",
+                query.len(),
+                self.dimensions
+            );
+            return Err(ValentinusError::IndexError);
+        }
+        let distances: Vec<f32> = self
+            .centroids
+            .axis_iter(Axis(0))
+            .map(|c| distance(c, query))
+            .collect();
+        let mut lists: Vec<usize> = (0..distances.len()).collect();
+        lists.sort_by(|a, b| distances[*a].total_cmp(&distances[*b]));
+        lists.truncate(nprobe.max(1));
+        Ok(lists)
+    }
+    /// Squared distances from the residual of `query` in `list` to every
+    ///
+    /// subspace centroid, indexed by subspace and code
+    fn tables(&self, query: ArrayView1<f32>, list: usize) -> Vec<f32> {
+        let residual = &query - &self.centroids.row(list);
+        let width: usize = self.dimensions / self.config.subspaces;
+        let mut tables: Vec<f32> = Vec::with_capacity(self.codebooks.nrows());
+        for (i, centroid) in self.codebooks.axis_iter(Axis(0)).enumerate() {
+            let s: usize = i / self.subspace_centroids;
+            tables.push(distance(
+                residual.slice(ndarray::s![s * width..(s + 1) * width]),
+                centroid,
+            ));
+        }
+        tables
+    }
+    /// Approximate squared distance from `query` to every entry of the
+    ///
+    /// stored `list`, as `(row, distance)`
+    pub fn scan_list(
+        &self,
+        query: ArrayView1<f32>,
+        list: usize,
+        raw: &[u8],
+    ) -> Result<Vec<(usize, f32)>, ValentinusError> {
+        if query.len() != self.dimensions {
+            error!(
+                "query has {} dimensions, the index {}",
+                query.len(),
+                self.dimensions
+            );
+            return Err(ValentinusError::IndexError);
+        }
+        let subspaces: usize = self.config.subspaces;
+        // a decoded head isn't trusted to match its own lists
+        let consistent: bool = subspaces > 0
+            && self.dimensions.is_multiple_of(subspaces)
+            && list < self.get_lists()
+            && self.centroids.ncols() == self.dimensions
+            && self.subspace_centroids <= MAX_SUBSPACE_CENTROIDS
+            && Some(self.codebooks.nrows()) == subspaces.checked_mul(self.subspace_centroids)
+            && self.codebooks.ncols() == self.dimensions / subspaces;
+        let count: Option<usize> = raw
+            .get(..LIST_HEADER_LEN)
+            .and_then(|b| b.try_into().ok())
+            .and_then(|b| usize::try_from(u64::from_be_bytes(b)).ok());
+        let count: Option<usize> = count.filter(|c| {
+            c.checked_mul(4 + subspaces)
+                .and_then(|len| len.checked_add(LIST_HEADER_LEN))
+                == Some(raw.len())
+        });
+        let Some(count) = count.filter(|_| consistent) else {
+            error!("stored IVF-PQ list {} is invalid", list);
+            return Err(ValentinusError::CorruptionError);
+        };
+        let tables: Vec<f32> = self.tables(query, list);
+        let (rows, codes) = raw[LIST_HEADER_LEN..].split_at(count * 4);
+        rows.chunks_exact(4)
+            .zip(codes.chunks_exact(subspaces))
+            .map(|(row, code)| {
+                let row: u32 = u32::from_le_bytes([row[0], row[1], row[2], row[3]]);
+                let mut d: f32 = 0.0;
+                for (s, c) in code.iter().enumerate() {
+                    if *c as usize >= self.subspace_centroids {
+                        error!("stored IVF-PQ list {} has an invalid code", list);
+                        return Err(ValentinusError::CorruptionError);
+                    }
+                    d += tables[s * self.subspace_centroids + *c as usize];
+                }
+                Ok((row as usize, d))
+            })
+            .collect()
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn index_delete_test() -> Result<(), ValentinusError> {
+        let db = crate::memory::MemoryBackend::new();
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((16, 8), |(i, j)| ((i * 8 + j) as f32 * 0.37).sin());
+        let config = IvfPqConfig {
+            lists: 3,
+            subspaces: 2,
+            ..Default::default()
+        };
+        let (raw_head, raw_lists) = encode_index(embeddings.view(), &config)?;
+        let write = |head: &[u8]| {
+            let mut batch = WriteBatch::new();
+            batch.put(ivf_key("key-a").as_bytes(), head);
+            for (n, raw) in raw_lists.iter().enumerate() {
+                batch.put(ivf_list_key("key-a", n).as_bytes(), raw);
+            }
+            db.commit_batch(&batch)
+        };
+        // from the head, or by prefix when the head is unreadable
+        for head in [&raw_head[..], b"not a head"] {
+            write(head)?;
+            let mut batch = WriteBatch::new();
+            stage_index_delete(&db, "key-a", &mut batch)?;
+            db.commit_batch(&batch)?;
+            let prefix: String = ivf_key("key-a");
+            assert!(!db.keys()?.iter().any(|k| k.starts_with(prefix.as_bytes())));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ivf_pq_test() -> Result<(), ValentinusError> {
+        // unit vectors around a few clusters, like embeddings of topics
+        let mut rng = StdRng::seed_from_u64(3);
+        let topics: Array2<f32> = Array2::from_shape_fn((8, 32), |_| rng.random::<f32>() - 0.5);
+        let mut embeddings: Array2<f32> = Array2::from_shape_fn((400, 32), |(i, j)| {
+            topics[[i % 8, j]] + (rng.random::<f32>() - 0.5) * 0.3
+        });
+        for mut row in embeddings.axis_iter_mut(Axis(0)) {
+            let norm: f32 = row.dot(&row).sqrt();
+            row.mapv_inplace(|v| v / norm);
+        }
+        let config = IvfPqConfig {
+            lists: 8,
+            ..Default::default()
+        };
+        let (raw_head, raw_lists) = encode_index(embeddings.view(), &config)?;
+        let head: IvfPqHead = decode_head(&raw_head)?;
+        assert_eq!(head.get_lists(), 8);
+        assert_eq!(raw_lists.len(), 8);
+        assert!(!head.is_stale(embeddings.view()));
+        assert!(head.is_stale(embeddings.slice(ndarray::s![..399, ..])));
+        // every row lands in exactly one list
+        let mut seen: Vec<usize> = Vec::new();
+        let query = embeddings.row(42);
+        for (list, raw) in raw_lists.iter().enumerate() {
+            seen.extend(
+                head.scan_list(query, list, raw)?
+                    .into_iter()
+                    .map(|(r, _)| r),
+            );
+        }
+        seen.sort();
+        assert_eq!(seen, (0..400).collect::<Vec<usize>>());
+        // the nearest list holds the query, scored as the closest
+        let probed: Vec<usize> = head.probe(query, 2)?;
+        assert_eq!(probed.len(), 2);
+        let mut found: Vec<(usize, f32)> = Vec::new();
+        for list in probed {
+            found.extend(head.scan_list(query, list, &raw_lists[list])?);
+        }
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let top: Vec<usize> = found.iter().take(10).map(|(r, _)| *r).collect();
+        assert!(top.contains(&42));
+        assert!(top.iter().all(|r| r % 8 == 42 % 8));
+        // invalid settings and values are rejected
+        let odd = IvfPqConfig {
+            subspaces: 5,
+            ..Default::default()
+        };
+        assert!(encode_index(embeddings.view(), &odd).is_err());
+        assert!(head.probe(query.slice(ndarray::s![..8]), 1).is_err());
+        assert!(head.scan_list(query, 0, &raw_lists[0][1..]).is_err());
+        assert!(head
+            .scan_list(query.slice(ndarray::s![..8]), 0, &raw_lists[0])
+            .is_err());
+        assert!(head.scan_list(query, 8, &raw_lists[0]).is_err());
+        // counts that overflow and codes past the trained centroids
+        let mut overflowing: Vec<u8> = raw_lists[0].clone();
+        overflowing[..LIST_HEADER_LEN].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            head.scan_list(query, 0, &overflowing),
+            Err(ValentinusError::CorruptionError)
+        ));
+        let (raw_small, small_lists) =
+            encode_index(embeddings.slice(ndarray::s![..20, ..]), &config)?;
+        let small: IvfPqHead = decode_head(&raw_small)?;
+        let (list, raw) = small_lists
+            .iter()
+            .enumerate()
+            .find(|(_, raw)| raw.len() > LIST_HEADER_LEN)
+            .ok_or(ValentinusError::IndexError)?;
+        let mut invalid: Vec<u8> = raw.clone();
+        let last: usize = invalid.len() - 1;
+        invalid[last] = 255;
+        assert!(small.scan_list(query, list, raw).is_ok());
+        assert!(matches!(
+            small.scan_list(query, list, &invalid),
+            Err(ValentinusError::CorruptionError)
+        ));
+        Ok(())
+    }
+}
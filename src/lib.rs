@@ -1,3 +1,12 @@
+/// Hot backup and restore.
+///
+mod backup;
+/// Document chunking.
+///
+mod chunking;
+/// Compression of stored collections.
+///
+mod compression;
 /// LMDB bindings.
 ///
 mod database;
@@ -8,9 +17,39 @@ mod database;
 /// Apache-2.0 License.
 ///
 pub mod embeddings;
+/// Encryption at rest.
+///
+mod encryption;
+/// Portable collection export.
+///
+mod export;
+/// Inverted file index with product quantization.
+///
+mod ivf;
 /// Multi-dimensional Metadata filter
 ///
 mod md2f;
+/// In-memory storage backend.
+///
+mod memory;
+/// Format versions and migrations.
+///
+mod migrate;
+/// Maximal marginal relevance reranking.
+///
+mod mmr;
 /// ONNX interface.
 ///
 mod onnx;
+/// Quantization of stored embeddings.
+///
+mod quantization;
+/// Parallel exact scoring.
+///
+mod scan;
+/// Contiguous embedding storage.
+///
+mod vectors;
+/// Consistency checks and repair.
+///
+mod verify;
@@ -0,0 +1,118 @@
+#![deny(missing_docs)]
+
+//! In-memory storage backend for tests and ephemeral collections.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use kn0sys_lmdb_rs::MdbError;
+use log::*;
+
+use crate::database::*;
+
+/// Keeps every key in a sorted map. Nothing touches the filesystem and
+///
+/// everything is dropped with the backend, so each instance is isolated.
+pub struct MemoryBackend {
+    store: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        let mut store: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        store.insert(
+            VALENTINUS_FORMAT.as_bytes().to_vec(),
+            FORMAT_VERSION.to_be_bytes().to_vec(),
+        );
+        MemoryBackend {
+            store: RwLock::new(store),
+        }
+    }
+}
+
+impl MemoryBackend {
+    /// Create an empty backend at the current format version
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+        if k.is_empty() {
+            error!("can't read empty key");
+            return Err(MdbError::NotFound);
+        }
+        let store = self.store.read().map_err(|_| MdbError::Panic)?;
+        let result: Vec<u8> = store.get(k).cloned().unwrap_or_default();
+        if result.is_empty() {
+            error!("failed to read key {:?} from memory", k);
+        }
+        Ok(result)
+    }
+    fn keys(&self) -> Result<Vec<Vec<u8>>, MdbError> {
+        let store = self.store.read().map_err(|_| MdbError::Panic)?;
+        Ok(store.keys().cloned().collect())
+    }
+    fn update(&self, batch: &WriteBatch, f: &mut UpdateFn) -> Result<(), MdbError> {
+        let mut store = self.store.write().map_err(|_| MdbError::Panic)?;
+        let mut staged = WriteBatch::new();
+        {
+            let read = |k: &[u8]| Ok(store.get(k).cloned().unwrap_or_default());
+            f(&read, &mut staged)?;
+        }
+        let ops: Vec<&BatchOp> = batch
+            .get_operations()
+            .iter()
+            .chain(staged.get_operations())
+            .collect();
+        if ops.iter().any(|op| op.key().is_empty()) {
+            error!("can't write or delete empty key");
+            return Err(MdbError::NotFound);
+        }
+        for op in ops {
+            match op {
+                BatchOp::Put(k, v) | BatchOp::PutUnchunked(k, v) => {
+                    store.insert(k.clone(), v.clone());
+                }
+                BatchOp::Delete(k) => {
+                    store.remove(k);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn memory_backend_test() -> Result<(), MdbError> {
+        let db = MemoryBackend::new();
+        assert_eq!(db.format_version()?, FORMAT_VERSION);
+        let mut batch = WriteBatch::new();
+        batch.put(b"k", b"v");
+        batch.put(b"gone", b"v");
+        batch.delete(b"gone");
+        db.commit_batch(&batch)?;
+        assert_eq!(db.read(b"k")?, b"v".to_vec());
+        assert!(db.read(b"gone")?.is_empty());
+        assert_eq!(
+            db.keys()?,
+            vec![VALENTINUS_FORMAT.as_bytes().to_vec(), b"k".to_vec()]
+        );
+        // a batch with an empty key is rejected as a whole
+        let mut batch = WriteBatch::new();
+        batch.put(b"k", b"changed");
+        batch.put(b"", b"v");
+        assert!(db.commit_batch(&batch).is_err());
+        assert_eq!(db.read(b"k")?, b"v".to_vec());
+        assert!(db.copy_to(&std::env::temp_dir(), false).is_err());
+        Ok(())
+    }
+}
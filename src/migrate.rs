@@ -0,0 +1,994 @@
+#![deny(missing_docs)]
+
+//! Versioned encoding of stored collections and their migrations.
+//!
+//! Collections are stored as `VALN`, a big endian `u32` version and the
+//!
+//! bincode payload. Values without the marker predate versioning and are
+//!
+//! version 1. Each struct below freezes the layout of one version, down to
+//!
+//! the enums and settings it embeds, so changing a live type can't change
+//!
+//! how older values decode.
+
+use std::borrow::Cow;
+
+use kn0sys_lmdb_rs::MdbError;
+use log::*;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::*;
+use crate::database::*;
+use crate::embeddings::{EmbeddingCollection, ModelType, ValentinusError};
+use crate::onnx::{
+    EmbedderConfig, ExecutionProvider, LongDocumentStrategy, OptimizationLevel,
+    TruncationDirection, WindowPooling,
+};
+use crate::quantization::{quantize, quantized_key, quantized_len, Quantization};
+use crate::vectors::{encode_vectors, vectors_key, view_vectors};
+
+/// Marks a versioned collection value
+const COLLECTION_MAGIC: &[u8; 4] = b"VALN";
+/// Current collection format version
+pub const COLLECTION_VERSION: u32 = 5;
+/// Upgrades a payload to the next version
+type Migration = fn(&[u8]) -> Result<Vec<u8>, ValentinusError>;
+/// Migration of version `index + 1` to the next version
+const MIGRATIONS: [Migration; 4] = [v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5];
+
+/// `ModelType` as of version 1
+#[derive(Clone, Deserialize, Serialize)]
+enum ModelTypeV1 {
+    AllMiniLmL12V2,
+    AllMiniLmL6V2,
+    Custom,
+}
+
+impl From<&ModelType> for ModelTypeV1 {
+    fn from(model_type: &ModelType) -> Self {
+        match model_type {
+            ModelType::AllMiniLmL12V2 => ModelTypeV1::AllMiniLmL12V2,
+            ModelType::AllMiniLmL6V2 => ModelTypeV1::AllMiniLmL6V2,
+            ModelType::Custom => ModelTypeV1::Custom,
+        }
+    }
+}
+
+impl From<ModelTypeV1> for ModelType {
+    fn from(model_type: ModelTypeV1) -> Self {
+        match model_type {
+            ModelTypeV1::AllMiniLmL12V2 => ModelType::AllMiniLmL12V2,
+            ModelTypeV1::AllMiniLmL6V2 => ModelType::AllMiniLmL6V2,
+            ModelTypeV1::Custom => ModelType::Custom,
+        }
+    }
+}
+
+/// `TruncationDirection` as of version 2
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum TruncationDirectionV2 {
+    Left,
+    Right,
+}
+
+/// `WindowPooling` as of version 2
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum WindowPoolingV2 {
+    Mean,
+    Max,
+}
+
+/// `LongDocumentStrategy` as of version 2
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum LongDocumentStrategyV2 {
+    Truncate,
+    Window {
+        stride: usize,
+        pooling: WindowPoolingV2,
+    },
+}
+
+/// `ExecutionProvider` as of version 2
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum ExecutionProviderV2 {
+    Cpu,
+    Cuda { device_id: i32 },
+    TensorRt { device_id: i32 },
+    Rocm { device_id: i32 },
+    CoreMl,
+    DirectMl { device_id: i32 },
+}
+
+/// `OptimizationLevel` as of version 2
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum OptimizationLevelV2 {
+    Disable,
+    Level1,
+    Level2,
+    Level3,
+}
+
+/// `EmbedderConfig` as of version 2
+#[derive(Clone, Deserialize, Serialize)]
+struct EmbedderConfigV2 {
+    max_length: usize,
+    truncation: TruncationDirectionV2,
+    long_documents: LongDocumentStrategyV2,
+    batch_size: usize,
+    max_batch_tokens: usize,
+    execution_providers: Vec<ExecutionProviderV2>,
+    optimization_level: OptimizationLevelV2,
+    intra_threads: usize,
+    inter_threads: usize,
+}
+
+impl From<&EmbedderConfig> for EmbedderConfigV2 {
+    fn from(config: &EmbedderConfig) -> Self {
+        let pooling = |p: WindowPooling| match p {
+            WindowPooling::Mean => WindowPoolingV2::Mean,
+            WindowPooling::Max => WindowPoolingV2::Max,
+        };
+        EmbedderConfigV2 {
+            max_length: config.max_length,
+            truncation: match config.truncation {
+                TruncationDirection::Left => TruncationDirectionV2::Left,
+                TruncationDirection::Right => TruncationDirectionV2::Right,
+            },
+            long_documents: match config.long_documents {
+                LongDocumentStrategy::Truncate => LongDocumentStrategyV2::Truncate,
+                LongDocumentStrategy::Window { stride, pooling: p } => {
+                    LongDocumentStrategyV2::Window {
+                        stride,
+                        pooling: pooling(p),
+                    }
+                }
+            },
+            batch_size: config.batch_size,
+            max_batch_tokens: config.max_batch_tokens,
+            execution_providers: config
+                .execution_providers
+                .iter()
+                .map(|provider| match *provider {
+                    ExecutionProvider::Cpu => ExecutionProviderV2::Cpu,
+                    ExecutionProvider::Cuda { device_id } => {
+                        ExecutionProviderV2::Cuda { device_id }
+                    }
+                    ExecutionProvider::TensorRt { device_id } => {
+                        ExecutionProviderV2::TensorRt { device_id }
+                    }
+                    ExecutionProvider::Rocm { device_id } => {
+                        ExecutionProviderV2::Rocm { device_id }
+                    }
+                    ExecutionProvider::CoreMl => ExecutionProviderV2::CoreMl,
+                    ExecutionProvider::DirectMl { device_id } => {
+                        ExecutionProviderV2::DirectMl { device_id }
+                    }
+                })
+                .collect(),
+            optimization_level: match config.optimization_level {
+                OptimizationLevel::Disable => OptimizationLevelV2::Disable,
+                OptimizationLevel::Level1 => OptimizationLevelV2::Level1,
+                OptimizationLevel::Level2 => OptimizationLevelV2::Level2,
+                OptimizationLevel::Level3 => OptimizationLevelV2::Level3,
+            },
+            intra_threads: config.intra_threads,
+            inter_threads: config.inter_threads,
+        }
+    }
+}
+
+impl From<EmbedderConfigV2> for EmbedderConfig {
+    fn from(config: EmbedderConfigV2) -> Self {
+        let pooling = |p: WindowPoolingV2| match p {
+            WindowPoolingV2::Mean => WindowPooling::Mean,
+            WindowPoolingV2::Max => WindowPooling::Max,
+        };
+        EmbedderConfig {
+            max_length: config.max_length,
+            truncation: match config.truncation {
+                TruncationDirectionV2::Left => TruncationDirection::Left,
+                TruncationDirectionV2::Right => TruncationDirection::Right,
+            },
+            long_documents: match config.long_documents {
+                LongDocumentStrategyV2::Truncate => LongDocumentStrategy::Truncate,
+                LongDocumentStrategyV2::Window { stride, pooling: p } => {
+                    LongDocumentStrategy::Window {
+                        stride,
+                        pooling: pooling(p),
+                    }
+                }
+            },
+            batch_size: config.batch_size,
+            max_batch_tokens: config.max_batch_tokens,
+            execution_providers: config
+                .execution_providers
+                .into_iter()
+                .map(|provider| match provider {
+                    ExecutionProviderV2::Cpu => ExecutionProvider::Cpu,
+                    ExecutionProviderV2::Cuda { device_id } => {
+                        ExecutionProvider::Cuda { device_id }
+                    }
+                    ExecutionProviderV2::TensorRt { device_id } => {
+                        ExecutionProvider::TensorRt { device_id }
+                    }
+                    ExecutionProviderV2::Rocm { device_id } => {
+                        ExecutionProvider::Rocm { device_id }
+                    }
+                    ExecutionProviderV2::CoreMl => ExecutionProvider::CoreMl,
+                    ExecutionProviderV2::DirectMl { device_id } => {
+                        ExecutionProvider::DirectMl { device_id }
+                    }
+                })
+                .collect(),
+            optimization_level: match config.optimization_level {
+                OptimizationLevelV2::Disable => OptimizationLevel::Disable,
+                OptimizationLevelV2::Level1 => OptimizationLevel::Level1,
+                OptimizationLevelV2::Level2 => OptimizationLevel::Level2,
+                OptimizationLevelV2::Level3 => OptimizationLevel::Level3,
+            },
+            intra_threads: config.intra_threads,
+            inter_threads: config.inter_threads,
+        }
+    }
+}
+
+/// `Compression` as of version 3
+#[derive(Clone, Copy, Deserialize, PartialEq, Serialize)]
+enum CompressionV3 {
+    None,
+    Lz4,
+    Zstd(i32),
+}
+
+impl From<Compression> for CompressionV3 {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => CompressionV3::None,
+            Compression::Lz4 => CompressionV3::Lz4,
+            Compression::Zstd(level) => CompressionV3::Zstd(level),
+        }
+    }
+}
+
+impl From<CompressionV3> for Compression {
+    fn from(compression: CompressionV3) -> Self {
+        match compression {
+            CompressionV3::None => Compression::None,
+            CompressionV3::Lz4 => Compression::Lz4,
+            CompressionV3::Zstd(level) => Compression::Zstd(level),
+        }
+    }
+}
+
+/// `Quantization` as of version 5
+#[derive(Clone, Copy, Deserialize, Serialize)]
+enum QuantizationV5 {
+    None,
+    Int8,
+    Binary,
+}
+
+impl From<Quantization> for QuantizationV5 {
+    fn from(quantization: Quantization) -> Self {
+        match quantization {
+            Quantization::None => QuantizationV5::None,
+            Quantization::Int8 => QuantizationV5::Int8,
+            Quantization::Binary => QuantizationV5::Binary,
+        }
+    }
+}
+
+impl From<QuantizationV5> for Quantization {
+    fn from(quantization: QuantizationV5) -> Self {
+        match quantization {
+            QuantizationV5::None => Quantization::None,
+            QuantizationV5::Int8 => Quantization::Int8,
+            QuantizationV5::Binary => Quantization::Binary,
+        }
+    }
+}
+
+/// Collection layout before versioning
+#[derive(Deserialize, Serialize)]
+struct CollectionV1 {
+    documents: Vec<String>,
+    embeddings: Array2<f32>,
+    metadata: Vec<Vec<String>>,
+    model_path: String,
+    model_type: ModelTypeV1,
+    ids: Vec<String>,
+    key: String,
+    view: String,
+}
+
+/// Version 2 adds the embedder configuration and chunk parents
+#[derive(Deserialize, Serialize)]
+struct CollectionV2 {
+    documents: Vec<String>,
+    embeddings: Array2<f32>,
+    metadata: Vec<Vec<String>>,
+    model_path: String,
+    model_type: ModelTypeV1,
+    embedder: EmbedderConfigV2,
+    ids: Vec<String>,
+    parents: Vec<String>,
+    key: String,
+    view: String,
+}
+
+fn v1_to_v2(payload: &[u8]) -> Result<Vec<u8>, ValentinusError> {
+    let v1: CollectionV1 =
+        bincode::deserialize(payload).map_err(|_| ValentinusError::BincodeError)?;
+    let v2 = CollectionV2 {
+        documents: v1.documents,
+        embeddings: v1.embeddings,
+        metadata: v1.metadata,
+        model_path: v1.model_path,
+        model_type: v1.model_type,
+        embedder: EmbedderConfigV2::from(&EmbedderConfig::default()),
+        ids: v1.ids,
+        parents: Vec::new(),
+        key: v1.key,
+        view: v1.view,
+    };
+    bincode::serialize(&v2).map_err(|_| ValentinusError::BincodeError)
+}
+
+/// Version 3 stores the embeddings apart from the other fields so each
+///
+/// can be compressed with the collection's `Compression`
+#[derive(Deserialize, Serialize)]
+struct CollectionV3 {
+    compression: CompressionV3,
+    rows: u64,
+    cols: u64,
+    /// Length of `body` before compression
+    body_len: u64,
+    /// Compressed bincode of `CollectionBodyV3`
+    body: Vec<u8>,
+    /// Length of `vectors` before compression
+    vectors_len: u64,
+    /// Compressed, byte shuffled embeddings
+    vectors: Vec<u8>,
+}
+
+/// Version 4 stores the embeddings of uncompressed collections apart, so
+///
+/// queries can scan them in place
+#[derive(Deserialize, Serialize)]
+struct CollectionV4 {
+    compression: CompressionV3,
+    rows: u64,
+    cols: u64,
+    /// Length of `body` before compression
+    body_len: u64,
+    /// Compressed bincode of `CollectionBodyV3`
+    body: Vec<u8>,
+    /// Embeddings are stored under `vectors_key` and `vectors` is empty
+    detached: bool,
+    /// Length of `vectors` before compression
+    vectors_len: u64,
+    /// Compressed, byte shuffled embeddings
+    vectors: Vec<u8>,
+}
+
+/// Where the full embeddings of a collection are stored
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Placement {
+    /// In the collection value
+    Inline,
+    /// In one piece under `vectors_key`
+    Detached,
+    /// Only quantized, under `quantized_key`
+    Quantized,
+}
+
+/// Version 5 adds quantization. Embeddings of quantized collections
+///
+/// that opted out of the full embeddings are only stored quantized
+#[derive(Deserialize, Serialize)]
+struct CollectionV5 {
+    compression: CompressionV3,
+    quantization: QuantizationV5,
+    /// Candidates of the quantized first pass rescored with the full embeddings
+    rescore: u64,
+    rows: u64,
+    cols: u64,
+    /// Length of `body` before compression
+    body_len: u64,
+    /// Compressed bincode of `CollectionBodyV3`
+    body: Vec<u8>,
+    /// Unless `Placement::Inline`, `vectors` is empty
+    placement: Placement,
+    /// Length of `vectors` before compression
+    vectors_len: u64,
+    /// Compressed, byte shuffled embeddings
+    vectors: Vec<u8>,
+}
+
+/// Every field of a version 3 to 5 collection besides the embeddings
+#[derive(Deserialize, Serialize)]
+struct CollectionBodyV3<'a> {
+    documents: Cow<'a, [String]>,
+    metadata: Cow<'a, [Vec<String>]>,
+    model_path: Cow<'a, str>,
+    model_type: ModelTypeV1,
+    embedder: EmbedderConfigV2,
+    ids: Cow<'a, [String]>,
+    parents: Cow<'a, [String]>,
+    key: Cow<'a, str>,
+    view: Cow<'a, str>,
+}
+
+fn v2_to_v3(payload: &[u8]) -> Result<Vec<u8>, ValentinusError> {
+    let v2: CollectionV2 =
+        bincode::deserialize(payload).map_err(|_| ValentinusError::BincodeError)?;
+    let body = CollectionBodyV3 {
+        documents: Cow::Owned(v2.documents),
+        metadata: Cow::Owned(v2.metadata),
+        model_path: Cow::Owned(v2.model_path),
+        model_type: v2.model_type,
+        embedder: v2.embedder,
+        ids: Cow::Owned(v2.ids),
+        parents: Cow::Owned(v2.parents),
+        key: Cow::Owned(v2.key),
+        view: Cow::Owned(v2.view),
+    };
+    // uncompressed, so both parts are stored as is
+    let raw_body: Vec<u8> = bincode::serialize(&body).map_err(|_| ValentinusError::BincodeError)?;
+    let values: Vec<f32> = v2.embeddings.iter().copied().collect();
+    let raw_vectors: Vec<u8> = shuffle(&values);
+    let v3 = CollectionV3 {
+        compression: CompressionV3::None,
+        rows: v2.embeddings.nrows() as u64,
+        cols: v2.embeddings.ncols() as u64,
+        body_len: raw_body.len() as u64,
+        body: raw_body,
+        vectors_len: raw_vectors.len() as u64,
+        vectors: raw_vectors,
+    };
+    bincode::serialize(&v3).map_err(|_| ValentinusError::BincodeError)
+}
+
+fn v3_to_v4(payload: &[u8]) -> Result<Vec<u8>, ValentinusError> {
+    let v3: CollectionV3 =
+        bincode::deserialize(payload).map_err(|_| ValentinusError::BincodeError)?;
+    let v4 = CollectionV4 {
+        compression: v3.compression,
+        rows: v3.rows,
+        cols: v3.cols,
+        body_len: v3.body_len,
+        body: v3.body,
+        detached: false,
+        vectors_len: v3.vectors_len,
+        vectors: v3.vectors,
+    };
+    bincode::serialize(&v4).map_err(|_| ValentinusError::BincodeError)
+}
+
+fn v4_to_v5(payload: &[u8]) -> Result<Vec<u8>, ValentinusError> {
+    let v4: CollectionV4 =
+        bincode::deserialize(payload).map_err(|_| ValentinusError::BincodeError)?;
+    let v5 = CollectionV5 {
+        compression: v4.compression,
+        quantization: QuantizationV5::None,
+        rescore: 0,
+        rows: v4.rows,
+        cols: v4.cols,
+        body_len: v4.body_len,
+        body: v4.body,
+        placement: if v4.detached {
+            Placement::Detached
+        } else {
+            Placement::Inline
+        },
+        vectors_len: v4.vectors_len,
+        vectors: v4.vectors,
+    };
+    bincode::serialize(&v5).map_err(|_| ValentinusError::BincodeError)
+}
+
+/// Compress the body and, when `Placement::Inline`, the embeddings into
+///
+/// a version 5 collection
+fn pack(
+    body: &CollectionBodyV3,
+    embeddings: &Array2<f32>,
+    compression: Compression,
+    quantization: Quantization,
+    rescore: usize,
+    placement: Placement,
+) -> Result<CollectionV5, ValentinusError> {
+    let raw_body: Vec<u8> = bincode::serialize(body).map_err(|_| ValentinusError::BincodeError)?;
+    let raw_vectors: Vec<u8> = if placement == Placement::Inline {
+        let values: Vec<f32> = embeddings.iter().copied().collect();
+        shuffle(&values)
+    } else {
+        Vec::new()
+    };
+    Ok(CollectionV5 {
+        compression: CompressionV3::from(compression),
+        quantization: QuantizationV5::from(quantization),
+        rescore: rescore as u64,
+        rows: embeddings.nrows() as u64,
+        cols: embeddings.ncols() as u64,
+        body_len: raw_body.len() as u64,
+        body: compress(compression, &raw_body)?,
+        placement,
+        vectors_len: raw_vectors.len() as u64,
+        vectors: compress(compression, &raw_vectors)?,
+    })
+}
+
+/// Decompress a version 5 payload. Embeddings that aren't inline are
+///
+/// left empty
+fn unpack(payload: &[u8]) -> Result<(EmbeddingCollection, Placement), ValentinusError> {
+    let v5: CollectionV5 =
+        bincode::deserialize(payload).map_err(|_| ValentinusError::BincodeError)?;
+    let compression: Compression = Compression::from(v5.compression);
+    let raw_body: Vec<u8> = decompress(compression, &v5.body, v5.body_len as usize)?;
+    let body: CollectionBodyV3 =
+        bincode::deserialize(&raw_body).map_err(|_| ValentinusError::BincodeError)?;
+    let embeddings: Array2<f32> = if v5.placement == Placement::Inline {
+        let raw_vectors: Vec<u8> = decompress(compression, &v5.vectors, v5.vectors_len as usize)?;
+        Array2::from_shape_vec(
+            (v5.rows as usize, v5.cols as usize),
+            unshuffle(&raw_vectors)?,
+        )
+        .map_err(|_| ValentinusError::CompressionError)?
+    } else {
+        Array2::zeros((0, v5.cols as usize))
+    };
+    let collection = EmbeddingCollection {
+        documents: body.documents.into_owned(),
+        embeddings,
+        metadata: body.metadata.into_owned(),
+        model_path: body.model_path.into_owned(),
+        model_type: ModelType::from(body.model_type),
+        embedder: EmbedderConfig::from(body.embedder),
+        compression,
+        quantization: Quantization::from(v5.quantization),
+        rescore: v5.rescore as usize,
+        quantized_only: v5.placement == Placement::Quantized,
+        ids: body.ids.into_owned(),
+        parents: body.parents.into_owned(),
+        key: body.key.into_owned(),
+        view: body.view.into_owned(),
+    };
+    Ok((collection, v5.placement))
+}
+
+/// Split a stored value into its version and payload
+fn split(raw: &[u8]) -> (u32, &[u8]) {
+    match raw.strip_prefix(COLLECTION_MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let (version, payload) = rest.split_at(4);
+            let bytes: [u8; 4] = version.try_into().unwrap_or_default();
+            (u32::from_be_bytes(bytes), payload)
+        }
+        _ => (1, raw),
+    }
+}
+
+/// Bring a stored value up to the current version. Returns `None` when
+///
+/// it is already current.
+fn upgrade(raw: &[u8]) -> Result<Option<Vec<u8>>, ValentinusError> {
+    let (version, payload) = split(raw);
+    if version > COLLECTION_VERSION || version == 0 {
+        error!("unsupported collection version {}", version);
+        return Err(ValentinusError::FormatVersionError(version));
+    }
+    if version == COLLECTION_VERSION {
+        return Ok(None);
+    }
+    let mut payload: Vec<u8> = payload.to_vec();
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        payload = migration(&payload)?;
+    }
+    Ok(Some(payload))
+}
+
+/// Payload of a stored value in the current version
+fn current_payload(raw: &[u8]) -> Result<Cow<'_, [u8]>, ValentinusError> {
+    Ok(match upgrade(raw)? {
+        Some(payload) => Cow::Owned(payload),
+        None => Cow::Borrowed(split(raw).1),
+    })
+}
+
+/// Prefix `payload` with the marker and current version
+fn envelope(payload: &[u8]) -> Vec<u8> {
+    let mut raw: Vec<u8> = Vec::with_capacity(payload.len() + 8);
+    raw.extend_from_slice(COLLECTION_MAGIC);
+    raw.extend_from_slice(&COLLECTION_VERSION.to_be_bytes());
+    raw.extend_from_slice(payload);
+    raw
+}
+
+/// Borrow every field of `collection` besides the embeddings
+fn body_of(collection: &EmbeddingCollection) -> CollectionBodyV3<'_> {
+    CollectionBodyV3 {
+        documents: Cow::Borrowed(&collection.documents[..]),
+        metadata: Cow::Borrowed(&collection.metadata[..]),
+        model_path: Cow::Borrowed(&collection.model_path),
+        model_type: ModelTypeV1::from(&collection.model_type),
+        embedder: EmbedderConfigV2::from(&collection.embedder),
+        ids: Cow::Borrowed(&collection.ids[..]),
+        parents: Cow::Borrowed(&collection.parents[..]),
+        key: Cow::Borrowed(&collection.key),
+        view: Cow::Borrowed(&collection.view),
+    }
+}
+
+/// Encode a collection for storage with its `Compression`, the
+///
+/// embeddings placed as given
+pub fn encode_collection(
+    collection: &EmbeddingCollection,
+    placement: Placement,
+) -> Result<Vec<u8>, ValentinusError> {
+    let v5: CollectionV5 = pack(
+        &body_of(collection),
+        &collection.embeddings,
+        collection.compression,
+        collection.quantization,
+        collection.rescore,
+        placement,
+    )?;
+    let payload: Vec<u8> = bincode::serialize(&v5).map_err(|_| ValentinusError::BincodeError)?;
+    Ok(envelope(&payload))
+}
+
+/// Stage the write of `collection` under `key`. Uncompressed embeddings
+///
+/// are written apart, in one piece under `vectors_key`, so queries can
+///
+/// scan them without decoding the collection. Quantized embeddings are
+///
+/// written in one piece under `quantized_key`; the full embeddings are
+///
+/// only dropped when the collection opted in with `set_quantized_only`
+///
+/// and isn't rescored.
+pub fn stage_collection(
+    batch: &mut WriteBatch,
+    key: &[u8],
+    collection: &EmbeddingCollection,
+) -> Result<(), ValentinusError> {
+    let placement: Placement = if collection.quantized_only
+        && collection.quantization != Quantization::None
+        && collection.rescore == 0
+    {
+        Placement::Quantized
+    } else if collection.compression == Compression::None {
+        Placement::Detached
+    } else {
+        Placement::Inline
+    };
+    batch.put(key, &encode_collection(collection, placement)?);
+    let vectors: String = vectors_key(&collection.key);
+    if placement == Placement::Detached {
+        batch.put_unchunked(vectors.as_bytes(), &encode_vectors(&collection.embeddings));
+    } else {
+        batch.delete(vectors.as_bytes());
+    }
+    let quantized: String = quantized_key(&collection.key);
+    if collection.quantization == Quantization::None {
+        batch.delete(quantized.as_bytes());
+    } else {
+        let codes: Vec<u8> = quantize(collection.quantization, collection.embeddings.view());
+        batch.put_unchunked(quantized.as_bytes(), &codes);
+    }
+    Ok(())
+}
+
+/// Decode a stored collection of any supported version. Embeddings
+///
+/// that aren't inline are left empty, the returned placement tells
+///
+/// where they are.
+pub fn decode_stored(raw: &[u8]) -> Result<(EmbeddingCollection, Placement), ValentinusError> {
+    unpack(&current_payload(raw)?)
+}
+
+/// Decode a stored collection of any supported version, see `decode_stored`
+pub fn decode_collection(raw: &[u8]) -> Result<EmbeddingCollection, ValentinusError> {
+    Ok(decode_stored(raw)?.0)
+}
+
+/// Sizes of a stored collection before and after compression, without
+///
+/// decompressing it
+pub fn collection_stats(raw: &[u8]) -> Result<CompressionStats, ValentinusError> {
+    let v5: CollectionV5 =
+        bincode::deserialize(&current_payload(raw)?).map_err(|_| ValentinusError::BincodeError)?;
+    let full: u64 = v5.rows * v5.cols * 4;
+    // embeddings stored apart aren't compressed
+    let (embeddings_raw, embeddings_stored) = match v5.placement {
+        Placement::Inline => (v5.vectors_len, v5.vectors.len() as u64),
+        Placement::Detached => (full, full),
+        Placement::Quantized => (
+            full,
+            quantized_len(Quantization::from(v5.quantization), v5.rows, v5.cols),
+        ),
+    };
+    Ok(CompressionStats {
+        compression: Compression::from(v5.compression),
+        documents_raw: v5.body_len,
+        documents_stored: v5.body.len() as u64,
+        embeddings_raw,
+        embeddings_stored,
+    })
+}
+
+/// Rewrite every collection stored in an older version and record the
+///
+/// current database format version. Returns the number of collections
+///
+/// upgraded. Each collection is committed on its own, so an interrupted
+///
+/// migration can simply be run again. Corrupted or undecodable collections
+///
+/// are skipped and left for `verify` to quarantine.
+pub fn migrate(db: &dyn StorageBackend) -> Result<usize, ValentinusError> {
+    info!("migrating database to format version {}", FORMAT_VERSION);
+    let mut migrated: usize = 0;
+    for key in db.keys().map_err(ValentinusError::from)? {
+        if !is_collection_key(&key) {
+            continue;
+        }
+        // leave bit-rotted values for verify to quarantine
+        let raw: Vec<u8> = match db.read(&key) {
+            Ok(raw) => raw,
+            Err(MdbError::Corrupted) => {
+                warn!("skipping corrupted collection {:?}", key);
+                continue;
+            }
+            Err(e) => return Err(ValentinusError::from(e)),
+        };
+        let decoded = match upgrade(&raw) {
+            Ok(Some(payload)) => unpack(&payload),
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        let Ok((mut collection, placement)) = decoded else {
+            warn!("skipping undecodable collection {:?}", key);
+            continue;
+        };
+        // older versions only store embeddings inline or detached, load
+        // detached ones so they're written back instead of emptied
+        if placement == Placement::Detached {
+            let vectors: Vec<u8> = match db.read(vectors_key(&collection.key).as_bytes()) {
+                Ok(vectors) => vectors,
+                Err(MdbError::Corrupted) => {
+                    warn!("skipping collection {:?} with corrupted embeddings", key);
+                    continue;
+                }
+                Err(e) => return Err(ValentinusError::from(e)),
+            };
+            collection.embeddings = view_vectors(&vectors)?.into_owned();
+        }
+        let mut batch = WriteBatch::new();
+        stage_collection(&mut batch, &key, &collection)?;
+        db.commit_batch(&batch).map_err(ValentinusError::from)?;
+        migrated += 1;
+    }
+    let mut batch = WriteBatch::new();
+    batch.put(VALENTINUS_FORMAT.as_bytes(), &FORMAT_VERSION.to_be_bytes());
+    db.commit_batch(&batch).map_err(ValentinusError::from)?;
+    info!("migrated {} collections", migrated);
+    Ok(migrated)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::embeddings::{find, DatabaseConfig, KeyViewIndexer, Valentinus};
+    use std::path::Path;
+
+    const FIXTURE_KEY: &str = "key-00000000-0000-4000-8000-000000000001";
+    const FIXTURE_VIEW: &str = "view-legacy";
+
+    /// Collection written by valentinus before format versioning
+    fn v1_fixture() -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("data")
+            .join("fixtures")
+            .join("collection_v1.bin");
+        std::fs::read(path).expect("fixture should exist")
+    }
+
+    #[test]
+    fn decode_v1_fixture_test() -> Result<(), ValentinusError> {
+        let collection: EmbeddingCollection = decode_collection(&v1_fixture())?;
+        assert_eq!(collection.get_key(), FIXTURE_KEY);
+        assert_eq!(collection.get_view(), FIXTURE_VIEW);
+        assert_eq!(
+            collection.get_ids(),
+            &vec![String::from("id0"), String::from("id1")]
+        );
+        assert_eq!(collection.get_embedder_config(), &EmbedderConfig::default());
+        assert!(collection.get_parents().is_empty());
+        // re-encoding writes the current version
+        let encoded: Vec<u8> = encode_collection(&collection, Placement::Inline)?;
+        assert_eq!(split(&encoded).0, COLLECTION_VERSION);
+        assert_eq!(
+            decode_collection(&encoded)?.get_documents(),
+            collection.get_documents()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn migration_layout_test() -> Result<(), ValentinusError> {
+        // every step writes exactly the next layout, which re-encodes identically
+        let v2: Vec<u8> = v1_to_v2(&v1_fixture())?;
+        let decoded: CollectionV2 =
+            bincode::deserialize(&v2).map_err(|_| ValentinusError::BincodeError)?;
+        assert_eq!(bincode::serialize(&decoded).ok(), Some(v2.clone()));
+        let v3: Vec<u8> = v2_to_v3(&v2)?;
+        let decoded: CollectionV3 =
+            bincode::deserialize(&v3).map_err(|_| ValentinusError::BincodeError)?;
+        assert_eq!(bincode::serialize(&decoded).ok(), Some(v3.clone()));
+        let v4: Vec<u8> = v3_to_v4(&v3)?;
+        let decoded: CollectionV4 =
+            bincode::deserialize(&v4).map_err(|_| ValentinusError::BincodeError)?;
+        assert_eq!(bincode::serialize(&decoded).ok(), Some(v4.clone()));
+        let v5: Vec<u8> = v4_to_v5(&v4)?;
+        let (collection, placement) = unpack(&v5)?;
+        assert_eq!(placement, Placement::Inline);
+        assert_eq!(collection.get_key(), FIXTURE_KEY);
+        assert_eq!(collection.get_embedder_config(), &EmbedderConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_detached_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        let mut collection: EmbeddingCollection = decode_collection(&v1_fixture())?;
+        collection.embeddings = ndarray::array![[0.6, 0.8, 0.0], [0.0, 0.28, 0.96]];
+        {
+            // a version 4 collection with its embeddings stored apart
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            let raw_body: Vec<u8> = bincode::serialize(&body_of(&collection))
+                .map_err(|_| ValentinusError::BincodeError)?;
+            let v4 = CollectionV4 {
+                compression: CompressionV3::None,
+                rows: 2,
+                cols: 3,
+                body_len: raw_body.len() as u64,
+                body: raw_body,
+                detached: true,
+                vectors_len: 0,
+                vectors: Vec::new(),
+            };
+            let payload: Vec<u8> =
+                bincode::serialize(&v4).map_err(|_| ValentinusError::BincodeError)?;
+            let mut raw: Vec<u8> = COLLECTION_MAGIC.to_vec();
+            raw.extend_from_slice(&4u32.to_be_bytes());
+            raw.extend_from_slice(&payload);
+            let indexer = |v: &str| {
+                bincode::serialize(&KeyViewIndexer::new(&[String::from(v)])).unwrap_or_default()
+            };
+            let mut batch = WriteBatch::new();
+            batch.put(VALENTINUS_FORMAT.as_bytes(), &4u32.to_be_bytes());
+            batch.put(FIXTURE_KEY.as_bytes(), &raw);
+            batch.put_unchunked(
+                vectors_key(FIXTURE_KEY).as_bytes(),
+                &encode_vectors(&collection.embeddings),
+            );
+            batch.put(VALENTINUS_KEYS.as_bytes(), &indexer(FIXTURE_KEY));
+            batch.put(VALENTINUS_VIEWS.as_bytes(), &indexer(FIXTURE_VIEW));
+            let lookup: String = format!("{}-{}", VALENTINUS_KEY, FIXTURE_VIEW);
+            batch.put(lookup.as_bytes(), FIXTURE_KEY.as_bytes());
+            db.commit_batch(&batch).map_err(ValentinusError::from)?;
+        }
+        let valentinus = Valentinus::open(&config)?;
+        assert!(!valentinus.needs_migration()?);
+        let found = find(&valentinus, None, Some(String::from(FIXTURE_VIEW)))?;
+        assert_eq!(found.embeddings, collection.embeddings);
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_corrupted_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        let rotten_key = "key-00000000-0000-4000-8000-000000000002";
+        let rotten: Vec<u8> = [v1_fixture(), b"bit-rotted collection".to_vec()].concat();
+        {
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            let indexer = |v: &str| {
+                bincode::serialize(&KeyViewIndexer::new(&[String::from(v)])).unwrap_or_default()
+            };
+            let mut batch = WriteBatch::new();
+            batch.delete(VALENTINUS_FORMAT.as_bytes());
+            batch.put(FIXTURE_KEY.as_bytes(), &v1_fixture());
+            batch.put(rotten_key.as_bytes(), &rotten);
+            batch.put(VALENTINUS_KEYS.as_bytes(), &indexer(FIXTURE_KEY));
+            batch.put(VALENTINUS_VIEWS.as_bytes(), &indexer(FIXTURE_VIEW));
+            let lookup: String = format!("{}-{}", VALENTINUS_KEY, FIXTURE_VIEW);
+            batch.put(lookup.as_bytes(), FIXTURE_KEY.as_bytes());
+            db.commit_batch(&batch).map_err(ValentinusError::from)?;
+        }
+        // flip a bit of the second collection on disk
+        let data_file = path.join(&config.env).join("data.mdb");
+        let mut data: Vec<u8> = std::fs::read(&data_file).map_err(ValentinusError::IoError)?;
+        let offset: usize = data
+            .windows(rotten.len())
+            .position(|w| w == &rotten[..])
+            .ok_or(ValentinusError::TestError)?;
+        data[offset + rotten.len() - 1] ^= 1;
+        std::fs::write(&data_file, &data).map_err(ValentinusError::IoError)?;
+        let valentinus = Valentinus::open(&config)?;
+        assert!(!valentinus.needs_migration()?);
+        let found = find(&valentinus, None, Some(String::from(FIXTURE_VIEW)))?;
+        assert_eq!(found.get_key(), FIXTURE_KEY);
+        let report = valentinus.verify(true)?;
+        assert_eq!(report.get_quarantined(), &vec![String::from(rotten_key)]);
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_version_test() {
+        let mut raw: Vec<u8> = COLLECTION_MAGIC.to_vec();
+        raw.extend_from_slice(&(COLLECTION_VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            decode_collection(&raw),
+            Err(ValentinusError::FormatVersionError(v)) if v == COLLECTION_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn migrate_on_open_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = DatabaseConfig::new(&path);
+        {
+            // lay out a database as written before versioning
+            let db = DatabaseEnvironment::open(&config).map_err(ValentinusError::from)?;
+            let indexer = |v: &str| {
+                bincode::serialize(&KeyViewIndexer::new(&[String::from(v)])).unwrap_or_default()
+            };
+            let mut batch = WriteBatch::new();
+            batch.delete(VALENTINUS_FORMAT.as_bytes());
+            batch.put(FIXTURE_KEY.as_bytes(), &v1_fixture());
+            batch.put(VALENTINUS_KEYS.as_bytes(), &indexer(FIXTURE_KEY));
+            batch.put(VALENTINUS_VIEWS.as_bytes(), &indexer(FIXTURE_VIEW));
+            let lookup: String = format!("{}-{}", VALENTINUS_KEY, FIXTURE_VIEW);
+            batch.put(lookup.as_bytes(), FIXTURE_KEY.as_bytes());
+            db.commit_batch(&batch).map_err(ValentinusError::from)?;
+        }
+        {
+            let manual = DatabaseConfig {
+                migrate_on_open: false,
+                ..config.clone()
+            };
+            let valentinus = Valentinus::open(&manual)?;
+            assert!(valentinus.needs_migration()?);
+            // old collections are still readable before migrating
+            let found = find(&valentinus, None, Some(String::from(FIXTURE_VIEW)))?;
+            assert_eq!(found.get_key(), FIXTURE_KEY);
+        }
+        let valentinus = Valentinus::open(&config)?;
+        assert!(!valentinus.needs_migration()?);
+        let raw: Vec<u8> = valentinus
+            .db
+            .read(FIXTURE_KEY.as_bytes())
+            .map_err(ValentinusError::from)?;
+        assert_eq!(split(&raw).0, COLLECTION_VERSION);
+        assert!(valentinus.verify(false)?.is_consistent());
+        assert_eq!(valentinus.migrate()?, 0);
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+#![deny(missing_docs)]
+
+//! Maximal marginal relevance (MMR) reranking.
+//!
+//! Candidates are picked one at a time, each maximizing
+//!
+//! `lambda * relevance - (1 - lambda) * redundancy`, where the redundancy
+//!
+//! of a candidate is its highest cosine similarity to the ones already
+//!
+//! picked.
+
+use log::*;
+use ndarray::{Array1, Array2, ArrayView2, Axis};
+
+use crate::embeddings::ValentinusError;
+
+/// Order up to `k` of the `candidates`, one embedding per row, by maximal
+///
+/// marginal relevance. `relevance` is the similarity of each candidate to
+///
+/// the query. A `lambda` of 1 keeps the relevance order and 0 favours
+///
+/// diversity only. Returns indices into `candidates`.
+pub fn mmr(
+    candidates: ArrayView2<f32>,
+    relevance: &[f32],
+    lambda: f32,
+    k: usize,
+) -> Result<Vec<usize>, ValentinusError> {
+    if !(0.0..=1.0).contains(&lambda) {
+        error!("MMR lambda {} isn't between 0 and 1", lambda);
+        return Err(ValentinusError::CosineError);
+    }
+    if candidates.nrows() != relevance.len() {
+        error!("MMR needs a relevance score per candidate");
+        return Err(ValentinusError::CosineError);
+    }
+    let n: usize = relevance.len();
+    // normalized, so approximate embeddings compare as cosines too
+    let norms: Array1<f32> =
+        candidates.map_axis(Axis(1), |row| row.dot(&row).sqrt().max(f32::EPSILON));
+    let unit: Array2<f32> = &candidates / &norms.insert_axis(Axis(1));
+    let similarities: Array2<f32> = unit.dot(&unit.t());
+    let mut redundancy: Vec<f32> = vec![f32::NEG_INFINITY; n];
+    let mut picked: Vec<bool> = vec![false; n];
+    let mut order: Vec<usize> = Vec::with_capacity(k.min(n));
+    while order.len() < k.min(n) {
+        let marginal = |i: usize| {
+            // nothing is redundant with an empty selection
+            let penalty: f32 = if order.is_empty() { 0.0 } else { redundancy[i] };
+            lambda * relevance[i] - (1.0 - lambda) * penalty
+        };
+        let best: Option<usize> = (0..n)
+            .filter(|i| !picked[*i])
+            .max_by(|a, b| marginal(*a).total_cmp(&marginal(*b)).then(b.cmp(a)));
+        let Some(best) = best else { break };
+        picked[best] = true;
+        order.push(best);
+        for (i, r) in redundancy.iter_mut().enumerate() {
+            *r = r.max(similarities[[i, best]]);
+        }
+    }
+    Ok(order)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use ndarray::array;
+
+    #[test]
+    fn mmr_test() -> Result<(), ValentinusError> {
+        // two near duplicates and a distinct, less relevant row of another scale
+        let candidates: Array2<f32> = array![[1.0, 0.0], [0.995, 0.0998], [0.0, 2.0]];
+        let relevance: Vec<f32> = vec![0.9, 0.89, 0.7];
+        // relevance alone keeps the duplicates together
+        assert_eq!(mmr(candidates.view(), &relevance, 1.0, 3)?, vec![0, 1, 2]);
+        // balancing for diversity promotes the distinct row
+        assert_eq!(mmr(candidates.view(), &relevance, 0.5, 3)?, vec![0, 2, 1]);
+        assert_eq!(mmr(candidates.view(), &relevance, 0.5, 2)?, vec![0, 2]);
+        assert_eq!(mmr(candidates.view(), &relevance, 0.5, 10)?.len(), 3);
+        assert!(mmr(candidates.view(), &relevance, 1.5, 3).is_err());
+        assert!(mmr(candidates.view(), &relevance[..2], 0.5, 3).is_err());
+        Ok(())
+    }
+}
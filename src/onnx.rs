@@ -4,86 +4,406 @@
 
 use ndarray::*;
 use ort::{
-    execution_providers::CUDAExecutionProvider,
+    execution_providers::{
+        CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider,
+        DirectMLExecutionProvider, ExecutionProviderDispatch, ROCmExecutionProvider,
+        TensorRTExecutionProvider,
+    },
     session::builder::GraphOptimizationLevel,
     session::Session,
-    value::TensorRef
+    value::TensorRef,
 };
-use tokenizers::Tokenizer;
+use serde::{Deserialize, Serialize};
+use tokenizers::{Encoding, Tokenizer, TruncationParams};
 
 use log::*;
 
-/// Used for controlling the amount of data being encoded
+/// Default number of documents encoded per batch
 pub const BATCH_SIZE: usize = 100;
 
+/// Default padded token budget of a single batch
+const DEFAULT_MAX_BATCH_TOKENS: usize = 32_768;
+
+/// Default maximum sequence length accepted by the model
+const DEFAULT_MAX_LENGTH: usize = 512;
+
 /// Default dimensions for the all-mini-lm-l6 model
 const DEFUALT_DIMENSIONS: usize = 384;
 
 /// Custom dimensions when setting custom models
 const VALENTINUS_CUSTOM_DIM: &str = "VALENTINUS_CUSTOM_DIM";
 
-/// Environment variable for parallel execution threads count
-const ONNX_PARALLEL_THREADS: &str = "ONNX_PARALLEL_THREADS";
-
 #[derive(Debug)]
 pub enum OnnxError {
     OrtError(ort::Error),
     ShapeError(ShapeError),
 }
 
-/// ONNX Embeddings generator
-fn generate_embeddings(model_path: &String, data: &[String]) -> Result<Array2<f32>, OnnxError> {
-    let threads: usize = match std::env::var(ONNX_PARALLEL_THREADS) {
-        Err(_) => 1,
-        Ok(t) => t.parse::<usize>().unwrap_or(1),
+/// Which end of an over-long document is dropped when truncating
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum TruncationDirection {
+    /// Drop tokens from the start, keeping the end of the document
+    Left,
+    /// Drop tokens from the end, keeping the start of the document
+    #[default]
+    Right,
+}
+
+impl From<TruncationDirection> for tokenizers::TruncationDirection {
+    fn from(d: TruncationDirection) -> Self {
+        match d {
+            TruncationDirection::Left => tokenizers::TruncationDirection::Left,
+            TruncationDirection::Right => tokenizers::TruncationDirection::Right,
+        }
+    }
+}
+
+/// Pooling applied to the window embeddings of a single document
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum WindowPooling {
+    /// Element-wise mean of the windows
+    #[default]
+    Mean,
+    /// Element-wise maximum of the windows
+    Max,
+}
+
+/// Handling of documents longer than `max_length` tokens
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum LongDocumentStrategy {
+    /// Embed only the first (or last) `max_length` tokens
+    #[default]
+    Truncate,
+    /// Split the document into windows of `max_length` tokens overlapping
+    ///
+    /// by `stride` tokens and pool the window embeddings into one vector.
+    Window {
+        /// Number of tokens shared by consecutive windows
+        stride: usize,
+        /// How the window embeddings are combined
+        pooling: WindowPooling,
+    },
+}
+
+/// Hardware backends for inference. Accelerated providers require
+///
+/// the matching cargo feature (`cuda`, `tensorrt`, `rocm`, `coreml`,
+///
+/// `directml`) and fall back to the next provider when unavailable.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum ExecutionProvider {
+    /// Default CPU provider, always available
+    #[default]
+    Cpu,
+    /// NVIDIA CUDA on the given device
+    Cuda {
+        /// GPU device id
+        device_id: i32,
+    },
+    /// NVIDIA TensorRT on the given device
+    TensorRt {
+        /// GPU device id
+        device_id: i32,
+    },
+    /// AMD ROCm on the given device
+    Rocm {
+        /// GPU device id
+        device_id: i32,
+    },
+    /// Apple CoreML
+    CoreMl,
+    /// DirectML on the given device
+    DirectMl {
+        /// GPU device id
+        device_id: i32,
+    },
+}
+
+impl ExecutionProvider {
+    /// Build the ort dispatch for this provider
+    fn dispatch(&self) -> ExecutionProviderDispatch {
+        match *self {
+            ExecutionProvider::Cpu => CPUExecutionProvider::default().build(),
+            ExecutionProvider::Cuda { device_id } => CUDAExecutionProvider::default()
+                .with_device_id(device_id)
+                .build(),
+            ExecutionProvider::TensorRt { device_id } => TensorRTExecutionProvider::default()
+                .with_device_id(device_id)
+                .build(),
+            ExecutionProvider::Rocm { device_id } => ROCmExecutionProvider::default()
+                .with_device_id(device_id)
+                .build(),
+            ExecutionProvider::CoreMl => CoreMLExecutionProvider::default().build(),
+            ExecutionProvider::DirectMl { device_id } => DirectMLExecutionProvider::default()
+                .with_device_id(device_id)
+                .build(),
+        }
+    }
+}
+
+/// Graph optimizations applied when loading the model
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum OptimizationLevel {
+    /// No graph optimizations
+    Disable,
+    /// Semantics-preserving rewrites such as constant folding
+    #[default]
+    Level1,
+    /// Level 1 plus extended node fusions
+    Level2,
+    /// All optimizations including layout transformations
+    Level3,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Level1 => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Level2 => GraphOptimizationLevel::Level2,
+            OptimizationLevel::Level3 => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+/// Settings for turning documents into embeddings
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EmbedderConfig {
+    /// Maximum number of tokens passed to the model per sequence
+    pub max_length: usize,
+    /// Which end of the document is dropped on truncation
+    pub truncation: TruncationDirection,
+    /// Truncate or window documents longer than `max_length`
+    pub long_documents: LongDocumentStrategy,
+    /// Maximum number of documents encoded per inference run
+    pub batch_size: usize,
+    /// Maximum padded tokens (rows * longest row) per inference run.
+    ///
+    /// A document longer than the budget is still encoded on its own.
+    pub max_batch_tokens: usize,
+    /// Providers to register, in order of preference
+    pub execution_providers: Vec<ExecutionProvider>,
+    /// Graph optimization level for the session
+    pub optimization_level: OptimizationLevel,
+    /// Threads used to parallelize the execution within nodes
+    pub intra_threads: usize,
+    /// Threads used to run independent nodes in parallel.
+    ///
+    /// Values above 1 enable parallel execution mode.
+    pub inter_threads: usize,
+}
+
+impl Default for EmbedderConfig {
+    fn default() -> Self {
+        EmbedderConfig {
+            max_length: DEFAULT_MAX_LENGTH,
+            truncation: Default::default(),
+            long_documents: Default::default(),
+            batch_size: BATCH_SIZE,
+            max_batch_tokens: DEFAULT_MAX_BATCH_TOKENS,
+            execution_providers: vec![ExecutionProvider::Cpu],
+            optimization_level: Default::default(),
+            intra_threads: 1,
+            inter_threads: 1,
+        }
+    }
+}
+
+impl EmbedderConfig {
+    /// ort dispatches of the execution providers, in order of preference
+    fn provider_dispatches(&self) -> Vec<ExecutionProviderDispatch> {
+        self.execution_providers
+            .iter()
+            .map(|p| p.dispatch())
+            .collect()
+    }
+}
+
+/// Pool the window embeddings of each document into a single row.
+///
+/// `owners[i]` is the document index of window `i`. Pooled rows are
+///
+/// scaled back to unit length so dot products remain cosine similarities.
+fn pool_windows(
+    embeddings: &Array2<f32>,
+    owners: &[usize],
+    documents: usize,
+    pooling: WindowPooling,
+) -> Array2<f32> {
+    let shape = (documents, embeddings.ncols());
+    let mut pooled: Array2<f32> = match pooling {
+        WindowPooling::Mean => Array2::zeros(shape),
+        WindowPooling::Max => Array2::from_elem(shape, f32::NEG_INFINITY),
     };
+    let mut counts: Vec<usize> = vec![0; documents];
+    for (window, owner) in embeddings.axis_iter(Axis(0)).zip(owners.iter()) {
+        let mut row = pooled.row_mut(*owner);
+        match pooling {
+            WindowPooling::Mean => row += &window,
+            WindowPooling::Max => row.zip_mut_with(&window, |a, b| *a = a.max(*b)),
+        }
+        counts[*owner] += 1;
+    }
+    for (mut row, count) in pooled.axis_iter_mut(Axis(0)).zip(counts) {
+        if count > 1 {
+            if pooling == WindowPooling::Mean {
+                row /= count as f32;
+            }
+            let norm = row.dot(&row).sqrt();
+            if norm > 0.0 {
+                row /= norm;
+            }
+        }
+    }
+    pooled
+}
+
+/// Load the model at `model_path` into an inference session
+fn load_session(model_path: &String, config: &EmbedderConfig) -> Result<Session, OnnxError> {
     info!(
-        "generating encodings from {} with {} threads",
-        model_path, threads
+        "generating encodings from {} with {:?} and {} intra/{} inter threads",
+        model_path, config.execution_providers, config.intra_threads, config.inter_threads
     );
-    // Create the ONNX Runtime environment, enabling CPU/GPU execution providers for all sessions created in this process.
+    // Create the ONNX Runtime environment shared by all sessions created in this process.
     ort::init()
         .with_name("valentinus")
-        .with_execution_providers([CUDAExecutionProvider::default().build()])
         .commit()
         .map_err(OnnxError::OrtError)?;
+    let providers: Vec<ExecutionProviderDispatch> = config.provider_dispatches();
     // Load our model
-    let mut session = Session::builder()
+    Session::builder()
+        .map_err(OnnxError::OrtError)?
+        .with_execution_providers(providers)
         .map_err(OnnxError::OrtError)?
-        .with_optimization_level(GraphOptimizationLevel::Level1)
+        .with_optimization_level(config.optimization_level.into())
         .map_err(OnnxError::OrtError)?
-        .with_parallel_execution(threads > 1)
+        .with_parallel_execution(config.inter_threads > 1)
         .map_err(OnnxError::OrtError)?
-        .with_intra_threads(threads)
+        .with_intra_threads(config.intra_threads)
+        .map_err(OnnxError::OrtError)?
+        .with_inter_threads(config.inter_threads)
         .map_err(OnnxError::OrtError)?
         .commit_from_file(format!("{}/model.onnx", model_path))
-        .map_err(OnnxError::OrtError)?;
-    let tokenizer = Tokenizer::from_file(format!("{}/tokenizer.json", model_path))
+        .map_err(OnnxError::OrtError)
+}
+
+/// Load the tokenizer at `model_path` with the truncation settings of
+///
+/// `config`. Padding is disabled since batches are padded on demand.
+///
+/// Returns the tokenizer and its padding token id.
+fn load_tokenizer(
+    model_path: &String,
+    config: &EmbedderConfig,
+) -> Result<(Tokenizer, i64), OnnxError> {
+    let mut tokenizer = Tokenizer::from_file(format!("{}/tokenizer.json", model_path))
         .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
-    // Encode our input strings. `encode_batch` will pad each input to be the same length.
-    let encodings = tokenizer
-        .encode_batch(data.to_vec(), false)
+    let stride = match config.long_documents {
+        LongDocumentStrategy::Truncate => 0,
+        LongDocumentStrategy::Window { stride, .. } => stride,
+    };
+    // Overflowing tokens are kept on each encoding and only used when windowing
+    tokenizer
+        .with_truncation(Some(TruncationParams {
+            direction: config.truncation.into(),
+            max_length: config.max_length,
+            stride,
+            ..Default::default()
+        }))
         .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
-    // Get the padded length of each encoding.
-    let padded_token_length = encodings[0].len();
-    // Get our token IDs & mask as a flattened array.
-    let ids: Vec<i64> = encodings
-        .iter()
-        .flat_map(|e| e.get_ids().iter().map(|i| *i as i64))
-        .collect();
-    let mask: Vec<i64> = encodings
-        .iter()
-        .flat_map(|e| e.get_attention_mask().iter().map(|i| *i as i64))
-        .collect();
+    let pad_id: i64 = tokenizer
+        .get_padding()
+        .map(|p| p.pad_id as i64)
+        .unwrap_or_default();
+    tokenizer.with_padding(None);
+    Ok((tokenizer, pad_id))
+}
+
+/// The windows of an encoded document passed to the model. Overflowing
+///
+/// tokens are only embedded when windowing long documents.
+fn windows_of<'a>(encoding: &'a Encoding, config: &EmbedderConfig) -> Vec<&'a Encoding> {
+    let mut windows: Vec<&Encoding> = vec![encoding];
+    if let LongDocumentStrategy::Window { .. } = config.long_documents {
+        windows.extend(encoding.get_overflowing().iter());
+    }
+    windows
+}
+
+/// Group documents into batches of at most `batch_size` documents whose
+///
+/// padded size (rows * longest row) stays within `max_batch_tokens`.
+///
+/// `sizes[i]` is the (rows, longest row) of document `i`. Documents are
+///
+/// sorted by length so that each batch pads to a similar length. Returns
+///
+/// the document indices of each batch.
+fn plan_batches(
+    sizes: &[(usize, usize)],
+    batch_size: usize,
+    max_batch_tokens: usize,
+) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|i| sizes[*i].1);
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut rows: usize = 0;
+    for index in order {
+        let (r, longest) = sizes[index];
+        let full = current.len() >= batch_size || (rows + r) * longest > max_batch_tokens;
+        if !current.is_empty() && full {
+            batches.push(std::mem::take(&mut current));
+            rows = 0;
+        }
+        current.push(index);
+        rows += r;
+    }
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    batches
+}
+
+/// ONNX Embeddings generator. Runs one batch of encoded documents
+///
+/// through the model and returns one row per document.
+fn generate_embeddings(
+    session: &mut Session,
+    encodings: &[&Encoding],
+    pad_id: i64,
+    config: &EmbedderConfig,
+) -> Result<Array2<f32>, OnnxError> {
+    // Flatten documents into windows, remembering which document owns each window.
+    let mut windows: Vec<&Encoding> = Vec::new();
+    let mut owners: Vec<usize> = Vec::new();
+    for (index, encoding) in encodings.iter().enumerate() {
+        for window in windows_of(encoding, config) {
+            windows.push(window);
+            owners.push(index);
+        }
+    }
+    // Pad every window to the longest one in this batch.
+    let padded_token_length = windows.iter().map(|e| e.len()).max().unwrap_or_default();
+    let mut ids: Vec<i64> = vec![pad_id; windows.len() * padded_token_length];
+    let mut mask: Vec<i64> = vec![0; windows.len() * padded_token_length];
+    for (row, window) in windows.iter().enumerate() {
+        let offset = row * padded_token_length;
+        for (col, (id, m)) in window
+            .get_ids()
+            .iter()
+            .zip(window.get_attention_mask())
+            .enumerate()
+        {
+            ids[offset + col] = *id as i64;
+            mask[offset + col] = *m as i64;
+        }
+    }
     // Convert our flattened arrays into 2-dimensional tensors of shape [N, L].
-    let a_ids = TensorRef::from_array_view(([data.len(), padded_token_length], &*ids))
+    let a_ids = TensorRef::from_array_view(([windows.len(), padded_token_length], &*ids))
         .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
-    let a_mask = TensorRef::from_array_view(([data.len(), padded_token_length], &*mask))
+    let a_mask = TensorRef::from_array_view(([windows.len(), padded_token_length], &*mask))
         .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
-    //let a_ids = Array2::from_shape_vec([data.len(), padded_token_length], ids)
-    //    .map_err(OnnxError::ShapeError)?;
-    //let a_mask = Array2::from_shape_vec([data.len(), padded_token_length], mask)
-    //    .map_err(OnnxError::ShapeError)?;
     // Run the model.
     let outputs = session
         .run(ort::inputs![a_ids, a_mask])
@@ -93,40 +413,198 @@ fn generate_embeddings(model_path: &String, data: &[String]) -> Result<Array2<f3
         .try_extract_tensor::<f32>()
         .map_err(OnnxError::OrtError)?
         .into_dimensionality::<Ix2>()
+        .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?
+        .into_owned();
+    match config.long_documents {
+        LongDocumentStrategy::Window { pooling, .. } if windows.len() > encodings.len() => {
+            Ok(pool_windows(&embeddings, &owners, encodings.len(), pooling))
+        }
+        _ => Ok(embeddings),
+    }
+}
+
+/// Byte offsets of every token in each document, using the tokenizer
+///
+/// at `model_path` without truncation or special tokens.
+pub fn token_offsets(
+    model_path: &String,
+    data: &[String],
+) -> Result<Vec<Vec<(usize, usize)>>, OnnxError> {
+    let mut tokenizer = Tokenizer::from_file(format!("{}/tokenizer.json", model_path))
+        .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
+    tokenizer
+        .with_truncation(None)
         .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
-    Ok(embeddings.into_owned())
+    tokenizer.with_padding(None);
+    let encodings = tokenizer
+        .encode_batch(data.to_vec(), false)
+        .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
+    Ok(encodings.iter().map(|e| e.get_offsets().to_vec()).collect())
 }
 
-/// Batch embeddings with a batch size of 100 elements.
-pub fn batch_embeddings(model_path: &String, data: &[String]) -> Result<Array2<f32>, OnnxError> {
+/// Batch embeddings. Documents are sorted by token count and grouped into
+///
+/// batches of at most `config.batch_size` documents and `config.max_batch_tokens`
+///
+/// padded tokens. Row `i` of the result is the embedding of `data[i]`.
+pub fn batch_embeddings(
+    model_path: &String,
+    data: &[String],
+    config: &EmbedderConfig,
+) -> Result<Array2<f32>, OnnxError> {
     info!("batching length {} from {}", data.len(), model_path);
     let dimensions: usize = match std::env::var(VALENTINUS_CUSTOM_DIM) {
         Err(_) => DEFUALT_DIMENSIONS,
         Ok(t) => t.parse::<usize>().unwrap_or(DEFUALT_DIMENSIONS),
     };
-    let mut data_array: ndarray::ArrayBase<OwnedRepr<f32>, ndarray::Dim<[usize; 2]>> =
-        ndarray::Array::zeros((data.len(), dimensions));
-    let mut begin: usize = 0;
-    let mut multiplier: usize = 1;
-    let length = data.len();
-    while length - begin > BATCH_SIZE {
-        info!("{} encodings remaining", length - begin);
-        let end = (BATCH_SIZE * multiplier) - 1;
-        let embeddings = generate_embeddings(model_path, &data[begin..end])?;
-        for index1 in begin..end {
-            for index2 in 0..dimensions {
-                data_array[[index1, index2]] = embeddings[[index1 - begin, index2]];
-            }
+    let mut data_array: Array2<f32> = Array2::zeros((data.len(), dimensions));
+    if data.is_empty() {
+        return Ok(data_array);
+    }
+    let mut session = load_session(model_path, config)?;
+    let (tokenizer, pad_id) = load_tokenizer(model_path, config)?;
+    let encodings = tokenizer
+        .encode_batch(data.to_vec(), false)
+        .map_err(|e| OnnxError::OrtError(ort::Error::new(e.to_string())))?;
+    let sizes: Vec<(usize, usize)> = encodings
+        .iter()
+        .map(|e| {
+            let windows = windows_of(e, config);
+            let longest = windows.iter().map(|w| w.len()).max().unwrap_or_default();
+            (windows.len(), longest)
+        })
+        .collect();
+    let batches = plan_batches(&sizes, config.batch_size.max(1), config.max_batch_tokens);
+    let mut remaining: usize = data.len();
+    for batch in batches {
+        info!("{} encodings remaining", remaining);
+        let batch_encodings: Vec<&Encoding> = batch.iter().map(|i| &encodings[*i]).collect();
+        let embeddings = generate_embeddings(&mut session, &batch_encodings, pad_id, config)?;
+        if embeddings.dim() != (batch.len(), dimensions) {
+            error!(
+                "expected embeddings of shape {:?}, got {:?}",
+                (batch.len(), dimensions),
+                embeddings.dim()
+            );
+            return Err(OnnxError::ShapeError(ShapeError::from_kind(
+                ErrorKind::IncompatibleShape,
+            )));
         }
-        begin += BATCH_SIZE;
-        multiplier += 1;
-    }
-    info!("{} encodings remaining", length - begin);
-    let embeddings = generate_embeddings(model_path, &data[begin..length])?;
-    for index1 in 0..length - begin {
-        for index2 in 0..dimensions {
-            data_array[[(index1 + begin), index2]] = embeddings[[index1, index2]];
+        // restore the original document order
+        for (row, index) in embeddings.axis_iter(Axis(0)).zip(batch.iter()) {
+            data_array.row_mut(*index).assign(&row);
         }
+        remaining -= batch.len();
     }
     Ok(data_array)
 }
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn batch_boundaries_test() -> Result<(), OnnxError> {
+        let model_path = String::from("all-MiniLM-L6-v2_onnx");
+        let data: Vec<String> = (0..7)
+            .map(|i| format!("Review number {} of the car was {} stars.", i, i % 5))
+            .collect();
+        // full batches followed by a partial one
+        let config = EmbedderConfig {
+            batch_size: 3,
+            ..Default::default()
+        };
+        let batched = batch_embeddings(&model_path, &data, &config)?;
+        assert_eq!(batched.nrows(), data.len());
+        for (row, document) in batched.axis_iter(Axis(0)).zip(data.iter()) {
+            let single = batch_embeddings(&model_path, &[String::from(document)], &config)?;
+            assert!(row.iter().any(|v| *v != 0.0));
+            for (a, b) in row.iter().zip(single.row(0).iter()) {
+                assert!((a - b).abs() < 1e-4);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn embedder_config_test() {
+        let names = |config: &EmbedderConfig| {
+            config
+                .provider_dispatches()
+                .iter()
+                .map(|d| format!("{:?}", d))
+                .map(|d| String::from(d.split_whitespace().next().unwrap_or_default()))
+                .collect::<Vec<String>>()
+        };
+        // only the CPU provider is registered by default
+        let config = EmbedderConfig::default();
+        assert_eq!(config.execution_providers, vec![ExecutionProvider::Cpu]);
+        assert_eq!(names(&config), vec!["CPUExecutionProvider"]);
+        assert_eq!(config.optimization_level, OptimizationLevel::Level1);
+        assert_eq!((config.intra_threads, config.inter_threads), (1, 1));
+        // accelerated providers are registered first, whether or not their
+        // feature is enabled, so ort can fall back to the next one
+        let accelerated = EmbedderConfig {
+            execution_providers: vec![
+                ExecutionProvider::Cuda { device_id: 1 },
+                ExecutionProvider::TensorRt { device_id: 0 },
+                ExecutionProvider::Cpu,
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            names(&accelerated),
+            vec![
+                "CUDAExecutionProvider",
+                "TensorrtExecutionProvider",
+                "CPUExecutionProvider"
+            ]
+        );
+        let levels = [
+            (OptimizationLevel::Disable, "Disable"),
+            (OptimizationLevel::Level1, "Level1"),
+            (OptimizationLevel::Level2, "Level2"),
+            (OptimizationLevel::Level3, "Level3"),
+        ];
+        for (level, expected) in levels {
+            assert_eq!(
+                format!("{:?}", GraphOptimizationLevel::from(level)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn plan_batches_test() {
+        let sizes: Vec<(usize, usize)> = vec![(1, 40), (1, 5), (2, 30), (1, 6), (1, 500)];
+        // sorted by length and split on the token budget
+        assert_eq!(
+            plan_batches(&sizes, 100, 120),
+            vec![vec![1, 3, 2], vec![0], vec![4]]
+        );
+        // split on the document count
+        assert_eq!(
+            plan_batches(&sizes, 2, 10_000),
+            vec![vec![1, 3], vec![2, 0], vec![4]]
+        );
+        assert_eq!(plan_batches(&sizes, 5, 10_000), vec![vec![1, 3, 2, 0, 4]]);
+    }
+
+    #[test]
+    fn pool_windows_test() {
+        let windows: Array2<f32> = array![[1.0, 0.0], [0.0, 1.0], [0.6, 0.8]];
+        let owners: Vec<usize> = vec![0, 0, 1];
+        let mean = pool_windows(&windows, &owners, 2, WindowPooling::Mean);
+        let expected = 0.5_f32.sqrt();
+        assert!((mean[[0, 0]] - expected).abs() < 1e-6);
+        assert!((mean[[0, 1]] - expected).abs() < 1e-6);
+        // single window documents are left untouched
+        assert_eq!(mean.row(1), windows.row(2));
+        let max = pool_windows(&windows, &owners, 2, WindowPooling::Max);
+        assert!((max[[0, 0]] - expected).abs() < 1e-6);
+        assert_eq!(max.row(1), windows.row(2));
+    }
+}
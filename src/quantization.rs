@@ -0,0 +1,341 @@
+#![deny(missing_docs)]
+
+//! Scalar and binary quantization of stored embeddings.
+//!
+//! Quantized vectors are stored as a kind byte, the big endian `u64` row
+//!
+//! and column counts, for int8 the little endian `f32` scale of every
+//!
+//! dimension, and the codes in row-major order. Queries score the codes
+//!
+//! in place for a fast first pass.
+
+use std::collections::HashSet;
+
+use log::*;
+use ndarray::{Array2, ArrayView1, ArrayView2, Axis};
+use serde::{Deserialize, Serialize};
+
+use crate::embeddings::ValentinusError;
+
+/// Prefix of the keys quantized embeddings are stored under
+pub const VALENTINUS_QUANTIZED: &str = "quantized";
+/// Length of the kind, row and column counts
+const QUANTIZED_HEADER_LEN: usize = 17;
+/// Kind byte of int8 codes
+const KIND_INT8: u8 = 1;
+/// Kind byte of binary codes
+const KIND_BINARY: u8 = 2;
+
+/// Quantization of a collection's embeddings for the first query pass
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Quantization {
+    /// Score the full `f32` embeddings
+    #[default]
+    None,
+    /// One signed byte per dimension, scaled by the dimension's largest
+    ///
+    /// magnitude. A quarter of the size of `f32`
+    Int8,
+    /// One sign bit per dimension, scored by Hamming distance. A 32nd of
+    ///
+    /// the size of `f32`
+    Binary,
+}
+
+/// Key of the quantized embeddings of the collection stored under `collection_key`
+pub fn quantized_key(collection_key: &str) -> String {
+    format!("{}-{}", VALENTINUS_QUANTIZED, collection_key)
+}
+
+/// Bytes of the codes of `rows` embeddings of `cols` dimensions
+pub fn quantized_len(quantization: Quantization, rows: u64, cols: u64) -> u64 {
+    match quantization {
+        Quantization::None => 0,
+        Quantization::Int8 => QUANTIZED_HEADER_LEN as u64 + cols * 4 + rows * cols,
+        Quantization::Binary => QUANTIZED_HEADER_LEN as u64 + rows * cols.div_ceil(8),
+    }
+}
+
+/// Pack the sign bits of `values`, most significant bit first
+fn sign_bits(values: ArrayView1<f32>) -> Vec<u8> {
+    let mut bits: Vec<u8> = vec![0; values.len().div_ceil(8)];
+    for (j, value) in values.iter().enumerate() {
+        if *value > 0.0 {
+            bits[j / 8] |= 0x80 >> (j % 8);
+        }
+    }
+    bits
+}
+
+/// Encode `embeddings` with `quantization`. Empty for `Quantization::None`
+pub fn quantize(quantization: Quantization, embeddings: ArrayView2<f32>) -> Vec<u8> {
+    let kind: u8 = match quantization {
+        Quantization::None => return Vec::new(),
+        Quantization::Int8 => KIND_INT8,
+        Quantization::Binary => KIND_BINARY,
+    };
+    let (rows, cols) = embeddings.dim();
+    let mut raw: Vec<u8> =
+        Vec::with_capacity(quantized_len(quantization, rows as u64, cols as u64) as usize);
+    raw.push(kind);
+    raw.extend_from_slice(&(rows as u64).to_be_bytes());
+    raw.extend_from_slice(&(cols as u64).to_be_bytes());
+    match quantization {
+        Quantization::Int8 => {
+            let scales: Vec<f32> = embeddings
+                .axis_iter(Axis(1))
+                .map(|column| column.iter().fold(0.0f32, |m, v| m.max(v.abs())) / 127.0)
+                .collect();
+            for scale in &scales {
+                raw.extend_from_slice(&scale.to_le_bytes());
+            }
+            for row in embeddings.axis_iter(Axis(0)) {
+                for (value, scale) in row.iter().zip(&scales) {
+                    let code: f32 = if *scale > 0.0 { value / scale } else { 0.0 };
+                    raw.push(code.round().clamp(-127.0, 127.0) as i8 as u8);
+                }
+            }
+        }
+        _ => {
+            for row in embeddings.axis_iter(Axis(0)) {
+                raw.extend(sign_bits(row));
+            }
+        }
+    }
+    raw
+}
+
+/// Quantized embeddings viewed in place
+pub struct QuantizedView<'a> {
+    quantization: Quantization,
+    rows: usize,
+    cols: usize,
+    /// Scale of every dimension, empty for binary codes
+    scales: Vec<f32>,
+    codes: &'a [u8],
+}
+
+/// View quantized embeddings stored by `quantize`
+pub fn view_quantized(raw: &[u8]) -> Result<QuantizedView<'_>, ValentinusError> {
+    let invalid = |reason: &str| {
+        error!("stored quantized embeddings are invalid: {}", reason);
+        ValentinusError::CorruptionError
+    };
+    if raw.len() < QUANTIZED_HEADER_LEN {
+        return Err(invalid("missing header"));
+    }
+    let quantization: Quantization = match raw[0] {
+        KIND_INT8 => Quantization::Int8,
+        KIND_BINARY => Quantization::Binary,
+        _ => return Err(invalid("unknown kind")),
+    };
+    let rows: u64 = u64::from_be_bytes(raw[1..9].try_into().unwrap_or_default());
+    let cols: u64 = u64::from_be_bytes(raw[9..17].try_into().unwrap_or_default());
+    let len: Option<u64> = match quantization {
+        Quantization::Int8 => rows
+            .checked_mul(cols)
+            .and_then(|n| n.checked_add(cols.checked_mul(4)?)),
+        _ => rows.checked_mul(cols.div_ceil(8)),
+    };
+    if len != Some((raw.len() - QUANTIZED_HEADER_LEN) as u64) {
+        return Err(invalid("length doesn't match the shape"));
+    }
+    let (rows, cols) = (rows as usize, cols as usize);
+    let mut codes: &[u8] = &raw[QUANTIZED_HEADER_LEN..];
+    let mut scales: Vec<f32> = Vec::new();
+    if quantization == Quantization::Int8 {
+        let (raw_scales, rest) = codes.split_at(cols * 4);
+        scales = raw_scales
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        codes = rest;
+    }
+    Ok(QuantizedView {
+        quantization,
+        rows,
+        cols,
+        scales,
+        codes,
+    })
+}
+
+impl QuantizedView<'_> {
+    /// Approximate similarity of `query` to every stored embedding. Int8
+    ///
+    /// codes approximate the dot product, binary codes score
+    ///
+    /// `1 - 2 * hamming / dimensions`, which is 1 for matching signs.
+    pub fn scores(&self, query: ArrayView1<f32>) -> Result<Vec<f32>, ValentinusError> {
+        if query.len() != self.cols {
+            error!(
+                "query has {} dimensions, quantized embeddings {}",
+                query.len(),
+                self.cols
+            );
+            return Err(ValentinusError::QuantizationError);
+        }
+        if self.cols == 0 {
+            return Ok(vec![0.0; self.rows]);
+        }
+        Ok(match self.quantization {
+            Quantization::Int8 => {
+                let scaled: Vec<f32> = query.iter().zip(&self.scales).map(|(q, s)| q * s).collect();
+                self.codes
+                    .chunks_exact(self.cols)
+                    .map(|row| {
+                        row.iter()
+                            .zip(&scaled)
+                            .map(|(c, q)| *c as i8 as f32 * q)
+                            .sum()
+                    })
+                    .collect()
+            }
+            _ => {
+                let bits: Vec<u8> = sign_bits(query);
+                self.codes
+                    .chunks_exact(bits.len())
+                    .map(|row| {
+                        let hamming: u32 = row
+                            .iter()
+                            .zip(&bits)
+                            .map(|(a, b)| (a ^ b).count_ones())
+                            .sum();
+                        1.0 - 2.0 * hamming as f32 / self.cols as f32
+                    })
+                    .collect()
+            }
+        })
+    }
+    /// Approximate `f32` embeddings. Binary codes become unit vectors
+    pub fn dequantize(&self) -> Array2<f32> {
+        match self.quantization {
+            Quantization::Int8 => Array2::from_shape_fn((self.rows, self.cols), |(i, j)| {
+                self.codes[i * self.cols + j] as i8 as f32 * self.scales[j]
+            }),
+            _ => {
+                let unit: f32 = 1.0 / (self.cols.max(1) as f32).sqrt();
+                let width: usize = self.cols.div_ceil(8);
+                Array2::from_shape_fn((self.rows, self.cols), |(i, j)| {
+                    if self.codes[i * width + j / 8] & (0x80 >> (j % 8)) != 0 {
+                        unit
+                    } else {
+                        -unit
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// Indices of the `n` highest scores, best first
+pub fn top_candidates(scores: &[f32], n: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    order.truncate(n);
+    order
+}
+
+/// Recall at `k` of a quantized first pass, with `rescore` candidates
+///
+/// rescored when not 0, against exact dot product search over `embeddings`.
+///
+/// Averaged over every row of `queries`; 1 means the same top `k`.
+pub fn quantization_recall(
+    embeddings: &Array2<f32>,
+    queries: &Array2<f32>,
+    quantization: Quantization,
+    rescore: usize,
+    k: usize,
+) -> Result<f64, ValentinusError> {
+    if queries.ncols() != embeddings.ncols() || k == 0 {
+        error!("recall needs queries of the embeddings' dimensions and k above 0");
+        return Err(ValentinusError::QuantizationError);
+    }
+    if queries.nrows() == 0 || embeddings.nrows() == 0 {
+        return Ok(1.0);
+    }
+    let raw: Vec<u8> = quantize(quantization, embeddings.view());
+    let view: Option<QuantizedView> = match quantization {
+        Quantization::None => None,
+        _ => Some(view_quantized(&raw)?),
+    };
+    let k: usize = k.min(embeddings.nrows());
+    let mut found: usize = 0;
+    for query in queries.axis_iter(Axis(0)) {
+        let exact: Vec<f32> = embeddings.dot(&query).to_vec();
+        let expected: HashSet<usize> = top_candidates(&exact, k).into_iter().collect();
+        let candidates: Vec<usize> = match &view {
+            None => top_candidates(&exact, k),
+            Some(view) => {
+                let approximate: Vec<f32> = view.scores(query)?;
+                let mut candidates: Vec<usize> = top_candidates(&approximate, rescore.max(k));
+                if rescore > 0 {
+                    candidates.sort_by(|a, b| exact[*b].total_cmp(&exact[*a]));
+                }
+                candidates.truncate(k);
+                candidates
+            }
+        };
+        found += candidates.iter().filter(|c| expected.contains(c)).count();
+    }
+    Ok(found as f64 / (k * queries.nrows()) as f64)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Random unit vectors, the same for every `seed`
+    fn unit_vectors(rows: usize, cols: usize, seed: u64) -> Array2<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut vectors: Array2<f32> =
+            Array2::from_shape_fn((rows, cols), |_| rng.random::<f32>() - 0.5);
+        for mut row in vectors.axis_iter_mut(Axis(0)) {
+            let norm: f32 = row.dot(&row).sqrt();
+            row.mapv_inplace(|v| v / norm);
+        }
+        vectors
+    }
+
+    #[test]
+    fn quantization_test() -> Result<(), ValentinusError> {
+        let embeddings: Array2<f32> = unit_vectors(200, 128, 7);
+        let queries: Array2<f32> = unit_vectors(20, 128, 11);
+        for quantization in [Quantization::Int8, Quantization::Binary] {
+            let raw: Vec<u8> = quantize(quantization, embeddings.view());
+            assert_eq!(raw.len() as u64, quantized_len(quantization, 200, 128));
+            let view: QuantizedView = view_quantized(&raw)?;
+            assert_eq!(view.scores(queries.row(0))?.len(), 200);
+            assert!(view.scores(queries.row(0).slice(ndarray::s![..8])).is_err());
+            assert_eq!(view.dequantize().dim(), (200, 128));
+            assert!(view_quantized(&raw[..raw.len() - 1]).is_err());
+        }
+        // int8 codes are close to the original values
+        let raw: Vec<u8> = quantize(Quantization::Int8, embeddings.view());
+        let error: f32 = (&view_quantized(&raw)?.dequantize() - &embeddings)
+            .iter()
+            .fold(0.0, |m, v| m.max(v.abs()));
+        assert!(error < 0.01);
+        // binary codes of a vector match its own signs exactly
+        let raw: Vec<u8> = quantize(Quantization::Binary, embeddings.view());
+        assert_eq!(view_quantized(&raw)?.scores(embeddings.row(3))?[3], 1.0);
+        // rescoring recovers what the first pass misses
+        let exact = quantization_recall(&embeddings, &queries, Quantization::None, 0, 10)?;
+        let int8 = quantization_recall(&embeddings, &queries, Quantization::Int8, 0, 10)?;
+        let binary = quantization_recall(&embeddings, &queries, Quantization::Binary, 0, 10)?;
+        let rescored = quantization_recall(&embeddings, &queries, Quantization::Binary, 50, 10)?;
+        assert_eq!(exact, 1.0);
+        assert!(int8 >= 0.9);
+        assert!(rescored > binary);
+        assert!(rescored >= 0.8);
+        Ok(())
+    }
+}
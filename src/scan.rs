@@ -0,0 +1,111 @@
+#![deny(missing_docs)]
+
+//! Exact scoring of embeddings for brute-force search.
+//!
+//! Scores are the product of the embeddings matrix and the query vector,
+//!
+//! computed over blocks of `SCAN_CHUNK_ROWS` rows on the rayon thread pool.
+//!
+//! Each block is a contiguous matrix-vector product whose unrolled dot
+//!
+//! products the compiler vectorizes.
+
+use log::*;
+use ndarray::{linalg::general_mat_vec_mul, s, Array1, ArrayView1, ArrayView2, ArrayViewMut1};
+use rayon::prelude::*;
+
+use crate::embeddings::ValentinusError;
+
+/// Rows scored per parallel task
+const SCAN_CHUNK_ROWS: usize = 1024;
+
+/// Dot product of `query` with every row of `embeddings`
+pub fn exact_scores(
+    embeddings: ArrayView2<f32>,
+    query: ArrayView1<f32>,
+) -> Result<Array1<f32>, ValentinusError> {
+    if embeddings.ncols() != query.len() {
+        error!("query doesn't match the dimensions of the embeddings");
+        return Err(ValentinusError::CosineError);
+    }
+    let mut scores: Vec<f32> = vec![0.0; embeddings.nrows()];
+    scores
+        .par_chunks_mut(SCAN_CHUNK_ROWS)
+        .enumerate()
+        .for_each(|(chunk, out)| {
+            let start: usize = chunk * SCAN_CHUNK_ROWS;
+            let block: ArrayView2<f32> = embeddings.slice(s![start..start + out.len(), ..]);
+            general_mat_vec_mul(1.0, &block, &query, 0.0, &mut ArrayViewMut1::from(out));
+        });
+    Ok(Array1::from(scores))
+}
+
+/// Dot product of `query` with the given `rows` of `embeddings`, as
+///
+/// `(row, score)` in the order of `rows`. Rows out of range are skipped.
+pub fn score_rows_exact(
+    embeddings: ArrayView2<f32>,
+    query: ArrayView1<f32>,
+    rows: &[usize],
+) -> Result<Vec<(usize, f32)>, ValentinusError> {
+    let n: usize = embeddings.nrows();
+    // scoring everything is cheaper than gathering most of the rows
+    if rows.len() * 2 >= n {
+        let scores: Array1<f32> = exact_scores(embeddings, query)?;
+        return Ok(rows
+            .iter()
+            .filter(|r| **r < n)
+            .map(|r| (*r, scores[*r]))
+            .collect());
+    }
+    if embeddings.ncols() != query.len() {
+        error!("query doesn't match the dimensions of the embeddings");
+        return Err(ValentinusError::CosineError);
+    }
+    Ok(rows
+        .par_iter()
+        .filter(|r| **r < n)
+        .map(|r| (*r, embeddings.row(*r).dot(&query)))
+        .collect())
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use ndarray::Array2;
+
+    #[test]
+    fn exact_scores_test() -> Result<(), ValentinusError> {
+        // spans several chunks with a partial last one
+        let rows: usize = SCAN_CHUNK_ROWS * 2 + 7;
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((rows, 24), |(i, j)| ((i * 24 + j) as f32 * 0.13).cos());
+        let query: Array1<f32> = Array1::from_shape_fn(24, |j| (j as f32 * 0.7).sin());
+        let scores: Array1<f32> = exact_scores(embeddings.view(), query.view())?;
+        assert_eq!(scores.len(), rows);
+        for (row, score) in embeddings.rows().into_iter().zip(scores.iter()) {
+            let expected: f32 = row.iter().zip(query.iter()).map(|(a, b)| a * b).sum();
+            assert!((score - expected).abs() < 1e-4);
+        }
+        // few and most rows take different paths to the same scores
+        let few: Vec<usize> = vec![rows - 1, 3, rows + 10, 1500];
+        let scored: Vec<(usize, f32)> = score_rows_exact(embeddings.view(), query.view(), &few)?;
+        assert_eq!(
+            scored.iter().map(|(r, _)| *r).collect::<Vec<usize>>(),
+            vec![rows - 1, 3, 1500]
+        );
+        let most: Vec<usize> = (0..rows).rev().collect();
+        let scored_most = score_rows_exact(embeddings.view(), query.view(), &most)?;
+        assert_eq!(scored_most[0], (rows - 1, scores[rows - 1]));
+        for (row, score) in scored.iter().chain(scored_most.iter()) {
+            assert!((score - scores[*row]).abs() < 1e-4);
+        }
+        assert!(exact_scores(embeddings.view(), query.slice(s![..8])).is_err());
+        assert!(score_rows_exact(embeddings.view(), query.slice(s![..8]), &few).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+#![deny(missing_docs)]
+
+//! Contiguous storage of uncompressed embeddings.
+//!
+//! Stored as the big endian `u64` row and column counts followed by the
+//!
+//! little endian `f32` values in row-major order. The values start 16
+//!
+//! bytes in, so on little endian targets queries view them in place.
+
+use log::*;
+use ndarray::{Array2, ArrayView2, CowArray, Ix2};
+
+use crate::embeddings::ValentinusError;
+
+/// Prefix of the keys embeddings are stored under apart from their collection
+pub const VALENTINUS_VECTORS: &str = "vectors";
+/// Length of the row and column counts
+const VECTORS_HEADER_LEN: usize = 16;
+
+/// Key of the embeddings of the collection stored under `collection_key`
+pub fn vectors_key(collection_key: &str) -> String {
+    format!("{}-{}", VALENTINUS_VECTORS, collection_key)
+}
+
+/// Encode `embeddings` for storage
+pub fn encode_vectors(embeddings: &Array2<f32>) -> Vec<u8> {
+    let mut raw: Vec<u8> = Vec::with_capacity(VECTORS_HEADER_LEN + embeddings.len() * 4);
+    raw.extend_from_slice(&(embeddings.nrows() as u64).to_be_bytes());
+    raw.extend_from_slice(&(embeddings.ncols() as u64).to_be_bytes());
+    for value in embeddings.iter() {
+        raw.extend_from_slice(&value.to_le_bytes());
+    }
+    raw
+}
+
+/// View stored embeddings. Borrows `raw` when it is suitably aligned,
+///
+/// otherwise the values are copied.
+pub fn view_vectors(raw: &[u8]) -> Result<CowArray<'_, f32, Ix2>, ValentinusError> {
+    let invalid = |reason: &str| {
+        error!("stored embeddings are invalid: {}", reason);
+        ValentinusError::CorruptionError
+    };
+    if raw.len() < VECTORS_HEADER_LEN {
+        return Err(invalid("missing header"));
+    }
+    let (header, values) = raw.split_at(VECTORS_HEADER_LEN);
+    let rows: u64 = u64::from_be_bytes(header[..8].try_into().unwrap_or_default());
+    let cols: u64 = u64::from_be_bytes(header[8..].try_into().unwrap_or_default());
+    let len: Option<u64> = rows.checked_mul(cols).and_then(|n| n.checked_mul(4));
+    if len != Some(values.len() as u64) {
+        return Err(invalid("length doesn't match the shape"));
+    }
+    let shape: (usize, usize) = (rows as usize, cols as usize);
+    if cfg!(target_endian = "little") {
+        if let Ok(floats) = bytemuck::try_cast_slice::<u8, f32>(values) {
+            let view: ArrayView2<f32> =
+                ArrayView2::from_shape(shape, floats).map_err(|_| invalid("bad shape"))?;
+            return Ok(CowArray::from(view));
+        }
+    }
+    debug!("copying unaligned embeddings");
+    let floats: Vec<f32> = values
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    let owned: Array2<f32> =
+        Array2::from_shape_vec(shape, floats).map_err(|_| invalid("bad shape"))?;
+    Ok(CowArray::from(owned))
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn vectors_round_trip_test() -> Result<(), ValentinusError> {
+        let embeddings: Array2<f32> =
+            Array2::from_shape_fn((5, 3), |(r, c)| (r * 3 + c) as f32 * 0.25);
+        let raw: Vec<u8> = encode_vectors(&embeddings);
+        // back the bytes with words so the alignment is known
+        let words: Vec<u32> = raw
+            .chunks_exact(4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let aligned: &[u8] = &bytemuck::cast_slice::<u32, u8>(&words)[..raw.len()];
+        let view: CowArray<f32, Ix2> = view_vectors(aligned)?;
+        assert_eq!(view, embeddings);
+        assert_eq!(view.is_view(), cfg!(target_endian = "little"));
+        // an unaligned value is copied
+        let mut shifted: Vec<u32> = vec![0; words.len() + 1];
+        bytemuck::cast_slice_mut::<u32, u8>(&mut shifted)[1..=raw.len()].copy_from_slice(&raw);
+        let unaligned: &[u8] = &bytemuck::cast_slice::<u32, u8>(&shifted)[1..=raw.len()];
+        let view: CowArray<f32, Ix2> = view_vectors(unaligned)?;
+        assert_eq!(view, embeddings);
+        assert!(!view.is_view());
+        // a transposed matrix is encoded in logical order
+        let transposed: Array2<f32> = embeddings.t().to_owned();
+        assert_eq!(view_vectors(&encode_vectors(&transposed))?, transposed);
+        assert!(view_vectors(&raw[..raw.len() - 1]).is_err());
+        assert!(view_vectors(&raw[..8]).is_err());
+        Ok(())
+    }
+}
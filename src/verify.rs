@@ -0,0 +1,602 @@
+#![deny(missing_docs)]
+
+//! Consistency checks for the hand maintained indexers.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use kn0sys_lmdb_rs::MdbError;
+use log::*;
+
+use crate::database::*;
+use crate::embeddings::{KeyViewIndexer, ValentinusError};
+use crate::migrate::decode_collection;
+
+/// A single inconsistency found by `Valentinus::verify`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inconsistency {
+    /// Value under this key could not be decoded
+    Undecodable(String),
+    /// Value under this key is truncated or failed its checksum
+    Corrupted(String),
+    /// Collection blob not reachable from the `keys` indexer or its view
+    OrphanedCollection(String),
+    /// Entry in the `keys` indexer without a collection blob
+    DanglingKey(String),
+    /// Collection whose view is missing from the `views` indexer
+    MissingView(String),
+    /// View in the `views` indexer or lookup without a collection
+    DanglingView(String),
+    /// View name used by more than one collection or indexer entry
+    DuplicateView(String),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::Undecodable(k) => write!(f, "undecodable value: {}", k),
+            Inconsistency::Corrupted(k) => write!(f, "corrupted value: {}", k),
+            Inconsistency::OrphanedCollection(k) => write!(f, "orphaned collection: {}", k),
+            Inconsistency::DanglingKey(k) => write!(f, "key without collection: {}", k),
+            Inconsistency::MissingView(v) => write!(f, "missing view: {}", v),
+            Inconsistency::DanglingView(v) => write!(f, "view without collection: {}", v),
+            Inconsistency::DuplicateView(v) => write!(f, "duplicate view: {}", v),
+        }
+    }
+}
+
+/// Outcome of `Valentinus::verify`
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    issues: Vec<Inconsistency>,
+    repaired: bool,
+    quarantined: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Inconsistencies found before any repair
+    pub fn get_issues(&self) -> &Vec<Inconsistency> {
+        &self.issues
+    }
+    /// True when nothing was found
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+    /// True when the issues were repaired
+    pub fn is_repaired(&self) -> bool {
+        self.repaired
+    }
+    /// Keys of the collections the repair set aside
+    pub fn get_quarantined(&self) -> &Vec<String> {
+        &self.quarantined
+    }
+}
+
+/// Indexers and lookups as read by the scan
+struct Scanned {
+    keys: Vec<String>,
+    views: Vec<String>,
+    quarantined: Vec<String>,
+    /// view -> collection key recorded by the key-view lookup
+    lookups: HashMap<String, String>,
+}
+
+/// Collection blob found while scanning
+struct Blob {
+    key: String,
+    /// `None` when the blob could not be decoded
+    view: Option<String>,
+}
+
+/// Record an issue unless it was already found
+fn push_once(issues: &mut Vec<Inconsistency>, issue: Inconsistency) {
+    if !issues.contains(&issue) {
+        issues.push(issue);
+    }
+}
+
+/// Read `key`, recording it as corrupted and returning `None` when it is
+fn read_checked(
+    db: &dyn StorageBackend,
+    key: &[u8],
+    issues: &mut Vec<Inconsistency>,
+) -> Result<Option<Vec<u8>>, ValentinusError> {
+    match db.read(key) {
+        Ok(raw) => Ok(Some(raw)),
+        Err(MdbError::Corrupted) => {
+            let key: String = String::from_utf8_lossy(key).into_owned();
+            issues.push(Inconsistency::Corrupted(key));
+            Ok(None)
+        }
+        Err(e) => Err(ValentinusError::from(e)),
+    }
+}
+
+/// Read and decode an indexer, recording it as undecodable on failure
+fn read_indexer(
+    db: &dyn StorageBackend,
+    key: &str,
+    issues: &mut Vec<Inconsistency>,
+) -> Result<Vec<String>, ValentinusError> {
+    let raw: Vec<u8> = read_checked(db, key.as_bytes(), issues)?.unwrap_or_default();
+    if raw.is_empty() {
+        return Ok(Vec::new());
+    }
+    match bincode::deserialize::<KeyViewIndexer>(&raw[..]) {
+        Ok(indexer) => Ok(indexer.get_values().to_vec()),
+        Err(_) => {
+            issues.push(Inconsistency::Undecodable(String::from(key)));
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Walk the database and report inconsistencies between the indexers
+///
+/// and the collection blobs. With `repair` the indexers are rebuilt from
+///
+/// the decodable collections in one transaction. Corrupted or undecodable
+///
+/// collections and all but one collection per view (preferring the one
+///
+/// its lookup points at) are left in place with their embeddings and
+///
+/// indexes, but dropped from the indexers and listed under the
+///
+/// `quarantine` indexer, which later checks skip.
+pub fn verify(db: &dyn StorageBackend, repair: bool) -> Result<VerifyReport, ValentinusError> {
+    info!("verifying database consistency");
+    let mut issues: Vec<Inconsistency> = Vec::new();
+    let keys: Vec<String> = read_indexer(db, VALENTINUS_KEYS, &mut issues)?;
+    let views: Vec<String> = read_indexer(db, VALENTINUS_VIEWS, &mut issues)?;
+    let quarantined: Vec<String> = read_indexer(db, VALENTINUS_QUARANTINE, &mut issues)?;
+    let lookup_prefix: String = format!("{}-{}-", VALENTINUS_KEY, VALENTINUS_VIEW);
+    let blob_prefix: String = format!("{}-", VALENTINUS_KEY);
+    let mut blobs: Vec<Blob> = Vec::new();
+    // view -> collection key recorded by the key-view lookup
+    let mut lookups: HashMap<String, String> = HashMap::new();
+    for raw in db.keys().map_err(ValentinusError::from)? {
+        let key: String = String::from_utf8_lossy(&raw).into_owned();
+        if key.starts_with(&lookup_prefix) {
+            let Some(target) = read_checked(db, &raw, &mut issues)? else {
+                continue;
+            };
+            lookups.insert(
+                String::from(&key[blob_prefix.len()..]),
+                String::from_utf8_lossy(&target).into_owned(),
+            );
+        } else if is_collection_key(&raw) && !quarantined.contains(&key) {
+            let Some(value) = read_checked(db, &raw, &mut issues)? else {
+                blobs.push(Blob { key, view: None });
+                continue;
+            };
+            let view: Option<String> = decode_collection(&value)
+                .ok()
+                .map(|c| String::from(c.get_view()));
+            if view.is_none() {
+                issues.push(Inconsistency::Undecodable(String::from(&key)));
+            }
+            blobs.push(Blob { key, view });
+        }
+    }
+    // collection key -> view of every decodable collection
+    let decoded: HashMap<&String, &String> = blobs
+        .iter()
+        .filter_map(|b| b.view.as_ref().map(|v| (&b.key, v)))
+        .collect();
+    let mut collections_per_view: HashMap<&String, usize> = HashMap::new();
+    for view in decoded.values() {
+        *collections_per_view.entry(*view).or_default() += 1;
+    }
+    for blob in &blobs {
+        let Some(view) = &blob.view else { continue };
+        let reachable = keys.contains(&blob.key) && lookups.get(view) == Some(&blob.key);
+        if !reachable {
+            issues.push(Inconsistency::OrphanedCollection(String::from(&blob.key)));
+        }
+        if !views.contains(view) {
+            push_once(&mut issues, Inconsistency::MissingView(String::from(view)));
+        }
+        if collections_per_view.get(view).copied().unwrap_or_default() > 1 {
+            push_once(
+                &mut issues,
+                Inconsistency::DuplicateView(String::from(view)),
+            );
+        }
+    }
+    for key in &keys {
+        if !blobs.iter().any(|b| &b.key == key) {
+            issues.push(Inconsistency::DanglingKey(String::from(key)));
+        }
+    }
+    for view in views.iter().chain(lookups.keys()) {
+        if views.iter().filter(|v| *v == view).count() > 1 {
+            push_once(
+                &mut issues,
+                Inconsistency::DuplicateView(String::from(view)),
+            );
+        }
+        let valid = lookups
+            .get(view)
+            .and_then(|k| decoded.get(k))
+            .is_some_and(|v| *v == view);
+        if !valid {
+            push_once(&mut issues, Inconsistency::DanglingView(String::from(view)));
+        }
+    }
+    let mut report = VerifyReport {
+        issues,
+        ..Default::default()
+    };
+    if repair && !report.is_consistent() {
+        let scanned = Scanned {
+            keys,
+            views,
+            quarantined,
+            lookups,
+        };
+        report.quarantined = rebuild(db, &blobs, &scanned, &report.issues)?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+/// Indexer values as read inside the repair transaction, or `scanned` when
+///
+/// the indexer can't be read or decoded there either
+fn reread_indexer(
+    read: &UpdateReader,
+    key: &str,
+    scanned: &[String],
+) -> Result<Vec<String>, MdbError> {
+    let raw: Vec<u8> = match read(key.as_bytes()) {
+        Ok(raw) => raw,
+        Err(MdbError::Corrupted) => return Ok(scanned.to_vec()),
+        Err(e) => return Err(e),
+    };
+    match bincode::deserialize::<KeyViewIndexer>(&raw[..]) {
+        Ok(indexer) => Ok(indexer.get_values().to_vec()),
+        Err(_) => Ok(scanned.to_vec()),
+    }
+}
+
+/// Rewrite the indexers and lookups from the decodable collections.
+///
+/// The indexers are read again inside the write transaction so that
+///
+/// collections saved or deleted since the scan are kept or left out.
+///
+/// Returns the keys of the collections newly quarantined.
+fn rebuild(
+    db: &dyn StorageBackend,
+    blobs: &[Blob],
+    scanned: &Scanned,
+    issues: &[Inconsistency],
+) -> Result<Vec<String>, ValentinusError> {
+    info!("repairing database indexers");
+    let mut new_quarantined: Vec<String> = Vec::new();
+    db.update(&WriteBatch::new(), &mut |read, batch| {
+        let keys: Vec<String> = reread_indexer(read, VALENTINUS_KEYS, &scanned.keys)?;
+        let views: Vec<String> = reread_indexer(read, VALENTINUS_VIEWS, &scanned.views)?;
+        let quarantined: Vec<String> =
+            reread_indexer(read, VALENTINUS_QUARANTINE, &scanned.quarantined)?;
+        // corrupted lookups still in use and the indexers are rewritten below
+        let lookup_prefix: String = format!("{}-{}-", VALENTINUS_KEY, VALENTINUS_VIEW);
+        for issue in issues {
+            if let Inconsistency::Corrupted(key) = issue {
+                if key.starts_with(&lookup_prefix) {
+                    warn!("deleting corrupted lookup {}", key);
+                    batch.delete(key.as_bytes());
+                }
+            }
+        }
+        // collections deleted since the scan are gone with their lookups
+        let deleted: Vec<&String> = scanned.keys.iter().filter(|k| !keys.contains(k)).collect();
+        // keep the collection the lookup points at, otherwise the first listed
+        let mut ordered: Vec<&Blob> = blobs
+            .iter()
+            .filter(|b| !deleted.contains(&&b.key))
+            .collect();
+        ordered.sort_by_key(|b| {
+            scanned
+                .keys
+                .iter()
+                .position(|k| k == &b.key)
+                .unwrap_or(usize::MAX)
+        });
+        let mut chosen: HashMap<&String, &String> = HashMap::new();
+        for blob in &ordered {
+            if let Some(view) = &blob.view {
+                if scanned.lookups.get(view) == Some(&blob.key) {
+                    chosen.insert(view, &blob.key);
+                }
+            }
+        }
+        let mut new_keys: Vec<String> = Vec::new();
+        let mut new_views: Vec<String> = Vec::new();
+        new_quarantined.clear();
+        for blob in &ordered {
+            let keep = match &blob.view {
+                Some(view) => *chosen.entry(view).or_insert(&blob.key) == &blob.key,
+                None => false,
+            };
+            if !keep {
+                warn!("quarantining collection {}", blob.key);
+                new_quarantined.push(String::from(&blob.key));
+                continue;
+            }
+            let view = blob.view.as_ref().map(String::from).unwrap_or_default();
+            let lookup: String = format!("{}-{}", VALENTINUS_KEY, view);
+            batch.put(lookup.as_bytes(), blob.key.as_bytes());
+            new_keys.push(String::from(&blob.key));
+            new_views.push(view);
+        }
+        for view in scanned.lookups.keys() {
+            if !new_views.contains(view) {
+                let lookup: String = format!("{}-{}", VALENTINUS_KEY, view);
+                batch.delete(lookup.as_bytes());
+            }
+        }
+        // collections saved since the scan keep their entries
+        new_keys.extend(
+            keys.into_iter()
+                .filter(|k| !scanned.keys.contains(k) && !blobs.iter().any(|b| &b.key == k)),
+        );
+        let added: Vec<String> = views
+            .into_iter()
+            .filter(|v| !scanned.views.contains(v) && !new_views.contains(v))
+            .collect();
+        new_views.extend(added);
+        let b_keys: Vec<u8> =
+            bincode::serialize(&KeyViewIndexer::new(&new_keys)).map_err(|_| MdbError::Panic)?;
+        let b_views: Vec<u8> =
+            bincode::serialize(&KeyViewIndexer::new(&new_views)).map_err(|_| MdbError::Panic)?;
+        batch.put(VALENTINUS_KEYS.as_bytes(), &b_keys);
+        batch.put(VALENTINUS_VIEWS.as_bytes(), &b_views);
+        // an unreadable quarantine indexer is rewritten with what's known
+        let unreadable = issues.iter().any(|i| match i {
+            Inconsistency::Undecodable(k) | Inconsistency::Corrupted(k) => {
+                k == VALENTINUS_QUARANTINE
+            }
+            _ => false,
+        });
+        if unreadable || !new_quarantined.is_empty() {
+            let all: Vec<String> = [&quarantined[..], &new_quarantined[..]].concat();
+            let b_quarantined: Vec<u8> =
+                bincode::serialize(&KeyViewIndexer::new(&all)).map_err(|_| MdbError::Panic)?;
+            batch.put(VALENTINUS_QUARANTINE.as_bytes(), &b_quarantined);
+        }
+        Ok(())
+    })
+    .map_err(ValentinusError::from)?;
+    Ok(new_quarantined)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::embeddings::{EmbeddingCollection, ModelType, Valentinus};
+    use crate::migrate::{encode_collection, Placement};
+    use crate::vectors::vectors_key;
+
+    /// Unsaved collection named `name`
+    fn collection(valentinus: &Valentinus, name: &str) -> EmbeddingCollection {
+        EmbeddingCollection::new(
+            valentinus,
+            vec![String::from("doc")],
+            vec![vec![]],
+            vec![String::from("id0")],
+            String::from(name),
+            ModelType::AllMiniLmL6V2,
+            String::from("all-MiniLM-L6-v2_onnx"),
+        )
+        .expect("collection should be created")
+    }
+
+    #[test]
+    fn verify_repair_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let a = collection(&valentinus, "a");
+        let b = collection(&valentinus, "b");
+        let encode =
+            |c: &EmbeddingCollection| encode_collection(c, Placement::Inline).unwrap_or_default();
+        let indexer = |v: &[&String]| {
+            let values: Vec<String> = v.iter().map(|s| String::from(*s)).collect();
+            bincode::serialize(&KeyViewIndexer::new(&values)).unwrap_or_default()
+        };
+        let dangling = String::from("key-missing");
+        let mut batch = WriteBatch::new();
+        batch.put(
+            VALENTINUS_KEYS.as_bytes(),
+            &indexer(&[a.get_key(), &dangling]),
+        );
+        batch.put(VALENTINUS_VIEWS.as_bytes(), &indexer(&[a.get_view()]));
+        let lookup = format!("{}-{}", VALENTINUS_KEY, a.get_view());
+        batch.put(lookup.as_bytes(), a.get_key().as_bytes());
+        batch.put(a.get_key().as_bytes(), &encode(&a));
+        // b was written without its index entries
+        batch.put(b.get_key().as_bytes(), &encode(&b));
+        batch.put(b"key-garbage", b"not a collection");
+        batch.put(vectors_key("key-garbage").as_bytes(), b"vectors");
+        valentinus
+            .db
+            .commit_batch(&batch)
+            .map_err(ValentinusError::from)?;
+        let report = valentinus.verify(false)?;
+        let issues = report.get_issues();
+        assert!(!report.is_repaired());
+        assert!(issues.contains(&Inconsistency::Undecodable(String::from("key-garbage"))));
+        assert!(issues.contains(&Inconsistency::OrphanedCollection(b.get_key().clone())));
+        assert!(issues.contains(&Inconsistency::MissingView(b.get_view().clone())));
+        assert!(issues.contains(&Inconsistency::DanglingKey(dangling)));
+        assert_eq!(issues.len(), 4);
+        let repaired = valentinus.verify(true)?;
+        assert!(repaired.is_repaired());
+        assert_eq!(
+            repaired.get_quarantined(),
+            &vec![String::from("key-garbage")]
+        );
+        assert!(valentinus.verify(false)?.is_consistent());
+        // the undecodable collection and its embeddings are kept
+        assert_eq!(valentinus.db.read(b"key-garbage")?, b"not a collection");
+        assert_eq!(
+            valentinus.db.read(vectors_key("key-garbage").as_bytes())?,
+            b"vectors"
+        );
+        let keys = EmbeddingCollection::fetch_collection_keys(&valentinus, false)?;
+        assert_eq!(
+            keys.get_values(),
+            &vec![a.get_key().clone(), b.get_key().clone()]
+        );
+        let found = crate::embeddings::find(&valentinus, None, Some(b.get_view().clone()))?;
+        assert_eq!(found.get_key(), b.get_key());
+        Ok(())
+    }
+
+    /// Backend that commits `race` right before its first update, as if it
+    ///
+    /// landed between the scan and the repair
+    struct RacingBackend {
+        inner: crate::memory::MemoryBackend,
+        race: std::sync::Mutex<Option<WriteBatch>>,
+    }
+
+    impl StorageBackend for RacingBackend {
+        fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+            self.inner.read(k)
+        }
+        fn keys(&self) -> Result<Vec<Vec<u8>>, MdbError> {
+            self.inner.keys()
+        }
+        fn update(&self, batch: &WriteBatch, f: &mut UpdateFn) -> Result<(), MdbError> {
+            let race: Option<WriteBatch> = self.race.lock().map_err(|_| MdbError::Panic)?.take();
+            if let Some(race) = race {
+                self.inner.commit_batch(&race)?;
+            }
+            self.inner.update(batch, f)
+        }
+    }
+
+    #[test]
+    fn repair_race_test() -> Result<(), ValentinusError> {
+        let valentinus = Valentinus::in_memory();
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|name| collection(&valentinus, name));
+        let encode =
+            |c: &EmbeddingCollection| encode_collection(c, Placement::Inline).unwrap_or_default();
+        let indexer = |v: &[&String]| {
+            let values: Vec<String> = v.iter().map(|s| String::from(*s)).collect();
+            bincode::serialize(&KeyViewIndexer::new(&values)).unwrap_or_default()
+        };
+        let lookup = |c: &EmbeddingCollection| format!("{}-{}", VALENTINUS_KEY, c.get_view());
+        let db = RacingBackend {
+            inner: crate::memory::MemoryBackend::new(),
+            race: std::sync::Mutex::new(None),
+        };
+        let mut batch = WriteBatch::new();
+        batch.put(
+            VALENTINUS_KEYS.as_bytes(),
+            &indexer(&[a.get_key(), d.get_key()]),
+        );
+        batch.put(
+            VALENTINUS_VIEWS.as_bytes(),
+            &indexer(&[a.get_view(), d.get_view()]),
+        );
+        for indexed in [&a, &d] {
+            batch.put(lookup(indexed).as_bytes(), indexed.get_key().as_bytes());
+            batch.put(indexed.get_key().as_bytes(), &encode(indexed));
+        }
+        // b was written without its index entries
+        batch.put(b.get_key().as_bytes(), &encode(&b));
+        db.commit_batch(&batch).map_err(ValentinusError::from)?;
+        // c is saved and d deleted after the scan
+        let mut race = WriteBatch::new();
+        race.put(
+            VALENTINUS_KEYS.as_bytes(),
+            &indexer(&[a.get_key(), c.get_key()]),
+        );
+        race.put(
+            VALENTINUS_VIEWS.as_bytes(),
+            &indexer(&[a.get_view(), c.get_view()]),
+        );
+        race.put(lookup(&c).as_bytes(), c.get_key().as_bytes());
+        race.put(c.get_key().as_bytes(), &encode(&c));
+        race.delete(lookup(&d).as_bytes());
+        race.delete(d.get_key().as_bytes());
+        *db.race.lock().map_err(|_| ValentinusError::TestError)? = Some(race);
+        assert!(verify(&db, true)?.is_repaired());
+        assert!(verify(&db, false)?.is_consistent());
+        let keys: KeyViewIndexer = bincode::deserialize(&db.read(VALENTINUS_KEYS.as_bytes())?)
+            .map_err(|_| ValentinusError::BincodeError)?;
+        assert_eq!(
+            keys.get_values(),
+            &vec![
+                a.get_key().clone(),
+                b.get_key().clone(),
+                c.get_key().clone()
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_value_test() -> Result<(), ValentinusError> {
+        let path = std::env::temp_dir().join(format!("valentinus-{}", uuid::Uuid::new_v4()));
+        let config = crate::embeddings::DatabaseConfig::new(&path);
+        let encoded: Vec<u8> = {
+            let valentinus = Valentinus::open(&config)?;
+            let a = collection(&valentinus, "a");
+            let encoded: Vec<u8> = encode_collection(&a, Placement::Inline)?;
+            let mut batch = WriteBatch::new();
+            batch.put(
+                VALENTINUS_KEYS.as_bytes(),
+                &bincode::serialize(&KeyViewIndexer::new(&[a.get_key().clone()]))
+                    .unwrap_or_default(),
+            );
+            batch.put(
+                VALENTINUS_VIEWS.as_bytes(),
+                &bincode::serialize(&KeyViewIndexer::new(&[a.get_view().clone()]))
+                    .unwrap_or_default(),
+            );
+            let lookup = format!("{}-{}", VALENTINUS_KEY, a.get_view());
+            batch.put(lookup.as_bytes(), a.get_key().as_bytes());
+            batch.put(a.get_key().as_bytes(), &encoded);
+            valentinus.db.commit_batch(&batch)?;
+            encoded
+        };
+        // flip a bit of the stored collection on disk
+        let data_file = path.join(&config.env).join("data.mdb");
+        let mut data: Vec<u8> = std::fs::read(&data_file).map_err(ValentinusError::IoError)?;
+        let offset: usize = data
+            .windows(encoded.len())
+            .position(|w| w == &encoded[..])
+            .ok_or(ValentinusError::TestError)?;
+        data[offset + encoded.len() / 2] ^= 1;
+        std::fs::write(&data_file, &data).map_err(ValentinusError::IoError)?;
+        let valentinus = Valentinus::open(&config)?;
+        let view = format!("{}-a", VALENTINUS_VIEW);
+        assert!(matches!(
+            crate::embeddings::find(&valentinus, None, Some(view.clone())),
+            Err(ValentinusError::CorruptionError)
+        ));
+        let report = valentinus.verify(true)?;
+        assert!(report.is_repaired());
+        assert!(report
+            .get_issues()
+            .iter()
+            .any(|i| matches!(i, Inconsistency::Corrupted(_))));
+        assert_eq!(report.get_quarantined().len(), 1);
+        assert!(valentinus.verify(false)?.is_consistent());
+        assert!(crate::embeddings::find(&valentinus, None, Some(view)).is_err());
+        // the corrupted value is left on disk for recovery
+        let key: &String = &report.get_quarantined()[0];
+        assert!(matches!(
+            valentinus.db.read(key.as_bytes()),
+            Err(MdbError::Corrupted)
+        ));
+        let _ = std::fs::remove_dir_all(path);
+        Ok(())
+    }
+}